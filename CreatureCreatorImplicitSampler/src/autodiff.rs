@@ -0,0 +1,500 @@
+use std::ops::{Add, Div, Mul, Neg, Sub};
+
+use nalgebra::{Matrix3, Point3, Vector3};
+
+use crate::shapes::{
+    smooth_min, Difference, Ellipsoid, Intersection, SmoothUnion, Sphere, Transformed, Union,
+};
+use crate::surface::Surface;
+use crate::tape::{Opcode, Tape, INLINE_REGISTERS};
+
+// Forward mode automatic differentiation
+// Surfaces that can be evaluated with any Scalar get exact derivatives by being evaluated with a Dual,
+// which carries the gradient along with the value through every operation
+
+pub trait Scalar:
+    Copy
+    + Add<Output = Self>
+    + Sub<Output = Self>
+    + Mul<Output = Self>
+    + Div<Output = Self>
+    + Neg<Output = Self>
+{
+    fn constant(value: f32) -> Self;
+
+    fn value(&self) -> f32;
+
+    fn sqrt(self) -> Self;
+
+    fn abs(self) -> Self {
+        if self.value() < 0.0 {
+            -self
+        } else {
+            self
+        }
+    }
+
+    fn min(self, other: Self) -> Self {
+        if other.value() < self.value() {
+            other
+        } else {
+            self
+        }
+    }
+
+    fn max(self, other: Self) -> Self {
+        if other.value() > self.value() {
+            other
+        } else {
+            self
+        }
+    }
+
+    fn scale(self, factor: f32) -> Self {
+        self * Self::constant(factor)
+    }
+
+    fn offset(self, amount: f32) -> Self {
+        self + Self::constant(amount)
+    }
+}
+
+impl Scalar for f32 {
+    fn constant(value: f32) -> Self {
+        value
+    }
+
+    fn value(&self) -> f32 {
+        *self
+    }
+
+    fn sqrt(self) -> Self {
+        f32::sqrt(self)
+    }
+}
+
+// Dual carries the gradient with respect to the sample point
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Dual {
+    pub value: f32,
+    pub gradient: Vector3<f32>,
+}
+
+impl Dual {
+    // variables returns x, y and z as duals, each one with respect to itself
+    pub fn variables(at: Point3<f32>) -> [Dual; 3] {
+        [0, 1, 2].map(|i| Dual {
+            value: at[i],
+            gradient: Vector3::ith(i, 1.0),
+        })
+    }
+
+    // chain applies a function with derivative `df` at self.value to the dual
+    fn chain(self, value: f32, df: f32) -> Dual {
+        Dual {
+            value,
+            gradient: self.gradient * df,
+        }
+    }
+}
+
+impl Add for Dual {
+    type Output = Dual;
+
+    fn add(self, rhs: Dual) -> Dual {
+        Dual {
+            value: self.value + rhs.value,
+            gradient: self.gradient + rhs.gradient,
+        }
+    }
+}
+
+impl Sub for Dual {
+    type Output = Dual;
+
+    fn sub(self, rhs: Dual) -> Dual {
+        Dual {
+            value: self.value - rhs.value,
+            gradient: self.gradient - rhs.gradient,
+        }
+    }
+}
+
+impl Mul for Dual {
+    type Output = Dual;
+
+    fn mul(self, rhs: Dual) -> Dual {
+        Dual {
+            value: self.value * rhs.value,
+            gradient: self.gradient * rhs.value + rhs.gradient * self.value,
+        }
+    }
+}
+
+impl Div for Dual {
+    type Output = Dual;
+
+    fn div(self, rhs: Dual) -> Dual {
+        let value = self.value / rhs.value;
+
+        Dual {
+            value,
+            gradient: (self.gradient - rhs.gradient * value) / rhs.value,
+        }
+    }
+}
+
+impl Neg for Dual {
+    type Output = Dual;
+
+    fn neg(self) -> Dual {
+        Dual {
+            value: -self.value,
+            gradient: -self.gradient,
+        }
+    }
+}
+
+impl Scalar for Dual {
+    fn constant(value: f32) -> Self {
+        Dual {
+            value,
+            gradient: Vector3::zeros(),
+        }
+    }
+
+    fn value(&self) -> f32 {
+        self.value
+    }
+
+    // sqrt has no slope at 0, like at the center of a sphere, so the gradient there is left at zero instead of NaN
+    fn sqrt(self) -> Self {
+        let value = self.value.sqrt();
+        if value <= 0.0 {
+            return self.chain(value, 0.0);
+        }
+
+        self.chain(value, 0.5 / value)
+    }
+
+    fn scale(self, factor: f32) -> Self {
+        Dual {
+            value: self.value * factor,
+            gradient: self.gradient * factor,
+        }
+    }
+
+    fn offset(self, amount: f32) -> Self {
+        Dual {
+            value: self.value + amount,
+            gradient: self.gradient,
+        }
+    }
+}
+
+// HyperDual carries the gradient and the hessian, for when second derivatives are needed
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct HyperDual {
+    pub value: f32,
+    pub gradient: Vector3<f32>,
+    pub hessian: Matrix3<f32>,
+}
+
+impl HyperDual {
+    pub fn variables(at: Point3<f32>) -> [HyperDual; 3] {
+        [0, 1, 2].map(|i| HyperDual {
+            value: at[i],
+            gradient: Vector3::ith(i, 1.0),
+            hessian: Matrix3::zeros(),
+        })
+    }
+
+    // chain applies a function with first derivative `df` and second derivative `ddf` at self.value
+    fn chain(self, value: f32, df: f32, ddf: f32) -> HyperDual {
+        HyperDual {
+            value,
+            gradient: self.gradient * df,
+            hessian: self.hessian * df + (self.gradient * self.gradient.transpose()) * ddf,
+        }
+    }
+
+    fn recip(self) -> HyperDual {
+        let value = 1.0 / self.value;
+
+        self.chain(value, -value * value, 2.0 * value * value * value)
+    }
+}
+
+impl Add for HyperDual {
+    type Output = HyperDual;
+
+    fn add(self, rhs: HyperDual) -> HyperDual {
+        HyperDual {
+            value: self.value + rhs.value,
+            gradient: self.gradient + rhs.gradient,
+            hessian: self.hessian + rhs.hessian,
+        }
+    }
+}
+
+impl Sub for HyperDual {
+    type Output = HyperDual;
+
+    fn sub(self, rhs: HyperDual) -> HyperDual {
+        HyperDual {
+            value: self.value - rhs.value,
+            gradient: self.gradient - rhs.gradient,
+            hessian: self.hessian - rhs.hessian,
+        }
+    }
+}
+
+impl Mul for HyperDual {
+    type Output = HyperDual;
+
+    fn mul(self, rhs: HyperDual) -> HyperDual {
+        let cross = self.gradient * rhs.gradient.transpose();
+
+        HyperDual {
+            value: self.value * rhs.value,
+            gradient: self.gradient * rhs.value + rhs.gradient * self.value,
+            hessian: self.hessian * rhs.value
+                + rhs.hessian * self.value
+                + cross
+                + cross.transpose(),
+        }
+    }
+}
+
+impl Div for HyperDual {
+    type Output = HyperDual;
+
+    fn div(self, rhs: HyperDual) -> HyperDual {
+        Mul::mul(self, rhs.recip())
+    }
+}
+
+impl Neg for HyperDual {
+    type Output = HyperDual;
+
+    fn neg(self) -> HyperDual {
+        HyperDual {
+            value: -self.value,
+            gradient: -self.gradient,
+            hessian: -self.hessian,
+        }
+    }
+}
+
+impl Scalar for HyperDual {
+    fn constant(value: f32) -> Self {
+        HyperDual {
+            value,
+            gradient: Vector3::zeros(),
+            hessian: Matrix3::zeros(),
+        }
+    }
+
+    fn value(&self) -> f32 {
+        self.value
+    }
+
+    fn sqrt(self) -> Self {
+        let value = self.value.sqrt();
+        if value <= 0.0 {
+            return self.chain(value, 0.0, 0.0);
+        }
+
+        self.chain(value, 0.5 / value, -0.25 / (value * self.value))
+    }
+
+    fn scale(self, factor: f32) -> Self {
+        HyperDual {
+            value: self.value * factor,
+            gradient: self.gradient * factor,
+            hessian: self.hessian * factor,
+        }
+    }
+
+    fn offset(self, amount: f32) -> Self {
+        HyperDual {
+            value: self.value + amount,
+            ..self
+        }
+    }
+}
+
+// DifferentiableSurface is implemented by surfaces that can be evaluated with any Scalar
+pub trait DifferentiableSurface: Surface {
+    fn sample_generic<T: Scalar>(&self, at: [T; 3]) -> T;
+}
+
+impl<S: DifferentiableSurface + ?Sized> DifferentiableSurface for &S {
+    fn sample_generic<T: Scalar>(&self, at: [T; 3]) -> T {
+        (**self).sample_generic(at)
+    }
+}
+
+impl<S: DifferentiableSurface + ?Sized> DifferentiableSurface for Box<S> {
+    fn sample_generic<T: Scalar>(&self, at: [T; 3]) -> T {
+        (**self).sample_generic(at)
+    }
+}
+
+// value_and_gradient evaluates the surface once, returning the exact gradient with the value
+pub fn value_and_gradient<S: DifferentiableSurface + ?Sized>(
+    surface: &S,
+    at: Point3<f32>,
+) -> (f32, Vector3<f32>) {
+    let d = surface.sample_generic(Dual::variables(at));
+
+    (d.value, d.gradient)
+}
+
+// value_gradient_hessian evaluates the surface once, returning the exact first and second derivatives
+pub fn value_gradient_hessian<S: DifferentiableSurface + ?Sized>(
+    surface: &S,
+    at: Point3<f32>,
+) -> (f32, Vector3<f32>, Matrix3<f32>) {
+    let d = surface.sample_generic(HyperDual::variables(at));
+
+    (d.value, d.gradient, d.hessian)
+}
+
+// curvature returns the mean and gaussian curvature of the level set through `at`
+// These are the formulas from Goldman's "Curvature formulas for implicit curves and surfaces"
+pub fn curvature<S: DifferentiableSurface + ?Sized>(surface: &S, at: Point3<f32>) -> (f32, f32) {
+    let (_, g, h) = value_gradient_hessian(surface, at);
+
+    let g2 = g.magnitude_squared();
+    let adjugate = adjugate(&h);
+
+    let gaussian = (g.transpose() * adjugate * g)[0] / (g2 * g2);
+    let mean = ((g.transpose() * h * g)[0] - g2 * h.trace()) / (2.0 * g2 * g2.sqrt());
+
+    (mean, gaussian)
+}
+
+// adjugate is computed from cofactors so it still works for singular matrices
+fn adjugate(m: &Matrix3<f32>) -> Matrix3<f32> {
+    let minor = |r0: usize, r1: usize, c0: usize, c1: usize| {
+        m[(r0, c0)] * m[(r1, c1)] - m[(r0, c1)] * m[(r1, c0)]
+    };
+
+    Matrix3::new(
+        minor(1, 2, 1, 2),
+        -minor(0, 2, 1, 2),
+        minor(0, 1, 1, 2),
+        -minor(1, 2, 0, 2),
+        minor(0, 2, 0, 2),
+        -minor(0, 1, 0, 2),
+        minor(1, 2, 0, 1),
+        -minor(0, 2, 0, 1),
+        minor(0, 1, 0, 1),
+    )
+}
+
+impl DifferentiableSurface for Sphere {
+    fn sample_generic<T: Scalar>(&self, at: [T; 3]) -> T {
+        let [x, y, z] = at;
+
+        (x * x + y * y + z * z).sqrt().offset(-self.radius)
+    }
+}
+
+impl DifferentiableSurface for Ellipsoid {
+    fn sample_generic<T: Scalar>(&self, at: [T; 3]) -> T {
+        let [x, y, z] =
+            [0, 1, 2].map(|i| (at[i] * at[i]).scale(1.0 / (self.size[i] * self.size[i])));
+
+        (x + y + z).offset(-1.0)
+    }
+}
+
+impl<S: DifferentiableSurface> DifferentiableSurface for Transformed<S> {
+    fn sample_generic<T: Scalar>(&self, at: [T; 3]) -> T {
+        let m = &self.inverse;
+        let transformed = [0, 1, 2].map(|row| {
+            (at[0].scale(m[(row, 0)]) + at[1].scale(m[(row, 1)]) + at[2].scale(m[(row, 2)]))
+                .offset(m[(row, 3)])
+        });
+
+        self.surface.sample_generic(transformed)
+    }
+}
+
+impl<A: DifferentiableSurface, B: DifferentiableSurface> DifferentiableSurface for Union<A, B> {
+    fn sample_generic<T: Scalar>(&self, at: [T; 3]) -> T {
+        self.0.sample_generic(at).min(self.1.sample_generic(at))
+    }
+}
+
+impl<A: DifferentiableSurface, B: DifferentiableSurface> DifferentiableSurface
+    for Intersection<A, B>
+{
+    fn sample_generic<T: Scalar>(&self, at: [T; 3]) -> T {
+        self.0.sample_generic(at).max(self.1.sample_generic(at))
+    }
+}
+
+impl<A: DifferentiableSurface, B: DifferentiableSurface> DifferentiableSurface
+    for Difference<A, B>
+{
+    fn sample_generic<T: Scalar>(&self, at: [T; 3]) -> T {
+        self.0.sample_generic(at).max(-self.1.sample_generic(at))
+    }
+}
+
+impl<A: DifferentiableSurface, B: DifferentiableSurface> DifferentiableSurface
+    for SmoothUnion<A, B>
+{
+    fn sample_generic<T: Scalar>(&self, at: [T; 3]) -> T {
+        smooth_min(self.a.sample_generic(at), self.b.sample_generic(at), self.k)
+    }
+}
+
+impl DifferentiableSurface for Tape {
+    fn sample_generic<T: Scalar>(&self, at: [T; 3]) -> T {
+        if self.register_count() <= INLINE_REGISTERS {
+            let mut registers = [T::constant(0.0); INLINE_REGISTERS];
+            run_generic(self, &mut registers, at)
+        } else {
+            let mut registers = vec![T::constant(0.0); self.register_count()];
+            run_generic(self, &mut registers, at)
+        }
+    }
+}
+
+fn run_generic<T: Scalar>(tape: &Tape, registers: &mut [T], at: [T; 3]) -> T {
+    for i in tape.instructions() {
+        let [a, b, c] = i.args.map(|r| registers[r as usize]);
+        let m = i.imm;
+
+        registers[i.out as usize] = match i.opcode {
+            Opcode::X => at[0],
+            Opcode::Y => at[1],
+            Opcode::Z => at[2],
+            Opcode::Const => T::constant(m[0]),
+            Opcode::Add => a + b,
+            Opcode::Sub => a - b,
+            Opcode::Mul => a * b,
+            Opcode::Div => a / b,
+            Opcode::Min => a.min(b),
+            Opcode::Max => a.max(b),
+            Opcode::Neg => -a,
+            Opcode::Abs => a.abs(),
+            Opcode::Square => a * a,
+            Opcode::Sqrt => a.sqrt(),
+            Opcode::AddImm => a.offset(m[0]),
+            Opcode::MulImm => a.scale(m[0]),
+            Opcode::Affine => (a.scale(m[0]) + b.scale(m[1]) + c.scale(m[2])).offset(m[3]),
+            Opcode::WeightedSquares => {
+                ((a * a).scale(m[0]) + (b * b).scale(m[1]) + (c * c).scale(m[2])).offset(m[3])
+            }
+            Opcode::Length => (a * a + b * b + c * c).sqrt(),
+            Opcode::SmoothMin => smooth_min(a, b, m[0]),
+        };
+    }
+
+    registers[tape.result_register()]
+}
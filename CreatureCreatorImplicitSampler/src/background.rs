@@ -0,0 +1,253 @@
+use std::marker::PhantomData;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use nalgebra::{Point3, Vector3};
+
+use crate::handles::ParticleHandle;
+use crate::live_sampling::{ImplicitSampler, Particle};
+use crate::spatial_index::kd_indexer::KdIndexer;
+use crate::spatial_index::SpatialIndexer;
+use crate::surface::Surface;
+
+// SETTLED_T is how far along the simulation has to be before the particles are taken to have settled
+// After that the sampler only updates once for each surface submitted, rather than over and over
+const SETTLED_T: f32 = 6.0;
+
+// Mailbox holds the latest value put in it, until it's taken out
+// Putting a value in replaces whatever's there, so a reader that falls behind only ever sees the newest. Both ends
+// just swap a pointer, neither ever waits on the other
+struct Mailbox<T> {
+    latest: AtomicPtr<T>,
+    _owns: PhantomData<Box<T>>,
+}
+
+// The value is moved between threads, but never shared, so it only has to be Send
+unsafe impl<T: Send> Sync for Mailbox<T> {}
+
+impl<T> Mailbox<T> {
+    fn new() -> Self {
+        Mailbox {
+            latest: AtomicPtr::new(ptr::null_mut()),
+            _owns: PhantomData,
+        }
+    }
+
+    // put returns the value it replaced, if the reader hadn't got to it yet
+    fn put(&self, value: Box<T>) -> Option<Box<T>> {
+        let old = self.latest.swap(Box::into_raw(value), Ordering::AcqRel);
+
+        // This is safe because every pointer in the mailbox came from Box::into_raw, and swapping it out means
+        // nothing else has it
+        (!old.is_null()).then(|| unsafe { Box::from_raw(old) })
+    }
+
+    fn take(&self) -> Option<Box<T>> {
+        let latest = self.latest.swap(ptr::null_mut(), Ordering::AcqRel);
+
+        // Safe for the same reasons as in put
+        (!latest.is_null()).then(|| unsafe { Box::from_raw(latest) })
+    }
+}
+
+impl<T> Drop for Mailbox<T> {
+    fn drop(&mut self) {
+        drop(self.take())
+    }
+}
+
+// Snapshot is every sample from one update, taken all at once so it's never part way through an update
+#[derive(Debug, Default)]
+pub struct Snapshot {
+    // How many updates had finished when it was taken
+    pub update: u64,
+    pub samples: Vec<(ParticleHandle, Point3<f32>, Vector3<f32>, f32)>,
+}
+
+// Handoff is everything both sides of a BackgroundSampler can reach
+struct Handoff<S> {
+    surfaces: Mailbox<S>,
+    snapshots: Mailbox<Snapshot>,
+    // Snapshots the render side is done with come back to be filled again, so they don't have to be reallocated
+    recycled: Mailbox<Snapshot>,
+
+    desired_radius: AtomicU32,
+    updates: AtomicU64,
+    stop: AtomicBool,
+}
+
+// BackgroundSampler keeps an ImplicitSampler updating on its own thread
+// The latest surface submitted is sampled over and over until the particles settle, and a snapshot of the samples is
+// published after every update. Neither side ever blocks the other, the render side just picks up the newest
+// snapshot there is
+pub struct BackgroundSampler<S, const MAX_SAMPLE_COUNT: usize, I = KdIndexer> {
+    handoff: Arc<Handoff<S>>,
+    worker: Option<JoinHandle<ImplicitSampler<MAX_SAMPLE_COUNT, I>>>,
+}
+
+impl<S, const MAX_SAMPLE_COUNT: usize, I> BackgroundSampler<S, MAX_SAMPLE_COUNT, I>
+where
+    S: Surface + Send + 'static,
+    I: SpatialIndexer<Particle> + Send + 'static,
+{
+    // start hands `sampler` to a new thread, it carries on from wherever it's got to
+    // Nothing's sampled until the first surface is submitted
+    pub fn start(sampler: ImplicitSampler<MAX_SAMPLE_COUNT, I>, desired_radius: f32) -> Self {
+        let handoff = Arc::new(Handoff {
+            surfaces: Mailbox::new(),
+            snapshots: Mailbox::new(),
+            recycled: Mailbox::new(),
+
+            desired_radius: AtomicU32::new(desired_radius.to_bits()),
+            updates: AtomicU64::new(0),
+            stop: AtomicBool::new(false),
+        });
+
+        let worker = {
+            let handoff = Arc::clone(&handoff);
+
+            thread::Builder::new()
+                .name("implicit sampler".into())
+                .spawn(move || run(sampler, &handoff))
+                .expect("sampler thread should start")
+        };
+
+        BackgroundSampler {
+            handoff,
+            worker: Some(worker),
+        }
+    }
+
+    // submit replaces the surface being sampled, it's picked up at the start of the next update
+    pub fn submit(&self, surface: S) {
+        drop(self.handoff.surfaces.put(Box::new(surface)));
+
+        if let Some(worker) = &self.worker {
+            worker.thread().unpark()
+        }
+    }
+
+    pub fn set_desired_radius(&self, desired_radius: f32) {
+        self.handoff
+            .desired_radius
+            .store(desired_radius.to_bits(), Ordering::Relaxed)
+    }
+
+    // latest takes the newest snapshot published since the last call, if there's been one
+    pub fn latest(&self) -> Option<Box<Snapshot>> {
+        self.handoff.snapshots.take()
+    }
+
+    // recycle gives a snapshot back to be reused, once it's been replaced by a newer one
+    pub fn recycle(&self, snapshot: Box<Snapshot>) {
+        drop(self.handoff.recycled.put(snapshot))
+    }
+
+    // updates is how many updates have finished
+    pub fn updates(&self) -> u64 {
+        self.handoff.updates.load(Ordering::Relaxed)
+    }
+
+    // is_alive is false once the sampler thread has panicked, nothing new will be published after that
+    // The thread only ever ends early by panicking, stopping it takes the BackgroundSampler with it
+    pub fn is_alive(&self) -> bool {
+        self.worker
+            .as_ref()
+            .is_some_and(|worker| !worker.is_finished())
+    }
+
+    // stop waits for the update in progress to finish, then gives the sampler back
+    // A panic on the sampler thread carries on from here
+    pub fn stop(self) -> ImplicitSampler<MAX_SAMPLE_COUNT, I> {
+        match self.try_stop() {
+            Ok(sampler) => sampler,
+            Err(panic) => std::panic::resume_unwind(panic),
+        }
+    }
+
+    // detach stops the sampler without waiting for it, the update in progress finishes on the sampler thread and the
+    // sampler is dropped there. A panic on the sampler thread is only printed
+    pub fn detach(mut self) {
+        let worker = self.worker.take().unwrap();
+        self.handoff.stop.store(true, Ordering::Relaxed);
+        worker.thread().unpark()
+    }
+
+    // try_stop is stop, but gives back whatever the sampler thread panicked with instead of carrying on with it
+    pub fn try_stop(mut self) -> thread::Result<ImplicitSampler<MAX_SAMPLE_COUNT, I>> {
+        let worker = self.worker.take().unwrap();
+        self.handoff.stop.store(true, Ordering::Relaxed);
+        worker.thread().unpark();
+
+        worker.join()
+    }
+}
+
+impl<S, const MAX_SAMPLE_COUNT: usize, I> Drop for BackgroundSampler<S, MAX_SAMPLE_COUNT, I> {
+    fn drop(&mut self) {
+        if let Some(worker) = self.worker.take() {
+            self.handoff.stop.store(true, Ordering::Relaxed);
+            worker.thread().unpark();
+
+            // A panic on the sampler thread carries on here, unless this thread is already unwinding
+            if let Err(panic) = worker.join() {
+                if !thread::panicking() {
+                    std::panic::resume_unwind(panic)
+                }
+            }
+        }
+    }
+}
+
+fn run<S, const MAX_SAMPLE_COUNT: usize, I>(
+    mut sampler: ImplicitSampler<MAX_SAMPLE_COUNT, I>,
+    handoff: &Handoff<S>,
+) -> ImplicitSampler<MAX_SAMPLE_COUNT, I>
+where
+    S: Surface,
+    I: SpatialIndexer<Particle>,
+{
+    let mut surface = None;
+    // Whether the surface was submitted since the last update
+    let mut fresh = false;
+
+    while !handoff.stop.load(Ordering::Relaxed) {
+        if let Some(latest) = handoff.surfaces.take() {
+            surface = Some(latest);
+            fresh = true
+        }
+
+        let Some(surface) = &surface else {
+            // Woken by submit or stop
+            thread::park();
+            continue;
+        };
+
+        // Settled particles only move when the surface does, so there's nothing to do until another is submitted
+        if sampler.t >= SETTLED_T && !fresh {
+            thread::park();
+            continue;
+        }
+        fresh = false;
+
+        let desired_radius = f32::from_bits(handoff.desired_radius.load(Ordering::Relaxed));
+        sampler.update(desired_radius, surface.as_ref());
+        sampler.compact();
+
+        let update = handoff.updates.fetch_add(1, Ordering::Relaxed) + 1;
+
+        let mut snapshot = handoff.recycled.take().unwrap_or_default();
+        snapshot.update = update;
+        snapshot.samples.clear();
+        snapshot.samples.extend(sampler.samples_with_handles());
+
+        // A snapshot the render side never picked up is as good as recycled
+        if let Some(stale) = handoff.snapshots.put(snapshot) {
+            drop(handoff.recycled.put(stale))
+        }
+    }
+
+    sampler
+}
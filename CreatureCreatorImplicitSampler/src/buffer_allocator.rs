@@ -1,3 +1,6 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
 // A BufferAllocator is responsible for allocating indices in a large static buffer
 pub trait BufferAllocator<const SIZE: usize> {
     // get the next free index
@@ -7,51 +10,68 @@ pub trait BufferAllocator<const SIZE: usize> {
     fn remove(&mut self, index: usize);
 }
 
-// uses a simple stack based method for tracking free indices
-pub struct StackBufferAllocator<const SIZE: usize> {
+// Hands out the lowest free index, so the buffer stays packed towards the front, and can be compacted on demand
+pub struct CompactingBufferAllocator<const SIZE: usize> {
+    // Indices from the head up are yet to be given out
     buffer_head: usize,
-    // The highest index given out
-    returned_indices: Vec<usize>,
+    // Returned indices below the head, lowest on top
+    returned_indices: BinaryHeap<Reverse<usize>>,
 }
 
-impl<const SIZE: usize> StackBufferAllocator<SIZE> {
+impl<const SIZE: usize> CompactingBufferAllocator<SIZE> {
     pub fn new() -> Self {
-        StackBufferAllocator {
+        CompactingBufferAllocator {
             buffer_head: 0,
-            returned_indices: vec![],
+            returned_indices: BinaryHeap::new(),
         }
     }
 
-    fn compact(&mut self) {
-        self.reduce_head();
+    // len is how many indices are in use
+    pub fn len(&self) -> usize {
+        self.buffer_head - self.returned_indices.len()
+    }
+
+    // is_compact is whether the indices in use are exactly 0..len
+    pub fn is_compact(&self) -> bool {
+        self.returned_indices.is_empty()
     }
 
-    fn reduce_head(&mut self) {
-        // If possible, reduce the size of the stack by reducing the head
+    // compact moves the indices in use into 0..len, and returns (old, new) for each one that moved, sorted by old
+    // Whatever is stored at the old indices has to be moved to the new ones
+    pub fn compact(&mut self) -> Vec<(usize, usize)> {
+        let len = self.len();
+
+        let mut returned = std::mem::take(&mut self.returned_indices).into_sorted_vec();
+        // into_sorted_vec sorts by Reverse, so it's highest first
+        returned.reverse();
 
-        // TODO: this doesn't have to look at the whole stack
-        let mut partition_point = None;
-        for i in (0..self.returned_indices.len()).rev() {
-            if self.returned_indices[i] == (self.buffer_head - 1) {
-                partition_point = Some(i);
-                self.buffer_head -= 1;
+        // The holes below len are filled by the indices in use above it
+        let split = returned.partition_point(|i| i.0 < len);
+        let (holes, above) = returned.split_at(split);
+
+        let mut above = above.iter().map(|i| i.0).peekable();
+        let moving = (len..self.buffer_head).filter(|i| {
+            if above.peek() == Some(i) {
+                above.next();
+                return false;
             }
-        }
 
-        if let Some(i) = partition_point {
-            // The head has already been reduced, we just need to cut the old indices off the edge
-            assert_eq!(self.buffer_head, self.returned_indices[i]);
-            self.returned_indices.drain(0..(i + 1));
-        }
+            true
+        });
+
+        let remap: Vec<(usize, usize)> = moving.zip(holes.iter().map(|i| i.0)).collect();
+        assert_eq!(remap.len(), holes.len(), "every hole should be filled");
+
+        self.buffer_head = len;
+
+        remap
     }
 }
 
-impl<const SIZE: usize> BufferAllocator<SIZE> for StackBufferAllocator<SIZE> {
+impl<const SIZE: usize> BufferAllocator<SIZE> for CompactingBufferAllocator<SIZE> {
     fn insert(&mut self) -> usize {
-        // dbg!(self.buffer_head, &self.returned_indices);
-
         match self.returned_indices.pop() {
-            Some(i) => i,
+            Some(Reverse(i)) => i,
             None => {
                 let i = self.buffer_head;
                 self.buffer_head += 1;
@@ -64,15 +84,81 @@ impl<const SIZE: usize> BufferAllocator<SIZE> for StackBufferAllocator<SIZE> {
     }
 
     fn remove(&mut self, index: usize) {
-        // Use partition point to make sure returned_indices stays sorted
-        let idx = self.returned_indices.partition_point(|&x| x > index);
+        assert!(index < self.buffer_head, "index was never given out");
+
+        // The head only comes back down when compacting
+        self.returned_indices.push(Reverse(index))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
 
-        if idx == (self.buffer_head - 1) {
-            self.buffer_head -= 1;
-        } else {
-            self.returned_indices.insert(idx, index);
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use super::*;
+
+    const SIZE: usize = 1000;
+
+    #[test]
+    fn lowest_free_index_is_given_out() {
+        let mut allocator = CompactingBufferAllocator::<SIZE>::new();
+        for i in 0..5 {
+            assert_eq!(allocator.insert(), i);
         }
 
-        self.compact();
+        allocator.remove(3);
+        allocator.remove(1);
+
+        assert_eq!(allocator.len(), 3);
+        assert_eq!(allocator.insert(), 1);
+        assert_eq!(allocator.insert(), 3);
+        assert_eq!(allocator.insert(), 5);
+    }
+
+    #[test]
+    fn compact_fills_every_hole() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut allocator = CompactingBufferAllocator::<SIZE>::new();
+        let mut used = BTreeSet::new();
+
+        for _ in 0..100 {
+            // Fill up a bit, then punch holes anywhere, including right at the top
+            for _ in 0..rng.gen_range(0..50) {
+                if used.len() < SIZE / 2 {
+                    assert!(used.insert(allocator.insert()), "index was already in use");
+                }
+            }
+            for _ in 0..rng.gen_range(0..50) {
+                let Some(&index) = used.iter().nth(rng.gen_range(0..used.len().max(1))) else {
+                    break;
+                };
+                used.remove(&index);
+                allocator.remove(index);
+            }
+
+            if rng.gen_bool(0.3) {
+                let remap = allocator.compact();
+
+                let len = used.len();
+                assert!(allocator.is_compact());
+                assert_eq!(allocator.len(), len);
+                assert!(
+                    remap.windows(2).all(|w| w[0].0 < w[1].0),
+                    "remap should be sorted by old"
+                );
+                for &(old, new) in &remap {
+                    assert!(old >= len && new < len, "{old} -> {new} with {len} in use");
+                    assert!(used.remove(&old), "{old} wasn't in use");
+                    assert!(used.insert(new), "{new} was already in use");
+                }
+
+                assert!(used.iter().copied().eq(0..len));
+            }
+
+            assert_eq!(allocator.len(), used.len());
+        }
     }
 }
@@ -0,0 +1,226 @@
+use nalgebra::Point3;
+
+use crate::interval::Aabb;
+
+// A bounding volume hierarchy over boxes, used to find which items might matter at a point
+// without looking at every one of them. It only stores indices, the items themselves live elsewhere
+
+// BVH_LEAF_SIZE is the most items kept in a leaf
+const BVH_LEAF_SIZE: usize = 4;
+
+// Each node covers the items in order[start..end], branches split that range between their children
+#[derive(Debug)]
+struct BvhNode {
+    bounds: Aabb,
+    start: usize,
+    end: usize,
+    children: Option<(usize, usize)>,
+}
+
+#[derive(Debug, Default)]
+pub struct Bvh {
+    nodes: Vec<BvhNode>,
+
+    // Item indices, ordered so every leaf covers a contiguous range
+    order: Vec<usize>,
+    item_bounds: Vec<Aabb>,
+}
+
+impl Bvh {
+    // new builds a hierarchy over items with the given bounds, items are referred to by their index in `bounds`
+    pub fn new(bounds: Vec<Aabb>) -> Self {
+        let mut bvh = Bvh {
+            nodes: vec![],
+            order: (0..bounds.len()).collect(),
+            item_bounds: bounds,
+        };
+
+        if !bvh.item_bounds.is_empty() {
+            bvh.build(0, bvh.order.len());
+        }
+
+        bvh
+    }
+
+    pub fn len(&self) -> usize {
+        self.item_bounds.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.item_bounds.is_empty()
+    }
+
+    pub fn item_bounds(&self, item: usize) -> &Aabb {
+        &self.item_bounds[item]
+    }
+
+    // bounds is the box around every item, None if there aren't any
+    pub fn bounds(&self) -> Option<&Aabb> {
+        self.nodes.first().map(|n| &n.bounds)
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn node_bounds(&self, node: usize) -> &Aabb {
+        &self.nodes[node].bounds
+    }
+
+    // node_items is every item under `node`
+    pub fn node_items(&self, node: usize) -> &[usize] {
+        let node = &self.nodes[node];
+
+        &self.order[node.start..node.end]
+    }
+
+    // build creates the node for order[start..end] and returns its index
+    // Items are split at the median of their centers along the longest axis
+    fn build(&mut self, start: usize, end: usize) -> usize {
+        let bounds = self.order[start..end]
+            .iter()
+            .map(|i| self.item_bounds[*i])
+            .reduce(|a, b| a.union(&b))
+            .expect("nodes should never be empty");
+
+        let node = self.nodes.len();
+        self.nodes.push(BvhNode {
+            bounds,
+            start,
+            end,
+            children: None,
+        });
+
+        if end - start <= BVH_LEAF_SIZE {
+            return node;
+        }
+
+        let axis = bounds.size().imax();
+        let item_bounds = &self.item_bounds;
+        let middle = (start + end) / 2;
+        self.order[start..end].select_nth_unstable_by(middle - start, |a, b| {
+            let (a, b) = (
+                item_bounds[*a].center()[axis],
+                item_bounds[*b].center()[axis],
+            );
+            a.total_cmp(&b)
+        });
+
+        let left = self.build(start, middle);
+        let right = self.build(middle, end);
+        self.nodes[node].children = Some((left, right));
+
+        node
+    }
+
+    // visit walks down the hierarchy, skipping any box that `enter` returns false for
+    // `item` is called with every item whose own box was entered
+    pub fn visit(&self, enter: impl Fn(&Aabb) -> bool, mut item: impl FnMut(usize)) {
+        self.visit_nodes(
+            |_, bounds| enter(bounds),
+            |i| {
+                if enter(&self.item_bounds[i]) {
+                    item(i)
+                }
+            },
+        )
+    }
+
+    // visit_nodes is like visit, but `enter` is also given the node's index and items aren't checked against their own box
+    pub fn visit_nodes(
+        &self,
+        mut enter: impl FnMut(usize, &Aabb) -> bool,
+        mut item: impl FnMut(usize),
+    ) {
+        if self.nodes.is_empty() {
+            return;
+        }
+
+        // The tree is balanced, so this is deeper than it could ever need to be
+        let mut pending = [0; 64];
+        let mut pending_len = 1;
+        while pending_len > 0 {
+            pending_len -= 1;
+            let index = pending[pending_len];
+            let node = &self.nodes[index];
+            if !enter(index, &node.bounds) {
+                continue;
+            }
+
+            match node.children {
+                None => {
+                    for i in &self.order[node.start..node.end] {
+                        item(*i)
+                    }
+                }
+                Some((left, right)) => {
+                    pending[pending_len] = right;
+                    pending[pending_len + 1] = left;
+                    pending_len += 2;
+                }
+            }
+        }
+    }
+
+    // nearest finds the item closest to `at`, along with its distance
+    // `distance` gives the distance to an item, which can't be any less than the distance to the item's box
+    pub fn nearest(
+        &self,
+        at: Point3<f32>,
+        mut distance: impl FnMut(usize) -> f32,
+    ) -> Option<(usize, f32)> {
+        if self.nodes.is_empty() {
+            return None;
+        }
+
+        let mut best: Option<(usize, f32)> = None;
+
+        // Nearer children are visited first, so the best distance shrinks quickly and more gets skipped
+        let mut pending = [(0, 0.0); 64];
+        let mut pending_len = 1;
+        while pending_len > 0 {
+            pending_len -= 1;
+            let (index, box_distance) = pending[pending_len];
+            if best.is_some_and(|(_, d)| box_distance >= d) {
+                continue;
+            }
+
+            let node = &self.nodes[index];
+            match node.children {
+                None => {
+                    for i in &self.order[node.start..node.end] {
+                        let d = distance(*i);
+                        if best.is_none_or(|(_, best)| d < best) {
+                            best = Some((*i, d))
+                        }
+                    }
+                }
+                Some((left, right)) => {
+                    let near = (left, self.nodes[left].bounds.distance_to(at));
+                    let far = (right, self.nodes[right].bounds.distance_to(at));
+                    let (near, far) = if near.1 <= far.1 {
+                        (near, far)
+                    } else {
+                        (far, near)
+                    };
+
+                    pending[pending_len] = far;
+                    pending[pending_len + 1] = near;
+                    pending_len += 2;
+                }
+            }
+        }
+
+        best
+    }
+
+    // items_containing adds every item whose box contains `at` to `out`, in no particular order
+    pub fn items_containing(&self, at: Point3<f32>, out: &mut Vec<usize>) {
+        self.visit(|b| b.contains(at), |i| out.push(i))
+    }
+
+    // items_overlapping adds every item whose box overlaps `bounds` to `out`, in no particular order
+    pub fn items_overlapping(&self, bounds: &Aabb, out: &mut Vec<usize>) {
+        self.visit(|b| b.overlaps(bounds), |i| out.push(i))
+    }
+}
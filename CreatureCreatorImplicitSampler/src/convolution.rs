@@ -0,0 +1,181 @@
+use std::f32::consts::PI;
+
+use nalgebra::{Point3, Vector3};
+use serde::{Deserialize, Serialize};
+
+use crate::surface::{find_crossing, Surface};
+
+// Convolution surfaces integrate a kernel along a skeleton instead of summing it at points
+// Segments that share an end integrate to the same thing as one longer segment, so joints don't bulge
+// The Cauchy kernel 1/(1 + s²r²)² is used since it has closed form integrals along a segment
+// (McCormack and Sherstyuk, "Creating and rendering convolution surfaces")
+// The kernel never reaches zero, so every segment contributes to every sample
+
+// weight is how strongly a segment has to be weighted for an infinitely long one to have the given radius
+// Along an infinite line the field is weight * π / (2s(1 + s²r²)^(3/2)), this solves that for the field to be 1 at `radius`
+fn weight(radius: f32, sharpness: f32) -> f32 {
+    let s = sharpness;
+
+    2.0 * s * (1.0 + s * s * radius * radius).powf(1.5) / PI
+}
+
+// Segment is a piece of skeleton, the radius is interpolated between the ends
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Segment {
+    pub start: Point3<f32>,
+    pub end: Point3<f32>,
+    pub start_radius: f32,
+    pub end_radius: f32,
+}
+
+impl Segment {
+    pub fn new(start: Point3<f32>, end: Point3<f32>, start_radius: f32, end_radius: f32) -> Self {
+        Segment {
+            start,
+            end,
+            start_radius,
+            end_radius,
+        }
+    }
+
+    // contribution returns the integral of the weighted kernel along the segment and its gradient
+    fn contribution(&self, at: Point3<f32>, sharpness: f32) -> (f32, Vector3<f32>) {
+        let axis = self.end - self.start;
+        let length = axis.norm();
+        if length == 0.0 {
+            return (0.0, Vector3::zeros());
+        }
+
+        // Everything is integrated over τ, the distance along the segment measured from the closest point on its line
+        let u = axis / length;
+        let d = at - self.start;
+        let x = d.dot(&u);
+        let perpendicular = d - u * x;
+
+        let s = sharpness;
+        let b = s * s;
+        let a = 1.0 + b * perpendicular.norm_squared();
+        let p = a.sqrt();
+
+        // The weight is linear along the segment, w = alpha + beta * τ
+        let (w0, w1) = (weight(self.start_radius, s), weight(self.end_radius, s));
+        let beta = (w1 - w0) / length;
+        let alpha = w0 + beta * x;
+
+        // Antiderivatives of τⁿ/(a + bτ²)ᵐ, named after n and m
+        let antiderivatives = |t: f32| {
+            let q = a + b * t * t;
+
+            let i01 = (s * t / p).atan() / (s * p);
+            let i02 = t / (2.0 * a * q) + i01 / (2.0 * a);
+            let i03 = t / (4.0 * a * q * q) + 3.0 * i02 / (4.0 * a);
+            let i12 = -1.0 / (2.0 * b * q);
+            let i13 = -1.0 / (4.0 * b * q * q);
+            let i23 = (i02 - a * i03) / b;
+
+            [i02, i12, i03, i13, i23]
+        };
+
+        let (lo, hi) = (antiderivatives(-x), antiderivatives(length - x));
+        let [i02, i12, i03, i13, i23] = [0, 1, 2, 3, 4].map(|i| hi[i] - lo[i]);
+
+        let field = alpha * i02 + beta * i12;
+
+        // The kernel's derivative is -4s²(at - c)/(a + bτ²)³, and at - c = perpendicular - τu
+        let gradient = (perpendicular * (alpha * i03 + beta * i13)
+            - u * (alpha * i13 + beta * i23))
+            * (-4.0 * b);
+
+        (field, gradient)
+    }
+}
+
+// ConvolutionSurface is the surface where the summed contributions of its segments reach 1
+pub struct ConvolutionSurface {
+    segments: Vec<Segment>,
+    sharpness: f32,
+}
+
+impl ConvolutionSurface {
+    // Higher sharpness keeps nearby parts of the skeleton from blending together as much
+    pub fn new(sharpness: f32) -> Self {
+        assert!(sharpness > 0.0, "sharpness should be positive");
+
+        ConvolutionSurface {
+            segments: vec![],
+            sharpness,
+        }
+    }
+
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.segments.clear()
+    }
+
+    pub fn push_segment(&mut self, segment: Segment) {
+        self.segments.push(segment)
+    }
+
+    // push_polyline adds a segment between each pair of consecutive points, each point has its own radius
+    pub fn push_polyline(&mut self, points: &[(Point3<f32>, f32)]) {
+        for pair in points.windows(2) {
+            let ((start, start_radius), (end, end_radius)) = (pair[0], pair[1]);
+
+            self.segments
+                .push(Segment::new(start, end, start_radius, end_radius))
+        }
+    }
+}
+
+impl Surface for ConvolutionSurface {
+    fn sample(&self, at: Point3<f32>) -> f32 {
+        let field: f32 = self
+            .segments
+            .iter()
+            .map(|s| s.contribution(at, self.sharpness).0)
+            .sum();
+
+        1.0 - field
+    }
+
+    fn gradient(&self, at: Point3<f32>) -> Vector3<f32> {
+        -self
+            .segments
+            .iter()
+            .map(|s| s.contribution(at, self.sharpness).1)
+            .sum::<Vector3<f32>>()
+    }
+
+    // The field flattens out quickly away from the skeleton, so start the search from the middle of a segment
+    fn seed_hint(&self) -> Option<Point3<f32>> {
+        let segment = self.segments.iter().find(|s| s.start != s.end)?;
+
+        let middle = nalgebra::center(&segment.start, &segment.end);
+        if self.sample(middle) >= 0.0 {
+            return None;
+        }
+
+        let axis = segment.end - segment.start;
+        let across = axis
+            .cross(&Vector3::x())
+            .try_normalize(f32::EPSILON)
+            .unwrap_or_else(|| axis.cross(&Vector3::y()).normalize());
+
+        Some(find_crossing(
+            self,
+            middle,
+            across,
+            segment
+                .start_radius
+                .max(segment.end_radius)
+                .max(1.0 / self.sharpness),
+        ))
+    }
+}
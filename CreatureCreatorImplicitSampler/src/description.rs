@@ -0,0 +1,703 @@
+use std::fmt;
+
+use nalgebra::{Matrix4, Point3, Quaternion, UnitQuaternion, Vector3};
+use serde::{Deserialize, Serialize};
+
+use crate::convolution::{ConvolutionSurface, Segment};
+use crate::metaballs::{Ball, Falloff, Metaballs};
+use crate::mirror::Mirror;
+use crate::noise::{displacement_slope, Displaced, Noise, MAX_SLOPE};
+use crate::plane::Plane;
+use crate::repeat::{Layout, Repeat};
+use crate::shapes::{Difference, Ellipsoid, Intersection, SmoothUnion, Sphere, Transformed, Union};
+use crate::surface::Surface;
+use crate::tape::{Compile, Tape};
+
+// A Description is a surface tree as plain data, so it can be saved to a file and built back into a live Surface later
+// Files are JSON or RON, and carry the schema version they were written with
+
+// SCHEMA_VERSION goes up whenever a change to these types means older files would load differently, or not at all
+pub const SCHEMA_VERSION: u32 = 1;
+
+// BuiltSurface is what a description turns into, it can be handed to another thread
+pub type BuiltSurface = Box<dyn Surface + Send + Sync>;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Description {
+    pub version: u32,
+    pub root: Node,
+}
+
+// Node is a single primitive or combinator in the tree
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Node {
+    Sphere {
+        radius: f32,
+    },
+    Ellipsoid {
+        size: [f32; 3],
+    },
+    Metaballs {
+        falloff: Falloff,
+        threshold: f32,
+        balls: Vec<Ball>,
+    },
+    Convolution {
+        sharpness: f32,
+        segments: Vec<Segment>,
+    },
+
+    Transformed {
+        transform: Transform,
+        surface: Box<Node>,
+    },
+    // The surface unioned with its reflection across the plane through `origin` facing `normal`
+    Mirror {
+        origin: [f32; 3],
+        normal: [f32; 3],
+        blend: f32,
+        surface: Box<Node>,
+    },
+    Displaced {
+        noise: Noise,
+        amplitude: f32,
+        frequency: f32,
+        surface: Box<Node>,
+    },
+    // `count` copies of the surface, scaled from 1 at the first down to `end_scale` at the last
+    Repeat {
+        layout: Layout,
+        count: usize,
+        #[serde(default = "Node::no_taper")]
+        end_scale: f32,
+        surface: Box<Node>,
+    },
+
+    // Combinators take any number of surfaces, these apply to all of them in order
+    Union(Vec<Node>),
+    Intersection(Vec<Node>),
+    SmoothUnion {
+        k: f32,
+        surfaces: Vec<Node>,
+    },
+    // Difference removes the second surface from the first
+    Difference(Box<Node>, Box<Node>),
+}
+
+// Transform is kept as separate parts instead of a matrix, so files stay readable and diff nicely
+// They're applied scale first, then rotation, then translation. Any of them can be left out of a file
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Transform {
+    #[serde(default)]
+    pub translation: [f32; 3],
+    // A quaternion as [x, y, z, w], it gets normalized when it's built
+    #[serde(default = "Transform::no_rotation")]
+    pub rotation: [f32; 4],
+    #[serde(default = "Transform::no_scale")]
+    pub scale: [f32; 3],
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Transform {
+            translation: [0.0; 3],
+            rotation: Transform::no_rotation(),
+            scale: Transform::no_scale(),
+        }
+    }
+}
+
+impl Transform {
+    fn no_rotation() -> [f32; 4] {
+        [0.0, 0.0, 0.0, 1.0]
+    }
+
+    fn no_scale() -> [f32; 3] {
+        [1.0; 3]
+    }
+
+    pub fn matrix(&self) -> Matrix4<f32> {
+        let [x, y, z, w] = self.rotation;
+        let rotation = UnitQuaternion::from_quaternion(Quaternion::new(w, x, y, z));
+
+        Matrix4::new_translation(&Vector3::from(self.translation))
+            * rotation.to_homogeneous()
+            * Matrix4::new_nonuniform_scaling(&Vector3::from(self.scale))
+    }
+}
+
+#[derive(Debug)]
+pub enum DescriptionError {
+    Json(serde_json::Error),
+    Ron(ron::error::SpannedError),
+    // The file was written with a schema version this build doesn't know how to read
+    Version(u32),
+    // The file parsed, but something in it is out of range, like a negative radius or an empty union
+    Invalid(String),
+    // The tree has a node with no tape instructions, so it can be built but not compiled
+    Uncompilable(&'static str),
+}
+
+impl fmt::Display for DescriptionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DescriptionError::Json(e) => write!(f, "invalid JSON surface description: {e}"),
+            DescriptionError::Ron(e) => write!(f, "invalid RON surface description: {e}"),
+            DescriptionError::Version(v) => write!(
+                f,
+                "surface description has schema version {v}, only version {SCHEMA_VERSION} is supported"
+            ),
+            DescriptionError::Invalid(problem) => write!(f, "invalid surface description: {problem}"),
+            DescriptionError::Uncompilable(node) => {
+                write!(f, "surface description can't be compiled, {node} has no tape instructions")
+            }
+        }
+    }
+}
+
+impl std::error::Error for DescriptionError {}
+
+// Just the version, read first so a newer file is reported as such instead of as whatever part of it fails to parse
+#[derive(Deserialize)]
+struct Versioned {
+    version: u32,
+}
+
+fn check_version(version: u32) -> Result<(), DescriptionError> {
+    if version != SCHEMA_VERSION {
+        return Err(DescriptionError::Version(version));
+    }
+
+    Ok(())
+}
+
+impl Description {
+    pub fn new(root: Node) -> Self {
+        Description {
+            version: SCHEMA_VERSION,
+            root,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("descriptions should always serialize")
+    }
+
+    pub fn from_json(source: &str) -> Result<Self, DescriptionError> {
+        let versioned: Versioned = serde_json::from_str(source).map_err(DescriptionError::Json)?;
+        check_version(versioned.version)?;
+
+        serde_json::from_str(source).map_err(DescriptionError::Json)
+    }
+
+    pub fn to_ron(&self) -> String {
+        ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())
+            .expect("descriptions should always serialize")
+    }
+
+    pub fn from_ron(source: &str) -> Result<Self, DescriptionError> {
+        let versioned: Versioned = ron::from_str(source).map_err(DescriptionError::Ron)?;
+        check_version(versioned.version)?;
+
+        ron::from_str(source).map_err(DescriptionError::Ron)
+    }
+
+    pub fn build(&self) -> Result<BuiltSurface, DescriptionError> {
+        self.root.build()
+    }
+
+    pub fn compile(&self) -> Result<Tape, DescriptionError> {
+        self.root.compile()
+    }
+}
+
+impl Node {
+    // build turns the description into a surface that can be sampled
+    // Everything is checked first, so a bad file gives an error instead of a panic or a field full of NaN
+    pub fn build(&self) -> Result<BuiltSurface, DescriptionError> {
+        self.validate()?;
+
+        Ok(self.build_valid())
+    }
+
+    // compile flattens the description into a Tape, which can also be bounded over intervals and differentiated exactly
+    // Metaballs, convolution, displacement and repetition have no tape instructions, so trees using them can't be compiled
+    pub fn compile(&self) -> Result<Tape, DescriptionError> {
+        self.validate()?;
+
+        let compiled = self.compile_valid()?;
+
+        Ok(Tape::compile(&compiled))
+    }
+
+    // validate checks everything the surfaces would otherwise assert on, or that would make the field NaN
+    fn validate(&self) -> Result<(), DescriptionError> {
+        let check = |ok: bool, problem: &str| {
+            if ok {
+                Ok(())
+            } else {
+                Err(DescriptionError::Invalid(problem.to_string()))
+            }
+        };
+        let finite = |v: &[f32]| v.iter().all(|c| c.is_finite());
+
+        match self {
+            Node::Sphere { radius } => check(radius.is_finite(), "sphere radius should be finite"),
+            Node::Ellipsoid { size } => check(
+                size.iter().all(|s| *s > 0.0 && s.is_finite()),
+                "ellipsoid size should be positive",
+            ),
+            Node::Metaballs {
+                falloff,
+                threshold,
+                balls,
+            } => {
+                check(falloff.is_valid(), "metaball falloff should be valid")?;
+                check(*threshold > 0.0, "metaball threshold should be positive")?;
+                check(
+                    balls.iter().all(Ball::is_valid),
+                    "metaballs need a positive radius and finite values",
+                )
+            }
+            Node::Convolution {
+                sharpness,
+                segments,
+            } => {
+                check(*sharpness > 0.0, "convolution sharpness should be positive")?;
+                check(
+                    segments.iter().all(|s| {
+                        finite(&[s.start_radius, s.end_radius])
+                            && finite(s.start.coords.as_slice())
+                            && finite(s.end.coords.as_slice())
+                    }),
+                    "convolution segments should be finite",
+                )
+            }
+
+            Node::Transformed { transform, surface } => {
+                let matrix = transform.matrix();
+                check(
+                    matrix.iter().all(|c| c.is_finite())
+                        && matrix
+                            .try_inverse()
+                            .is_some_and(|i| i.iter().all(|c| c.is_finite())),
+                    "transform should be invertible",
+                )?;
+
+                surface.validate()
+            }
+            Node::Mirror {
+                origin,
+                normal,
+                blend,
+                surface,
+            } => {
+                check(
+                    finite(origin) && finite(normal) && Vector3::from(*normal).norm() > 0.0,
+                    "mirror plane needs a finite origin and a nonzero normal",
+                )?;
+                check(*blend >= 0.0, "mirror blend can't be negative")?;
+
+                surface.validate()
+            }
+            Node::Displaced {
+                noise,
+                amplitude,
+                frequency,
+                surface,
+            } => {
+                check(
+                    noise.is_valid(),
+                    "fractal noise should have at least one octave, and positive lacunarity and gain",
+                )?;
+                check(
+                    *amplitude >= 0.0,
+                    "displacement amplitude can't be negative",
+                )?;
+                check(
+                    *frequency > 0.0,
+                    "displacement frequency should be positive",
+                )?;
+                check(
+                    displacement_slope(noise, *amplitude, *frequency) <= MAX_SLOPE,
+                    "displacement is too steep",
+                )?;
+
+                surface.validate()
+            }
+            Node::Repeat {
+                layout,
+                count,
+                end_scale,
+                surface,
+            } => {
+                check(layout.is_valid(), "repeat layout should be valid")?;
+                check(*count >= 1, "repeat needs at least one instance")?;
+                check(
+                    *end_scale > 0.0 && end_scale.is_finite(),
+                    "repeat end scale should be positive",
+                )?;
+
+                surface.validate()
+            }
+
+            Node::Union(surfaces) | Node::Intersection(surfaces) => Self::validate_all(surfaces),
+            Node::SmoothUnion { k, surfaces } => {
+                check(k.is_finite(), "smooth union size should be finite")?;
+
+                Self::validate_all(surfaces)
+            }
+            Node::Difference(a, b) => {
+                a.validate()?;
+                b.validate()
+            }
+        }
+    }
+
+    fn validate_all(surfaces: &[Node]) -> Result<(), DescriptionError> {
+        if surfaces.is_empty() {
+            return Err(DescriptionError::Invalid(
+                "combinators should have at least one surface".to_string(),
+            ));
+        }
+
+        surfaces.iter().try_for_each(Node::validate)
+    }
+
+    fn build_valid(&self) -> BuiltSurface {
+        match self {
+            Node::Sphere { radius } => Box::new(Sphere::new(*radius)),
+            Node::Ellipsoid { size } => Box::new(Ellipsoid::new(Vector3::from(*size))),
+            Node::Metaballs {
+                falloff,
+                threshold,
+                balls,
+            } => Box::new(Metaballs::from_balls(balls.clone(), *falloff, *threshold)),
+            Node::Convolution {
+                sharpness,
+                segments,
+            } => {
+                let mut surface = ConvolutionSurface::new(*sharpness);
+                for segment in segments {
+                    surface.push_segment(*segment)
+                }
+
+                Box::new(surface)
+            }
+
+            Node::Transformed { transform, surface } => {
+                Box::new(Transformed::new(surface.build_valid(), transform.matrix()))
+            }
+            Node::Mirror {
+                origin,
+                normal,
+                blend,
+                surface,
+            } => {
+                let plane =
+                    Plane::from_origin_normal(Point3::from(*origin), Vector3::from(*normal));
+
+                Box::new(Mirror::new(surface.build_valid(), plane, *blend))
+            }
+            Node::Displaced {
+                noise,
+                amplitude,
+                frequency,
+                surface,
+            } => Box::new(Displaced::new(
+                surface.build_valid(),
+                *noise,
+                *amplitude,
+                *frequency,
+            )),
+            Node::Repeat {
+                layout,
+                count,
+                end_scale,
+                surface,
+            } => {
+                let (count, end_scale) = (*count, *end_scale);
+                let taper = move |i: usize| {
+                    let t = if count > 1 {
+                        i as f32 / (count - 1) as f32
+                    } else {
+                        0.0
+                    };
+
+                    1.0 + (end_scale - 1.0) * t
+                };
+
+                Box::new(Repeat::new(
+                    surface.build_valid(),
+                    layout.clone(),
+                    count,
+                    taper,
+                ))
+            }
+
+            Node::Union(surfaces) => Self::fold(surfaces, |a, b| Box::new(Union(a, b))),
+            Node::Intersection(surfaces) => {
+                Self::fold(surfaces, |a, b| Box::new(Intersection(a, b)))
+            }
+            Node::SmoothUnion { k, surfaces } => {
+                Self::fold(surfaces, |a, b| Box::new(SmoothUnion::new(a, b, *k)))
+            }
+            Node::Difference(a, b) => Box::new(Difference(a.build_valid(), b.build_valid())),
+        }
+    }
+
+    fn no_taper() -> f32 {
+        1.0
+    }
+
+    fn fold(
+        surfaces: &[Node],
+        combine: impl Fn(BuiltSurface, BuiltSurface) -> BuiltSurface,
+    ) -> BuiltSurface {
+        surfaces
+            .iter()
+            .map(Node::build_valid)
+            .reduce(combine)
+            .expect("combinators should have at least one surface")
+    }
+
+    // compile_valid is build_valid for the nodes that can go on a tape
+    fn compile_valid(&self) -> Result<Box<dyn Compile>, DescriptionError> {
+        let unsupported = |node: &'static str| Err(DescriptionError::Uncompilable(node));
+
+        Ok(match self {
+            Node::Sphere { radius } => Box::new(Sphere::new(*radius)),
+            Node::Ellipsoid { size } => Box::new(Ellipsoid::new(Vector3::from(*size))),
+            Node::Metaballs { .. } => return unsupported("Metaballs"),
+            Node::Convolution { .. } => return unsupported("Convolution"),
+
+            Node::Transformed { transform, surface } => Box::new(Transformed::new(
+                surface.compile_valid()?,
+                transform.matrix(),
+            )),
+            Node::Mirror {
+                origin,
+                normal,
+                blend,
+                surface,
+            } => {
+                let plane =
+                    Plane::from_origin_normal(Point3::from(*origin), Vector3::from(*normal));
+
+                Box::new(Mirror::new(surface.compile_valid()?, plane, *blend))
+            }
+            Node::Displaced { .. } => return unsupported("Displaced"),
+            Node::Repeat { .. } => return unsupported("Repeat"),
+
+            Node::Union(surfaces) => Self::fold_compiled(surfaces, |a, b| Box::new(Union(a, b)))?,
+            Node::Intersection(surfaces) => {
+                Self::fold_compiled(surfaces, |a, b| Box::new(Intersection(a, b)))?
+            }
+            Node::SmoothUnion { k, surfaces } => {
+                Self::fold_compiled(surfaces, |a, b| Box::new(SmoothUnion::new(a, b, *k)))?
+            }
+            Node::Difference(a, b) => Box::new(Difference(a.compile_valid()?, b.compile_valid()?)),
+        })
+    }
+
+    fn fold_compiled(
+        surfaces: &[Node],
+        combine: impl Fn(Box<dyn Compile>, Box<dyn Compile>) -> Box<dyn Compile>,
+    ) -> Result<Box<dyn Compile>, DescriptionError> {
+        let mut compiled = surfaces.iter().map(Node::compile_valid);
+        let first = compiled
+            .next()
+            .expect("combinators should have at least one surface")?;
+
+        compiled.try_fold(first, |a, b| Ok(combine(a, b?)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::noise::{Basis, Fractal};
+    use nalgebra::point;
+
+    // One of every node, nested so each combinator has something under it
+    fn every_node() -> Node {
+        let sphere = Node::Sphere { radius: 0.5 };
+        let ellipsoid = Node::Ellipsoid {
+            size: [1.0, 0.5, 0.25],
+        };
+
+        Node::Union(vec![
+            Node::Transformed {
+                transform: Transform {
+                    translation: [0.1, -0.2, 0.3],
+                    rotation: [0.0, 0.382_683_43, 0.0, 0.923_879_5],
+                    scale: [1.0, 2.0, 1.5],
+                },
+                surface: Box::new(sphere.clone()),
+            },
+            Node::Mirror {
+                origin: [0.0, 0.0, 0.0],
+                normal: [1.0, 0.0, 0.0],
+                blend: 0.1,
+                surface: Box::new(Node::Metaballs {
+                    falloff: Falloff::Blinn { blobbiness: 2.0 },
+                    threshold: 0.5,
+                    balls: vec![
+                        Ball::new(point![0.5, 0.0, 0.0], 1.0, 1.0),
+                        Ball::new(point![1.0, 0.5, 0.0], 0.75, 0.8),
+                    ],
+                }),
+            },
+            Node::Displaced {
+                noise: Noise::new(
+                    Basis::Worley,
+                    Fractal::Fbm {
+                        octaves: 3,
+                        lacunarity: 2.0,
+                        gain: 0.5,
+                    },
+                    7,
+                ),
+                amplitude: 0.01,
+                frequency: 2.0,
+                surface: Box::new(Node::Convolution {
+                    sharpness: 4.0,
+                    segments: vec![Segment::new(
+                        point![0.0, 0.0, 0.0],
+                        point![0.0, 1.0, 0.0],
+                        0.3,
+                        0.1,
+                    )],
+                }),
+            },
+            Node::Repeat {
+                layout: Layout::Linear {
+                    direction: Vector3::x(),
+                    spacing: 0.5,
+                },
+                count: 4,
+                end_scale: 0.5,
+                surface: Box::new(ellipsoid.clone()),
+            },
+            Node::Intersection(vec![sphere.clone(), ellipsoid.clone()]),
+            Node::SmoothUnion {
+                k: 0.2,
+                surfaces: vec![sphere.clone(), ellipsoid.clone()],
+            },
+            Node::Difference(Box::new(ellipsoid), Box::new(sphere)),
+        ])
+    }
+
+    // The names of every kind of node in the tree. The match has no wildcard, so a new node won't compile until
+    // it's been added to every_node
+    fn kinds(node: &Node, found: &mut Vec<&'static str>) {
+        let (kind, children): (_, Vec<&Node>) = match node {
+            Node::Sphere { .. } => ("Sphere", vec![]),
+            Node::Ellipsoid { .. } => ("Ellipsoid", vec![]),
+            Node::Metaballs { .. } => ("Metaballs", vec![]),
+            Node::Convolution { .. } => ("Convolution", vec![]),
+            Node::Transformed { surface, .. } => ("Transformed", vec![surface]),
+            Node::Mirror { surface, .. } => ("Mirror", vec![surface]),
+            Node::Displaced { surface, .. } => ("Displaced", vec![surface]),
+            Node::Repeat { surface, .. } => ("Repeat", vec![surface]),
+            Node::Union(surfaces) => ("Union", surfaces.iter().collect()),
+            Node::Intersection(surfaces) => ("Intersection", surfaces.iter().collect()),
+            Node::SmoothUnion { surfaces, .. } => ("SmoothUnion", surfaces.iter().collect()),
+            Node::Difference(a, b) => ("Difference", vec![a, b]),
+        };
+
+        if !found.contains(&kind) {
+            found.push(kind)
+        }
+        for child in children {
+            kinds(child, found)
+        }
+    }
+
+    #[test]
+    fn every_node_is_covered() {
+        let mut found = Vec::new();
+        kinds(&every_node(), &mut found);
+
+        assert_eq!(found.len(), 12, "found {found:?}");
+    }
+
+    #[test]
+    fn json_round_trips() {
+        let description = Description::new(every_node());
+
+        let loaded = Description::from_json(&description.to_json()).unwrap();
+
+        assert_eq!(loaded, description);
+        assert!(loaded.build().is_ok());
+    }
+
+    #[test]
+    fn ron_round_trips() {
+        let description = Description::new(every_node());
+
+        let loaded = Description::from_ron(&description.to_ron()).unwrap();
+
+        assert_eq!(loaded, description);
+        assert!(loaded.build().is_ok());
+    }
+
+    #[test]
+    fn other_versions_are_reported() {
+        // The root is from some future schema, the version should be what's reported rather than the unknown node
+        let json = format!(
+            r#"{{"version": {}, "root": {{"Torus": {{"radius": 1.0}}}}}}"#,
+            SCHEMA_VERSION + 1
+        );
+        let ron = format!(
+            "(version: {}, root: Torus(radius: 1.0))",
+            SCHEMA_VERSION + 1
+        );
+
+        assert!(matches!(
+            Description::from_json(&json),
+            Err(DescriptionError::Version(v)) if v == SCHEMA_VERSION + 1
+        ));
+        assert!(matches!(
+            Description::from_ron(&ron),
+            Err(DescriptionError::Version(v)) if v == SCHEMA_VERSION + 1
+        ));
+    }
+
+    #[test]
+    fn invalid_nodes_are_reported() {
+        let invalid = [
+            Node::Ellipsoid {
+                size: [1.0, -1.0, 1.0],
+            },
+            Node::Union(vec![]),
+            Node::Mirror {
+                origin: [0.0; 3],
+                normal: [0.0; 3],
+                blend: 0.0,
+                surface: Box::new(Node::Sphere { radius: 1.0 }),
+            },
+            // Only the node deep inside the tree is wrong
+            Node::Difference(
+                Box::new(Node::Sphere { radius: 1.0 }),
+                Box::new(Node::SmoothUnion {
+                    k: 0.1,
+                    surfaces: vec![Node::Ellipsoid {
+                        size: [1.0, 0.0, 1.0],
+                    }],
+                }),
+            ),
+        ];
+
+        for node in invalid {
+            // It still parses, it's only once it's built that it's checked
+            let loaded = Description::from_json(&Description::new(node).to_json()).unwrap();
+
+            assert!(matches!(loaded.build(), Err(DescriptionError::Invalid(_))));
+            assert!(matches!(
+                loaded.compile(),
+                Err(DescriptionError::Invalid(_))
+            ));
+        }
+    }
+}
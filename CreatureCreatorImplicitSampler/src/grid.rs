@@ -0,0 +1,223 @@
+use nalgebra::{Point3, Vector3};
+
+use crate::interval::Aabb;
+use crate::surface::Surface;
+
+// GridSurface stores samples of a field at the points of a regular grid and interpolates between them
+// It's meant for signed distances, from sculpting or import, or baked from a tree that's too slow to sample directly
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Interpolation {
+    // Cheap, but the gradient jumps at every cell boundary
+    Trilinear,
+    // Catmull-Rom splines along each axis, the gradient is continuous
+    Tricubic,
+}
+
+// Flat bounds would make the step along that axis 0, and every sample NaN
+fn assert_bounds(bounds: &Aabb) {
+    assert!(
+        bounds.size().iter().all(|s| *s > 0.0 && s.is_finite()),
+        "grid bounds should have some size along each axis"
+    );
+}
+
+pub struct GridSurface {
+    bounds: Aabb,
+    // Number of grid points along each axis
+    counts: [usize; 3],
+    step: Vector3<f32>,
+    interpolation: Interpolation,
+
+    // Values are stored x first, then y, then z
+    values: Vec<f32>,
+}
+
+impl GridSurface {
+    // new makes a grid from existing values, the first and last points along each axis sit on the bounds
+    pub fn new(
+        bounds: Aabb,
+        counts: [usize; 3],
+        values: Vec<f32>,
+        interpolation: Interpolation,
+    ) -> Self {
+        assert!(
+            counts.iter().all(|c| *c >= 2),
+            "the grid needs at least 2 points along each axis"
+        );
+        assert_bounds(&bounds);
+        assert_eq!(
+            values.len(),
+            counts[0] * counts[1] * counts[2],
+            "there should be a value for every grid point"
+        );
+
+        let step = Vector3::from_fn(|i, _| bounds.size()[i] / (counts[i] - 1) as f32);
+
+        GridSurface {
+            bounds,
+            counts,
+            step,
+            interpolation,
+            values,
+        }
+    }
+
+    // bake samples `surface` over `bounds` with grid points no more than `resolution` apart
+    pub fn bake<S: Surface + ?Sized>(
+        surface: &S,
+        bounds: Aabb,
+        resolution: f32,
+        interpolation: Interpolation,
+    ) -> Self {
+        assert!(resolution > 0.0, "resolution should be positive");
+        assert_bounds(&bounds);
+
+        let size = bounds.size();
+        let counts = [0, 1, 2].map(|i| (size[i] / resolution).ceil() as usize + 1);
+        let counts = counts.map(|c| c.max(2));
+
+        let step = Vector3::from_fn(|i, _| size[i] / (counts[i] - 1) as f32);
+
+        let mut points = Vec::with_capacity(counts[0] * counts[1] * counts[2]);
+        for z in 0..counts[2] {
+            for y in 0..counts[1] {
+                for x in 0..counts[0] {
+                    let offset = Vector3::new(x as f32, y as f32, z as f32).component_mul(&step);
+                    points.push(bounds.min + offset)
+                }
+            }
+        }
+
+        let mut values = vec![0.0; points.len()];
+        surface.sample_batch(&points, &mut values);
+
+        GridSurface::new(bounds, counts, values, interpolation)
+    }
+
+    pub fn bounds(&self) -> &Aabb {
+        &self.bounds
+    }
+
+    pub fn counts(&self) -> [usize; 3] {
+        self.counts
+    }
+
+    pub fn values(&self) -> &[f32] {
+        &self.values
+    }
+
+    pub fn interpolation(&self) -> Interpolation {
+        self.interpolation
+    }
+
+    pub fn set_interpolation(&mut self, interpolation: Interpolation) {
+        self.interpolation = interpolation
+    }
+
+    pub fn value_at(&self, x: usize, y: usize, z: usize) -> f32 {
+        self.values[x + self.counts[0] * (y + self.counts[1] * z)]
+    }
+
+    // taps gives the 4 grid indices along `axis` around `coordinate`, with their weights and the derivatives
+    // of their weights with respect to the coordinate in grid units
+    fn taps(&self, axis: usize, coordinate: f32) -> ([usize; 4], [f32; 4], [f32; 4]) {
+        let last = self.counts[axis] - 1;
+
+        let u = (coordinate - self.bounds.min[axis]) / self.step[axis];
+        let i = (u.floor().max(0.0) as usize).min(last - 1);
+        let t = u - i as f32;
+
+        // Taps past the edges repeat the edge value
+        let indices = [i.saturating_sub(1), i, i + 1, (i + 2).min(last)];
+
+        let (weights, derivatives) = match self.interpolation {
+            Interpolation::Trilinear => ([0.0, 1.0 - t, t, 0.0], [0.0, -1.0, 1.0, 0.0]),
+            Interpolation::Tricubic => {
+                let (t2, t3) = (t * t, t * t * t);
+
+                (
+                    [
+                        (-t3 + 2.0 * t2 - t) / 2.0,
+                        (3.0 * t3 - 5.0 * t2 + 2.0) / 2.0,
+                        (-3.0 * t3 + 4.0 * t2 + t) / 2.0,
+                        (t3 - t2) / 2.0,
+                    ],
+                    [
+                        (-3.0 * t2 + 4.0 * t - 1.0) / 2.0,
+                        (9.0 * t2 - 10.0 * t) / 2.0,
+                        (-9.0 * t2 + 8.0 * t + 1.0) / 2.0,
+                        (3.0 * t2 - 2.0 * t) / 2.0,
+                    ],
+                )
+            }
+        };
+
+        (indices, weights, derivatives)
+    }
+
+    // interpolate returns the value and gradient at a point inside the bounds
+    fn interpolate(&self, at: Point3<f32>) -> (f32, Vector3<f32>) {
+        let (xi, xw, xd) = self.taps(0, at.x);
+        let (yi, yw, yd) = self.taps(1, at.y);
+        let (zi, zw, zd) = self.taps(2, at.z);
+
+        // Only the middle two taps have any weight when interpolating linearly
+        let taps = match self.interpolation {
+            Interpolation::Trilinear => 1..3,
+            Interpolation::Tricubic => 0..4,
+        };
+
+        let mut value = 0.0;
+        let mut gradient = Vector3::zeros();
+        for c in taps.clone() {
+            for b in taps.clone() {
+                for a in taps.clone() {
+                    let v = self.value_at(xi[a], yi[b], zi[c]);
+
+                    value += xw[a] * yw[b] * zw[c] * v;
+                    gradient += Vector3::new(
+                        xd[a] * yw[b] * zw[c],
+                        xw[a] * yd[b] * zw[c],
+                        xw[a] * yw[b] * zd[c],
+                    ) * v;
+                }
+            }
+        }
+
+        // Weight derivatives are per grid step, not per unit distance
+        (value, gradient.component_div(&self.step))
+    }
+
+    // evaluate handles points outside the bounds by taking the value at the closest point inside them,
+    // plus the distance from there. That's as far as the distance could possibly have grown
+    fn evaluate(&self, at: Point3<f32>) -> (f32, Vector3<f32>) {
+        let inside = at.sup(&self.bounds.min).inf(&self.bounds.max);
+        let (value, mut gradient) = self.interpolate(inside);
+
+        let outside = at - inside;
+        let distance = outside.magnitude();
+        if distance == 0.0 {
+            return (value, gradient);
+        }
+
+        // Along the axes that were clamped, the field grows with the distance instead
+        for i in 0..3 {
+            if outside[i] != 0.0 {
+                gradient[i] = 0.0
+            }
+        }
+
+        (value + distance, gradient + outside / distance)
+    }
+}
+
+impl Surface for GridSurface {
+    fn sample(&self, at: Point3<f32>) -> f32 {
+        self.evaluate(at).0
+    }
+
+    fn gradient(&self, at: Point3<f32>) -> Vector3<f32> {
+        self.evaluate(at).1
+    }
+}
@@ -0,0 +1,141 @@
+// ParticleHandle picks out one particle for as long as it lives
+// Particle indices are handed to new particles as soon as the old ones die, so they can't be held on to between
+// updates. Each slot a handle points at has a generation that goes up whenever its particle dies, so a handle to a
+// dead particle is caught instead of silently finding whichever particle took its place
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct ParticleHandle {
+    slot: u32,
+    generation: u32,
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Slot {
+    generation: u32,
+    // Where the particle is in the sampler's buffers, None once it's dead
+    index: Option<usize>,
+}
+
+// HandleTable maps handles to particle indices
+// Particles carry their slot around with them, so handles don't change when particles are moved about the buffers
+pub(crate) struct HandleTable {
+    slots: Vec<Slot>,
+    free_slots: Vec<u32>,
+}
+
+impl HandleTable {
+    pub fn new() -> Self {
+        HandleTable {
+            slots: vec![],
+            free_slots: vec![],
+        }
+    }
+
+    // insert gives the particle at `index` a slot, which should be kept with the particle
+    pub fn insert(&mut self, index: usize) -> u32 {
+        match self.free_slots.pop() {
+            Some(slot) => {
+                self.slots[slot as usize].index = Some(index);
+                slot
+            }
+            None => {
+                self.slots.push(Slot {
+                    generation: 0,
+                    index: Some(index),
+                });
+                (self.slots.len() - 1) as u32
+            }
+        }
+    }
+
+    // remove is called when a particle dies, every handle to it goes stale
+    pub fn remove(&mut self, slot: u32) {
+        let s = &mut self.slots[slot as usize];
+        assert!(s.index.is_some(), "slot should be in use");
+
+        s.index = None;
+        s.generation = s.generation.wrapping_add(1);
+
+        self.free_slots.push(slot)
+    }
+
+    // relocate is for when the particle in `slot` has been moved to another index
+    pub fn relocate(&mut self, slot: u32, index: usize) {
+        let s = &mut self.slots[slot as usize];
+        assert!(s.index.is_some(), "slot should be in use");
+
+        s.index = Some(index)
+    }
+
+    pub fn handle(&self, slot: u32) -> ParticleHandle {
+        ParticleHandle {
+            slot,
+            generation: self.slots[slot as usize].generation,
+        }
+    }
+
+    // index is where the handle's particle is, None if it's died
+    pub fn index(&self, handle: ParticleHandle) -> Option<usize> {
+        let s = self.slots.get(handle.slot as usize)?;
+        if s.generation != handle.generation {
+            return None;
+        }
+
+        s.index
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn handles_follow_their_particle() {
+        let mut table = HandleTable::new();
+        let a = table.insert(0);
+        let b = table.insert(1);
+        let (handle_a, handle_b) = (table.handle(a), table.handle(b));
+
+        table.relocate(a, 5);
+
+        assert_eq!(table.index(handle_a), Some(5));
+        assert_eq!(table.index(handle_b), Some(1));
+    }
+
+    #[test]
+    fn handles_go_stale_when_their_particle_dies() {
+        let mut table = HandleTable::new();
+        let a = table.insert(0);
+        let b = table.insert(1);
+        let (handle_a, handle_b) = (table.handle(a), table.handle(b));
+
+        table.remove(a);
+
+        assert_eq!(table.index(handle_a), None);
+        assert_eq!(table.index(handle_b), Some(1));
+    }
+
+    #[test]
+    fn handles_stay_stale_when_their_slot_is_reused() {
+        let mut table = HandleTable::new();
+        let a = table.insert(0);
+        let old = table.handle(a);
+
+        table.remove(a);
+        // The new particle gets the same slot, and even the same index
+        let c = table.insert(0);
+        assert_eq!(c, a);
+        let new = table.handle(c);
+
+        assert_ne!(new, old);
+        assert_eq!(table.index(old), None);
+        assert_eq!(table.index(new), Some(0));
+
+        // And again, so it isn't just the first reuse that's caught
+        table.remove(c);
+        let d = table.insert(3);
+
+        assert_eq!(table.index(old), None);
+        assert_eq!(table.index(new), None);
+        assert_eq!(table.index(table.handle(d)), Some(3));
+    }
+}
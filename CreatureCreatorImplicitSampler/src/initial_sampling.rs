@@ -1,95 +1,282 @@
-use std::f64::consts::PI;
+use std::collections::VecDeque;
+use std::f32::consts::{FRAC_PI_2, PI};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
-use nalgebra::{Point3, vector, Vector3};
+use nalgebra::{Point3, Vector3};
 
-use crate::spatial_index::kd_indexer::KdContainer;
-use crate::surface::{gradient, on_surface, seed, Surface};
+use crate::plane::Plane;
+use crate::spatial_index::kd_indexer::{KdContainer, KdIndexer};
+use crate::spatial_index::SpatialIndexer;
+use crate::surface::{gradient, seed, Surface};
 
-pub fn sample<S: Surface>(surface: &S, repulsion_radius: f32) -> Vec<Point3<f32>> {
-    let seed = seed(surface);
+// This follows Levet et al. (2006). Each point on the front gets a ring of six siblings, each one found by rotating
+// about the point, from the tangent plane towards the normal, until it lands on the surface. Siblings that land next
+// to an existing sample are dropped, the rest join the back of the front
 
-    let initial_siblings = sibling_points(surface, seed, repulsion_radius);
+// SIBLINGS is how many siblings surround each point, six makes a hexagonal lattice on flat regions
+const SIBLINGS: usize = 6;
+// COVERED_RADIUS is how many radii a sibling can be from an existing sample before it's considered already covered
+const COVERED_RADIUS: f32 = 1.9;
+// ROTATE_STEPS is how many steps a sibling takes swinging through a right angle to find the surface
+const ROTATE_STEPS: usize = 8;
+// ROTATE_BISECTIONS is how many times the step where it crossed the surface is halved
+const ROTATE_BISECTIONS: usize = 20;
+// PROGRESS_INTERVAL is how many points come off the front between progress reports
+const PROGRESS_INTERVAL: usize = 100;
 
-    let mut samples = KdContainer::new();
-    samples.append(initial_siblings.clone());
+// Coverage describes how well the initial sampling covered the surface
+#[derive(Debug, Clone)]
+pub struct Coverage {
+    pub samples: usize,
 
-    let mut untreated = initial_siblings;
+    // Distance from each sample to its nearest neighbour, relative to the ideal spacing of two radii
+    // A perfect hexagonal lattice has all of these at 1
+    pub spacing_min: f32,
+    pub spacing_mean: f32,
+    pub spacing_max: f32,
 
-    while let Some(next_seed) = untreated.pop() {
-        for point in sibling_points(surface, next_seed, repulsion_radius) {
-            if samples.any_items_in_radius(point, repulsion_radius * 1.9) {
-                continue;
-            }
+    // Samples with no neighbour within twice the ideal spacing, they're left out of the spacing figures
+    pub isolated: usize,
 
-            samples.push(point);
-            untreated.push(point);
-        }
-    }
+    // Sibling directions where the surface couldn't be reached and no sample was nearby, each is likely a hole
+    pub gaps: usize,
+}
 
-    samples.items
+// Progress is how far along a sampling is, the front is how many points are still waiting for their siblings
+#[derive(Debug, Copy, Clone)]
+pub struct Progress {
+    pub placed: usize,
+    pub front: usize,
 }
 
-fn plane_basis_vectors(
-    normal: Vector3<f32>
-) -> (Vector3<f32>, Vector3<f32>) {
-    let mut cardinal = vector![0.0, 0.0, 0.0];
-    cardinal[normal.imin()] = 1.0;
+// CancelToken stops a sampling that's running, it can be cloned and handed to another thread
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed)
+    }
 
-    let u = normal.cross(&cardinal).normalize();
-    let v = u.cross(&normal).normalize();
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
 
-    (u, v)
+    // reset lets the token be used again once a cancellation has been handled
+    pub fn reset(&self) {
+        self.0.store(false, Ordering::Relaxed)
+    }
 }
 
-fn sibling_points<S: Surface>(
-    surface: &S,
-    parent: Point3<f32>,
+// InitialSampling is a sampling that can be run a few points at a time
+pub struct InitialSampling {
     repulsion_radius: f32,
-) -> Vec<Point3<f32>> {
-    let normal = gradient(surface, parent).normalize();
 
-    let (u, v) = plane_basis_vectors(normal);
+    samples: KdContainer<Point3<f32>>,
+
+    // Each point on the front remembers the point it came from, its siblings are laid out starting from there so
+    // they line up with the ones that are already there
+    front: VecDeque<(Point3<f32>, Option<Point3<f32>>)>,
+
+    gaps: usize,
+}
+
+impl InitialSampling {
+    // new is None if there's no surface to sample, see seed
+    pub fn new<S: Surface>(surface: &S, repulsion_radius: f32) -> Option<Self> {
+        let seed = seed(surface)?;
+
+        let mut samples = KdContainer::new();
+        samples.push(seed);
+
+        Some(InitialSampling {
+            repulsion_radius,
+            samples,
+            front: VecDeque::from([(seed, None)]),
+            gaps: 0,
+        })
+    }
+
+    pub fn progress(&self) -> Progress {
+        Progress {
+            placed: self.samples.items.len(),
+            front: self.front.len(),
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.front.is_empty()
+    }
+
+    // step places the siblings of up to `count` points off the front, and returns whether the sampling is done
+    pub fn step<S: Surface>(&mut self, surface: &S, count: usize) -> bool {
+        for _ in 0..count {
+            let Some((parent, from)) = self.front.pop_front() else {
+                break;
+            };
+
+            self.place_siblings(surface, parent, from)
+        }
+
+        self.is_done()
+    }
+
+    // finish returns the samples and how well they cover the surface, the sampling should be done
+    pub fn finish(self) -> (Vec<Point3<f32>>, Coverage) {
+        assert!(self.is_done(), "sampling isn't done yet");
+
+        let coverage = measure_coverage(&self.samples.items, self.repulsion_radius * 2.0, self.gaps);
+
+        (self.samples.items, coverage)
+    }
+
+    fn place_siblings<S: Surface>(&mut self, surface: &S, parent: Point3<f32>, from: Option<Point3<f32>>) {
+        let spacing = self.repulsion_radius * 2.0;
+        let covered = self.repulsion_radius * COVERED_RADIUS;
+
+        let Some(normal) = gradient(surface, parent).try_normalize(0.0) else {
+            return;
+        };
+
+        let start = from
+            .and_then(|from| {
+                let towards = from - parent;
+                (towards - normal * towards.dot(&normal)).try_normalize(1e-6)
+            })
+            .unwrap_or_else(|| Plane::from_origin_normal(parent, normal).u());
+        let across = normal.cross(&start);
 
-    let mut siblings = Vec::with_capacity(6);
+        // The first direction points back at where this point came from, which is always covered
+        let first = if from.is_some() { 1 } else { 0 };
 
-    for i in 0..6 {
-        let ipi3 = (i as f64 * PI) / 3.0;
+        for i in first..SIBLINGS {
+            let theta = (2.0 * PI * i as f32) / SIBLINGS as f32;
+            let direction = start * theta.cos() + across * theta.sin();
+
+            match rotate_onto_surface(surface, parent, normal, direction, spacing) {
+                Some(point) => {
+                    if self.samples.any_items_in_radius(point, covered) {
+                        continue;
+                    }
+
+                    self.samples.push(point);
+                    self.front.push_back((point, Some(parent)));
+                }
+                None => {
+                    let guess = parent + direction * spacing;
+                    if !self.samples.any_items_in_radius(guess, covered) {
+                        self.gaps += 1
+                    }
+                }
+            }
+        }
+    }
+}
 
-        let point_guess = parent +
-            (u * (ipi3.cos() as f32 * (repulsion_radius * 2.0))) +
-            (v * (ipi3.sin() as f32 * (repulsion_radius * 2.0)));
+// sample runs a whole sampling, calling `progress` as it goes. It gives up and returns None if `cancel` is cancelled,
+// and there's nothing to return if there's no surface to sample
+pub fn sample<S: Surface>(
+    surface: &S,
+    repulsion_radius: f32,
+    mut progress: impl FnMut(Progress),
+    cancel: &CancelToken,
+) -> Option<(Vec<Point3<f32>>, Coverage)> {
+    let mut sampling = InitialSampling::new(surface, repulsion_radius)?;
 
+    while !sampling.step(surface, PROGRESS_INTERVAL) {
+        progress(sampling.progress());
 
-        siblings.push(refine_point(surface, repulsion_radius, parent, point_guess))
+        if cancel.is_cancelled() {
+            return None;
+        }
     }
 
-    siblings
+    progress(sampling.progress());
+
+    Some(sampling.finish())
 }
 
-fn refine_point<S: Surface>(
+// rotate_onto_surface finds a point `distance` away from `parent`, on the circle through `direction` and `normal`
+// Keeping to the circle means siblings stay evenly spaced around the parent however much the surface curves
+fn rotate_onto_surface<S: Surface>(
     surface: &S,
-    radius: f32,
     parent: Point3<f32>,
-    guess: Point3<f32>,
-) -> Point3<f32> {
-    let mut point = guess;
-
-    for _ in 0..10 {
-        let grad = gradient(surface, point);
-        point -= grad.scale(surface.sample(point) / grad.dot(&grad));
-
-        // Push point away from parent
-        // The original paper did some fancy shit to rotate about the parent
-        let mut away = point - parent;
-        if away.magnitude() < (radius * 2.0) {
-            away = away.scale((radius * 2.0) - away.magnitude());
-            point += away;
-        }
+    normal: Vector3<f32>,
+    direction: Vector3<f32>,
+    distance: f32,
+) -> Option<Point3<f32>> {
+    let at = |phi: f32| parent + (direction * phi.cos() + normal * phi.sin()) * distance;
+
+    // Starting in the tangent plane, the point swings towards the surface until it crosses it
+    // Stepping then bisecting copes with creases, where the field's gradient can't be trusted
+    let start = surface.sample(at(0.0));
+    let towards = if start > 0.0 { -1.0 } else { 1.0 };
 
-        if on_surface(surface, point) {
+    let mut near = 0.0;
+    let mut far = None;
+    for i in 1..=ROTATE_STEPS {
+        let phi = towards * FRAC_PI_2 * i as f32 / ROTATE_STEPS as f32;
+
+        if (surface.sample(at(phi)) > 0.0) != (start > 0.0) {
+            far = Some(phi);
             break;
         }
+
+        near = phi;
+    }
+
+    // Not crossing within a right angle means the surface turns away from the parent, there's nothing to land on
+    let mut far = far?;
+    for _ in 0..ROTATE_BISECTIONS {
+        let middle = (near + far) / 2.0;
+
+        if (surface.sample(at(middle)) > 0.0) == (start > 0.0) {
+            near = middle
+        } else {
+            far = middle
+        }
+    }
+
+    Some(at((near + far) / 2.0))
+}
+
+fn measure_coverage(samples: &[Point3<f32>], spacing: f32, gaps: usize) -> Coverage {
+    let mut index = KdIndexer::new();
+    index.reindex(samples, (0..samples.len()).collect());
+
+    let mut nearest = Vec::with_capacity(samples.len());
+    let mut isolated = 0;
+
+    for (i, p) in samples.iter().enumerate() {
+        // The closest is usually the sample itself
+        let closest = index
+            .k_nearest(samples, *p, 2)
+            .into_iter()
+            .find(|(j, _)| *j != i)
+            .map(|(_, distance)| distance)
+            .filter(|distance| *distance <= spacing * 2.0);
+
+        match closest {
+            Some(distance) => nearest.push(distance / spacing),
+            None => isolated += 1,
+        }
     }
 
-    point
+    let (spacing_min, spacing_max) = nearest
+        .iter()
+        .fold((f32::MAX, 0.0f32), |(min, max), d| (min.min(*d), max.max(*d)));
+
+    Coverage {
+        samples: samples.len(),
+        spacing_min: if nearest.is_empty() { 0.0 } else { spacing_min },
+        spacing_mean: nearest.iter().sum::<f32>() / nearest.len().max(1) as f32,
+        spacing_max,
+        isolated,
+        gaps,
+    }
 }
@@ -0,0 +1,510 @@
+use std::ops::{Add, Div, Mul, Neg, Sub};
+
+use nalgebra::{point, Point3, Vector3};
+
+use crate::mirror::Mirror;
+use crate::noise::Displaced;
+use crate::shapes::{
+    smooth_min, Difference, Ellipsoid, Intersection, SmoothUnion, Sphere, Transformed, Union,
+};
+use crate::surface::Surface;
+use crate::tape::{Opcode, Tape};
+
+// Interval arithmetic gives a bound on the value of a surface over a whole region at once
+// If the bound doesn't contain zero, the surface can't pass through the region and it can be skipped
+// Every operation that rounds widens its result by an ulp each way, so the bound still holds after round-off
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Interval {
+    pub min: f32,
+    pub max: f32,
+}
+
+impl Interval {
+    pub fn new(min: f32, max: f32) -> Self {
+        Interval { min, max }
+    }
+
+    pub fn point(value: f32) -> Self {
+        Interval {
+            min: value,
+            max: value,
+        }
+    }
+
+    // outward is for results that were rounded, it widens them by an ulp so the true bound is inside
+    fn outward(min: f32, max: f32) -> Self {
+        Interval {
+            min: min.next_down(),
+            max: max.next_up(),
+        }
+    }
+
+    pub fn unbounded() -> Self {
+        Interval {
+            min: f32::NEG_INFINITY,
+            max: f32::INFINITY,
+        }
+    }
+
+    pub fn contains(&self, value: f32) -> bool {
+        self.min <= value && value <= self.max
+    }
+
+    pub fn contains_zero(&self) -> bool {
+        self.contains(0.0)
+    }
+
+    pub fn width(&self) -> f32 {
+        self.max - self.min
+    }
+
+    pub fn min(self, other: Interval) -> Interval {
+        Interval::new(self.min.min(other.min), self.max.min(other.max))
+    }
+
+    pub fn max(self, other: Interval) -> Interval {
+        Interval::new(self.min.max(other.min), self.max.max(other.max))
+    }
+
+    pub fn abs(self) -> Interval {
+        if self.min >= 0.0 {
+            self
+        } else if self.max <= 0.0 {
+            -self
+        } else {
+            Interval::new(0.0, self.max.max(-self.min))
+        }
+    }
+
+    pub fn square(self) -> Interval {
+        let abs = self.abs();
+
+        Interval::outward(abs.min * abs.min, abs.max * abs.max)
+    }
+
+    pub fn sqrt(self) -> Interval {
+        Interval::outward(self.min.max(0.0).sqrt(), self.max.max(0.0).sqrt())
+    }
+
+    pub fn scale(self, factor: f32) -> Interval {
+        if factor >= 0.0 {
+            Interval::outward(self.min * factor, self.max * factor)
+        } else {
+            Interval::outward(self.max * factor, self.min * factor)
+        }
+    }
+
+    pub fn offset(self, amount: f32) -> Interval {
+        Interval::outward(self.min + amount, self.max + amount)
+    }
+
+    // smooth_min is non-decreasing in both arguments, so the ends of the intervals give the bound
+    // The blend takes a few roundings, each off by at most an ulp of k, so that much slack is added on top
+    pub fn smooth_min(self, other: Interval, k: f32) -> Interval {
+        let slack = k.max(0.0) * f32::EPSILON * 2.0;
+
+        Interval::outward(
+            smooth_min(self.min, other.min, k) - slack,
+            smooth_min(self.max, other.max, k) + slack,
+        )
+    }
+}
+
+impl Add for Interval {
+    type Output = Interval;
+
+    fn add(self, rhs: Interval) -> Interval {
+        Interval::outward(self.min + rhs.min, self.max + rhs.max)
+    }
+}
+
+impl Sub for Interval {
+    type Output = Interval;
+
+    fn sub(self, rhs: Interval) -> Interval {
+        Interval::outward(self.min - rhs.max, self.max - rhs.min)
+    }
+}
+
+// corners bounds every combination of the ends of two intervals, for operations that are monotonic in both
+fn corners(a: Interval, b: Interval, op: impl Fn(f32, f32) -> f32) -> Interval {
+    let results = [
+        op(a.min, b.min),
+        op(a.min, b.max),
+        op(a.max, b.min),
+        op(a.max, b.max),
+    ];
+
+    Interval::outward(
+        results.iter().copied().fold(f32::INFINITY, f32::min),
+        results.iter().copied().fold(f32::NEG_INFINITY, f32::max),
+    )
+}
+
+impl Mul for Interval {
+    type Output = Interval;
+
+    fn mul(self, rhs: Interval) -> Interval {
+        corners(self, rhs, |a, b| a * b)
+    }
+}
+
+impl Div for Interval {
+    type Output = Interval;
+
+    fn div(self, rhs: Interval) -> Interval {
+        if rhs.contains_zero() {
+            return Interval::unbounded();
+        }
+
+        // Dividing directly rounds once, going through 1 / rhs would round twice
+        corners(self, rhs, |a, b| a / b)
+    }
+}
+
+impl Neg for Interval {
+    type Output = Interval;
+
+    fn neg(self) -> Interval {
+        Interval::new(-self.max, -self.min)
+    }
+}
+
+// Aabb is an axis aligned bounding box
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Aabb {
+    pub min: Point3<f32>,
+    pub max: Point3<f32>,
+}
+
+impl Aabb {
+    pub fn new(min: Point3<f32>, max: Point3<f32>) -> Self {
+        Aabb { min, max }
+    }
+
+    pub fn from_center_size(center: Point3<f32>, size: Vector3<f32>) -> Self {
+        let half = size / 2.0;
+
+        Aabb {
+            min: center - half,
+            max: center + half,
+        }
+    }
+
+    pub fn center(&self) -> Point3<f32> {
+        nalgebra::center(&self.min, &self.max)
+    }
+
+    pub fn size(&self) -> Vector3<f32> {
+        self.max - self.min
+    }
+
+    pub fn contains(&self, p: Point3<f32>) -> bool {
+        (0..3).all(|i| self.min[i] <= p[i] && p[i] <= self.max[i])
+    }
+
+    // overlaps is whether the boxes share any points, touching counts
+    pub fn overlaps(&self, other: &Aabb) -> bool {
+        (0..3).all(|i| self.min[i] <= other.max[i] && other.min[i] <= self.max[i])
+    }
+
+    // distance_to is how far `p` is from the box, 0 if it's inside
+    pub fn distance_to(&self, p: Point3<f32>) -> f32 {
+        let outside = (self.min - p).sup(&(p - self.max)).sup(&Vector3::zeros());
+
+        outside.magnitude()
+    }
+
+    // union is the smallest box containing both boxes
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        Aabb::new(self.min.inf(&other.min), self.max.sup(&other.max))
+    }
+
+    // padded grows the box by `amount` on every side
+    pub fn padded(&self, amount: f32) -> Aabb {
+        let pad = Vector3::repeat(amount);
+
+        Aabb::new(self.min - pad, self.max + pad)
+    }
+
+    pub fn intervals(&self) -> [Interval; 3] {
+        [0, 1, 2].map(|i| Interval::new(self.min[i], self.max[i]))
+    }
+
+    // octants splits the box into 8 equal children
+    pub fn octants(&self) -> [Aabb; 8] {
+        let c = self.center();
+
+        [0, 1, 2, 3, 4, 5, 6, 7].map(|i| {
+            let pick = |axis: usize| {
+                if i & (1 << axis) == 0 {
+                    (self.min[axis], c[axis])
+                } else {
+                    (c[axis], self.max[axis])
+                }
+            };
+            let (x, y, z) = (pick(0), pick(1), pick(2));
+
+            Aabb::new(point![x.0, y.0, z.0], point![x.1, y.1, z.1])
+        })
+    }
+}
+
+// IntervalSurface is implemented by surfaces that can bound their value over a region
+pub trait IntervalSurface: Surface {
+    // sample_interval should return a bound on the field for every point with coordinates in `at`
+    fn sample_interval(&self, at: [Interval; 3]) -> Interval;
+}
+
+impl<S: IntervalSurface + ?Sized> IntervalSurface for &S {
+    fn sample_interval(&self, at: [Interval; 3]) -> Interval {
+        (**self).sample_interval(at)
+    }
+}
+
+impl<S: IntervalSurface + ?Sized> IntervalSurface for Box<S> {
+    fn sample_interval(&self, at: [Interval; 3]) -> Interval {
+        (**self).sample_interval(at)
+    }
+}
+
+// bound returns the range of values `surface` takes inside `region`
+pub fn bound<S: IntervalSurface + ?Sized>(surface: &S, region: &Aabb) -> Interval {
+    surface.sample_interval(region.intervals())
+}
+
+// subdivide splits `region` into octants until cells are no bigger than `min_size`,
+// throwing out every cell that the surface definitely doesn't pass through
+// The cells returned might contain the surface, all the space outside them definitely doesn't
+pub fn subdivide<S: IntervalSurface + ?Sized>(
+    surface: &S,
+    region: Aabb,
+    min_size: f32,
+) -> Vec<Aabb> {
+    assert!(min_size > 0.0, "cells can't be split down to nothing");
+
+    let mut cells = vec![];
+    let mut pending = vec![region];
+
+    while let Some(cell) = pending.pop() {
+        if !bound(surface, &cell).contains_zero() {
+            continue;
+        }
+
+        // A cell too small for f32 to split any further is as small as it gets
+        let c = cell.center();
+        let splittable = (0..3)
+            .all(|i| cell.min[i] == cell.max[i] || (cell.min[i] < c[i] && c[i] < cell.max[i]));
+
+        if cell.size().max() <= min_size || !splittable {
+            cells.push(cell);
+        } else {
+            // Flat axes aren't split, both halves would be the whole cell
+            let flat = |axis: usize| cell.min[axis] == cell.max[axis];
+            pending.extend(
+                cell.octants()
+                    .into_iter()
+                    .enumerate()
+                    .filter(|(i, _)| (0..3).all(|axis| i & (1 << axis) == 0 || !flat(axis)))
+                    .map(|(_, octant)| octant),
+            );
+        }
+    }
+
+    cells
+}
+
+impl IntervalSurface for Sphere {
+    fn sample_interval(&self, at: [Interval; 3]) -> Interval {
+        let [x, y, z] = at.map(Interval::square);
+
+        (x + y + z).sqrt().offset(-self.radius)
+    }
+}
+
+impl IntervalSurface for Ellipsoid {
+    fn sample_interval(&self, at: [Interval; 3]) -> Interval {
+        let [x, y, z] =
+            [0, 1, 2].map(|i| at[i].square().scale(1.0 / (self.size[i] * self.size[i])));
+
+        (x + y + z).offset(-1.0)
+    }
+}
+
+impl<S: IntervalSurface> IntervalSurface for Transformed<S> {
+    fn sample_interval(&self, at: [Interval; 3]) -> Interval {
+        let m = &self.inverse;
+        let transformed = [0, 1, 2].map(|row| {
+            (at[0].scale(m[(row, 0)]) + at[1].scale(m[(row, 1)]) + at[2].scale(m[(row, 2)]))
+                .offset(m[(row, 3)])
+        });
+
+        self.surface.sample_interval(transformed)
+    }
+}
+
+impl<A: IntervalSurface, B: IntervalSurface> IntervalSurface for Union<A, B> {
+    fn sample_interval(&self, at: [Interval; 3]) -> Interval {
+        self.0.sample_interval(at).min(self.1.sample_interval(at))
+    }
+}
+
+impl<A: IntervalSurface, B: IntervalSurface> IntervalSurface for Intersection<A, B> {
+    fn sample_interval(&self, at: [Interval; 3]) -> Interval {
+        self.0.sample_interval(at).max(self.1.sample_interval(at))
+    }
+}
+
+impl<A: IntervalSurface, B: IntervalSurface> IntervalSurface for Difference<A, B> {
+    fn sample_interval(&self, at: [Interval; 3]) -> Interval {
+        self.0.sample_interval(at).max(-self.1.sample_interval(at))
+    }
+}
+
+impl<A: IntervalSurface, B: IntervalSurface> IntervalSurface for SmoothUnion<A, B> {
+    fn sample_interval(&self, at: [Interval; 3]) -> Interval {
+        self.a
+            .sample_interval(at)
+            .smooth_min(self.b.sample_interval(at), self.k)
+    }
+}
+
+impl<S: IntervalSurface> IntervalSurface for Mirror<S> {
+    fn sample_interval(&self, at: [Interval; 3]) -> Interval {
+        let m = self.plane.reflection();
+        let reflected = [0, 1, 2].map(|row| {
+            (at[0].scale(m[(row, 0)]) + at[1].scale(m[(row, 1)]) + at[2].scale(m[(row, 2)]))
+                .offset(m[(row, 3)])
+        });
+
+        let (a, b) = (
+            self.surface.sample_interval(at),
+            self.surface.sample_interval(reflected),
+        );
+
+        if self.blend > 0.0 {
+            a.smooth_min(b, self.blend)
+        } else {
+            a.min(b)
+        }
+    }
+}
+
+impl<S: IntervalSurface> IntervalSurface for Displaced<S> {
+    // The noise can't change faster than the slope away from its value in the middle of the region,
+    // and never goes past the amplitude either way
+    fn sample_interval(&self, at: [Interval; 3]) -> Interval {
+        let amplitude = self.amplitude();
+        let radius = at.iter().map(|i| i.width().powi(2)).sum::<f32>().sqrt() / 2.0;
+
+        let displacement = if radius.is_finite() {
+            let center = at.map(|i| (i.min + i.max) / 2.0);
+            let n = self.displacement(point![center[0], center[1], center[2]]).0;
+            let spread = self.slope() * radius;
+
+            Interval::new((n - spread).max(-amplitude), (n + spread).min(amplitude))
+        } else {
+            Interval::new(-amplitude, amplitude)
+        };
+
+        self.surface().sample_interval(at) + displacement
+    }
+}
+
+impl IntervalSurface for Tape {
+    fn sample_interval(&self, at: [Interval; 3]) -> Interval {
+        let mut registers = vec![Interval::point(0.0); self.register_count()];
+
+        for i in self.instructions() {
+            let [a, b, c] = i.args.map(|r| registers[r as usize]);
+            let m = i.imm;
+
+            registers[i.out as usize] = match i.opcode {
+                Opcode::X => at[0],
+                Opcode::Y => at[1],
+                Opcode::Z => at[2],
+                Opcode::Const => Interval::point(m[0]),
+                Opcode::Add => a + b,
+                Opcode::Sub => a - b,
+                Opcode::Mul => a * b,
+                Opcode::Div => a / b,
+                Opcode::Min => a.min(b),
+                Opcode::Max => a.max(b),
+                Opcode::Neg => -a,
+                Opcode::Abs => a.abs(),
+                Opcode::Square => a.square(),
+                Opcode::Sqrt => a.sqrt(),
+                Opcode::AddImm => a.offset(m[0]),
+                Opcode::MulImm => a.scale(m[0]),
+                Opcode::Affine => (a.scale(m[0]) + b.scale(m[1]) + c.scale(m[2])).offset(m[3]),
+                Opcode::WeightedSquares => {
+                    (a.square().scale(m[0]) + b.square().scale(m[1]) + c.square().scale(m[2]))
+                        .offset(m[3])
+                }
+                Opcode::Length => (a.square() + b.square() + c.square()).sqrt(),
+                Opcode::SmoothMin => a.smooth_min(b, m[0]),
+            };
+        }
+
+        registers[self.result_register()]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // overlap is whether two cells share any space, cells that only touch don't count
+    fn overlap(a: &Aabb, b: &Aabb) -> bool {
+        (0..3).all(|i| {
+            let (low, high) = (a.min[i].max(b.min[i]), a.max[i].min(b.max[i]));
+
+            low < high || (low == high && a.min[i] == a.max[i] && b.min[i] == b.max[i])
+        })
+    }
+
+    fn assert_no_overlap(cells: &[Aabb]) {
+        for (i, a) in cells.iter().enumerate() {
+            for b in &cells[i + 1..] {
+                assert!(!overlap(a, b), "{a:?} overlaps {b:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn flat_regions_subdivide_without_overlap() {
+        let sphere = Sphere::new(1.0);
+
+        let flat = Aabb::new(point![-1.5, -1.5, 0.0], point![1.5, 1.5, 0.0]);
+        let cells = subdivide(&sphere, flat, 0.2);
+        assert!(!cells.is_empty());
+        assert_no_overlap(&cells);
+
+        let line = Aabb::new(point![-1.5, 0.0, 0.0], point![1.5, 0.0, 0.0]);
+        let cells = subdivide(&sphere, line, 0.2);
+        assert!(!cells.is_empty());
+        assert_no_overlap(&cells);
+    }
+
+    #[test]
+    fn cells_cover_the_surface() {
+        let sphere = Sphere::new(1.0);
+        let cells = subdivide(
+            &sphere,
+            Aabb::new(point![-2.0, -2.0, -2.0], point![2.0, 2.0, 2.0]),
+            0.25,
+        );
+        assert_no_overlap(&cells);
+
+        // Every point on the surface is in some cell
+        for i in 0..100 {
+            let (theta, phi) = (i as f32 * 0.7, i as f32 * 0.31);
+            let p = point![theta.cos() * phi.sin(), theta.sin() * phi.sin(), phi.cos()];
+
+            assert!(cells
+                .iter()
+                .any(|cell| (0..3).all(|a| cell.min[a] <= p[a] && p[a] <= cell.max[a])));
+        }
+    }
+}
@@ -1,9 +1,27 @@
-pub use live_sampling::ImplicitSampler;
+pub use handles::ParticleHandle;
+pub use live_sampling::{ImplicitSampler, Particle};
 pub use surface::Surface;
 
 mod surface;
-mod spatial_index;
+pub mod spatial_index;
 mod buffer_allocator;
-mod initial_sampling;
+mod handles;
+pub mod initial_sampling;
+pub mod background;
 mod live_sampling;
-
+pub mod shapes;
+pub mod tape;
+pub mod interval;
+pub mod autodiff;
+pub mod metaballs;
+pub mod convolution;
+pub mod bvh;
+pub mod quality;
+pub mod grid;
+pub mod mesh;
+pub mod reconstruction;
+pub mod noise;
+pub mod plane;
+pub mod mirror;
+pub mod repeat;
+pub mod description;
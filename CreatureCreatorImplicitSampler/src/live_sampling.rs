@@ -4,8 +4,9 @@ use std::ops::Neg;
 
 use nalgebra::{Point3, vector, Vector3};
 
-use crate::buffer_allocator::{BufferAllocator, StackBufferAllocator};
-use crate::initial_sampling::sample;
+use crate::buffer_allocator::{BufferAllocator, CompactingBufferAllocator};
+use crate::handles::{HandleTable, ParticleHandle};
+use crate::initial_sampling::{CancelToken, Coverage, InitialSampling, Progress};
 use crate::spatial_index::{Positioned, SpatialIndexer};
 use crate::spatial_index::kd_indexer::KdIndexer;
 use crate::surface::{gradient, Surface};
@@ -20,6 +21,8 @@ const FISSION_COEFFICIENT: f32 = 0.2;
 const DEATH_COEFFICIENT: f32 = 0.7;
 const MAX_RADIUS_COEFFICIENT: f32 = 1.2;
 const DESIRED_REPULSION_ENERGY: f32 = REPULSION_AMPLITUDE * 0.8;
+// INITIAL_SAMPLING_STEP is how many points come off the initial sampling's front each update
+const INITIAL_SAMPLING_STEP: usize = 500;
 
 fn random_velocity() -> Vector3<f32> {
     Vector3::new(rand::random(), rand::random(), rand::random()).normalize()
@@ -33,15 +36,15 @@ fn energy_contribution(i_repulsion_radius: f32, i: Point3<f32>, j: Point3<f32>)
         .exp()
 }
 
-fn constrain_to_surface<S: Surface>(
-    surface: &S,
-    position: Point3<f32>,
+// surface_value is the value of the surface at the particle's position
+fn constrain_to_surface(
+    surface_value: f32,
     normal: Vector3<f32>,
     velocity: Vector3<f32>,
 ) -> Vector3<f32> {
     velocity
         - normal.scale(
-        (normal.dot(&velocity) + (FEEDBACK * surface.sample(position))) / (normal.dot(&normal)),
+        (normal.dot(&velocity) + (FEEDBACK * surface_value)) / (normal.dot(&normal)),
     )
 }
 
@@ -61,12 +64,30 @@ fn should_fission_energy(radius: f32, energy: f32, desired_radius: f32) -> bool
     energy > fission_energy && radius > desired_radius
 }
 
+// Particle is only public so indexers can be picked for ImplicitSampler, nothing outside can look inside one
 #[derive(Copy, Clone, Debug)]
-struct Particle {
+pub struct Particle {
     position: Point3<f32>,
     velocity: Vector3<f32>,
     normal: Vector3<f32>,
     radius: f32,
+
+    // The slot in the HandleTable, it follows the particle wherever it's moved to
+    slot: u32,
+}
+
+impl Particle {
+    pub fn position(&self) -> Point3<f32> {
+        self.position
+    }
+
+    pub fn normal(&self) -> Vector3<f32> {
+        self.normal
+    }
+
+    pub fn radius(&self) -> f32 {
+        self.radius
+    }
 }
 
 impl Positioned for Particle {
@@ -75,15 +96,26 @@ impl Positioned for Particle {
     }
 }
 
-pub struct ImplicitSampler<const MAX_SAMPLE_COUNT: usize> {
+// The indexer finds each particle's neighbours, the different indexers suit different workloads
+pub struct ImplicitSampler<const MAX_SAMPLE_COUNT: usize, I = KdIndexer> {
     living_particles: Vec<usize>,
-    position_index: KdIndexer,
-    index_allocator: StackBufferAllocator<MAX_SAMPLE_COUNT>,
+    position_index: I,
+    index_allocator: CompactingBufferAllocator<MAX_SAMPLE_COUNT>,
+    handles: HandleTable,
 
     // These are boxed so we don't blow out the stack
     particles_a: Box<[Particle; MAX_SAMPLE_COUNT]>,
     particles_b: Box<[Particle; MAX_SAMPLE_COUNT]>,
 
+    // The initial sampling is spread over as many updates as it takes, so big surfaces don't stall a frame
+    // Particles aren't simulated until it's done
+    initial: Option<InitialSampling>,
+    progress_callback: Option<Box<dyn FnMut(Progress) + Send>>,
+    cancel: CancelToken,
+
+    // How well the initial sampling covered the surface, None until it's done
+    coverage: Option<Coverage>,
+
     pub t: f32,
 }
 
@@ -92,31 +124,78 @@ pub struct ImplicitSampler<const MAX_SAMPLE_COUNT: usize> {
 fn new_zeroed_box<T: Sized>() -> Box<T> {
     unsafe {
         // This is safe because the T is sized and we're using the global allocator
-        Box::from_raw(mem::transmute(alloc_zeroed(Layout::new::<T>())))
+        Box::from_raw(mem::transmute::<*mut u8, *mut T>(alloc_zeroed(Layout::new::<T>())))
     }
 }
 
 
+impl<const MAX_SAMPLE_COUNT: usize, I: SpatialIndexer<Particle> + Default> Default
+    for ImplicitSampler<MAX_SAMPLE_COUNT, I>
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
-impl<const MAX_SAMPLE_COUNT: usize> ImplicitSampler<MAX_SAMPLE_COUNT> {
+impl<const MAX_SAMPLE_COUNT: usize, I: SpatialIndexer<Particle> + Default> ImplicitSampler<MAX_SAMPLE_COUNT, I> {
     pub fn new() -> Self {
+        Self::with_indexer(I::default())
+    }
+}
+
+impl<const MAX_SAMPLE_COUNT: usize, I: SpatialIndexer<Particle>> ImplicitSampler<MAX_SAMPLE_COUNT, I> {
+    // with_indexer is for indexers that need setting up, like a GridIndexer with a fixed cell size
+    pub fn with_indexer(position_index: I) -> Self {
         ImplicitSampler {
             living_particles: vec![],
-            position_index: KdIndexer::new(),
-            index_allocator: StackBufferAllocator::new(),
+            position_index,
+            index_allocator: CompactingBufferAllocator::new(),
+            handles: HandleTable::new(),
 
             particles_a: new_zeroed_box(),
             particles_b: new_zeroed_box(),
 
+            initial: None,
+            progress_callback: None,
+            cancel: CancelToken::new(),
+
+            coverage: None,
+
             t: 0.0,
         }
     }
 
-    fn initial_sampling<S: Surface>(&mut self, desired_radius: f32, surface: &S) {
+    // initial_sampling carries the initial sampling on for another step, and returns whether it's done
+    fn initial_sampling<S: Surface>(&mut self, desired_radius: f32, surface: &S) -> bool {
         // The initial sampling isn't taken until the particle system is stepped for the first time
         // This way we don't need to know what surface we're sampling when the particle system is allocated
-        println!("Initial sampling...");
-        let positions = sample(surface, desired_radius);
+        if self.cancel.is_cancelled() {
+            // Whatever was placed is thrown away, the next update starts over
+            self.initial = None;
+            self.cancel.reset();
+            return false;
+        }
+
+        // A surface that can't be seeded has nothing to sample, it's tried again on the next update in case it's
+        // changed since
+        if self.initial.is_none() {
+            self.initial = InitialSampling::new(surface, desired_radius);
+        }
+        let Some(initial) = &mut self.initial else {
+            return false;
+        };
+        let done = initial.step(surface, INITIAL_SAMPLING_STEP);
+
+        let progress = initial.progress();
+        if let Some(callback) = &mut self.progress_callback {
+            callback(progress)
+        }
+
+        if !done {
+            return false;
+        }
+
+        let (positions, coverage) = self.initial.take().unwrap().finish();
         if positions.len() > MAX_SAMPLE_COUNT {
             panic!("TOO DANG BIG!!")
         }
@@ -126,36 +205,137 @@ impl<const MAX_SAMPLE_COUNT: usize> ImplicitSampler<MAX_SAMPLE_COUNT> {
 
             let i = self.index_allocator.insert();
             self.living_particles.push(i);
+            let slot = self.handles.insert(i);
 
             self.particles_a[i].position = p;
             self.particles_a[i].normal = normal;
             self.particles_a[i].radius = desired_radius;
+            self.particles_a[i].slot = slot;
             self.particles_b[i].position = p;
             self.particles_b[i].normal = normal;
             self.particles_b[i].radius = desired_radius;
+            self.particles_b[i].slot = slot;
         }
 
-        let mut position_index = KdIndexer::new();
-        position_index.reindex(self.particles_a.as_slice(), self.living_particles.clone());
+        self.position_index
+            .reindex(self.particles_a.as_slice(), self.living_particles.clone());
+
+        self.coverage = Some(coverage);
 
-        println!("Done!")
+        true
+    }
+
+    // set_progress_callback is called with the initial sampling's progress after every update it's running in
+    pub fn set_progress_callback(&mut self, callback: impl FnMut(Progress) + Send + 'static) {
+        self.progress_callback = Some(Box::new(callback))
+    }
+
+    // cancel_token can be used to throw away an initial sampling that's running, the next update starts it over
+    // This is for when the surface changes too much for the points placed so far to be worth keeping
+    pub fn cancel_token(&self) -> CancelToken {
+        self.cancel.clone()
+    }
+
+    // initial_progress is how far along the initial sampling is, None when it isn't running
+    pub fn initial_progress(&self) -> Option<Progress> {
+        self.initial.as_ref().map(InitialSampling::progress)
+    }
+
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_ref()
     }
 
     pub fn samples(
         &self,
-    ) -> impl Iterator<Item = (Point3<f32>, Vector3<f32>, f32)> + ExactSizeIterator + '_ {
+    ) -> impl ExactSizeIterator<Item = (Point3<f32>, Vector3<f32>, f32)> + '_ {
         self.living_particles.iter().map(|i| {
             let particle = self.particles_a[*i];
             (particle.position, particle.normal, particle.radius)
         })
     }
 
+    // particles is the living particles as they sit in the buffer, so they can be copied out in one go
+    // They're only packed together after compact, until something dies in the next update, otherwise it's None
+    pub fn particles(&self) -> Option<&[Particle]> {
+        self.index_allocator
+            .is_compact()
+            .then(|| &self.particles_a[..self.index_allocator.len()])
+    }
+
+    // samples_with_handles is samples, along with a handle that finds each sample's particle again in later updates
+    pub fn samples_with_handles(
+        &self,
+    ) -> impl ExactSizeIterator<Item = (ParticleHandle, Point3<f32>, Vector3<f32>, f32)> + '_ {
+        self.living_particles.iter().map(|i| {
+            let particle = self.particles_a[*i];
+            (
+                self.handles.handle(particle.slot),
+                particle.position,
+                particle.normal,
+                particle.radius,
+            )
+        })
+    }
+
+    // compact moves the living particles to the front of the buffers, with nothing dead between them
+    // Afterwards samples reads straight through the front of the buffer, and handles still find their particles
+    pub fn compact(&mut self) {
+        let remap = self.index_allocator.compact();
+        if remap.is_empty() {
+            return;
+        }
+
+        for (old, new) in &remap {
+            self.particles_a[*new] = self.particles_a[*old];
+            self.handles.relocate(self.particles_a[*new].slot, *new)
+        }
+
+        // Every index that's still in use is below the count, so they're just put back in order
+        assert_eq!(self.living_particles.len(), self.index_allocator.len());
+        self.living_particles = (0..self.index_allocator.len()).collect();
+
+        // Nothing's moved, so indexes that can be kept up to date only need the moved particles' indices changing
+        if self.position_index.incremental() {
+            for (old, new) in remap {
+                self.position_index
+                    .remap_item_index(self.particles_a.as_slice(), old, new)
+            }
+        } else {
+            self.position_index
+                .reindex(self.particles_a.as_slice(), self.living_particles.clone());
+        }
+    }
+
+    // particle is the sample for the handle's particle as it is now, None if the particle has died
+    pub fn particle(&self, handle: ParticleHandle) -> Option<(Point3<f32>, Vector3<f32>, f32)> {
+        let particle = self.particles_a[self.handles.index(handle)?];
+
+        Some((particle.position, particle.normal, particle.radius))
+    }
+
     pub fn update<S: Surface>(&mut self, desired_radius: f32, surface: &S) {
-        if self.t == 0.0 && self.living_particles.is_empty() {
-            self.initial_sampling(desired_radius, surface)
+        if self.t == 0.0
+            && self.living_particles.is_empty()
+            && !self.initial_sampling(desired_radius, surface)
+        {
+            return;
         }
 
         for _ in 0..UPDATE_ITERATIONS {
+            // The surface is sampled for every particle up front, and normals are found for every particle
+            // that moved afterwards, so each can be done in a single batch
+            let positions: Vec<Point3<f32>> = self
+                .living_particles
+                .iter()
+                .map(|i| self.particles_a[*i].position)
+                .collect();
+            let mut surface_values = vec![0.0; positions.len()];
+            surface.sample_batch(&positions, &mut surface_values);
+
+            // Particles from fission are kept apart from the ones that moved, since they weren't indexed before
+            let mut moved = Vec::with_capacity(self.living_particles.len());
+            let mut added = vec![];
+
             for j in (0..self.living_particles.len()).rev() {
                 let i = self.living_particles[j];
                 let particle = self.particles_a[i];
@@ -185,8 +365,13 @@ impl<const MAX_SAMPLE_COUNT: usize> ImplicitSampler<MAX_SAMPLE_COUNT> {
 
                 if particle.velocity.magnitude() < (EQUILIBRIUM_SPEED * particle.radius) {
                     if should_die(particle.radius, desired_radius) {
+                        if self.position_index.incremental() {
+                            self.position_index.remove_item_index(self.particles_a.as_slice(), i);
+                        }
+
                         self.living_particles.remove(j);
                         self.index_allocator.remove(i);
+                        self.handles.remove(particle.slot);
                         continue;
                     }
 
@@ -203,49 +388,84 @@ impl<const MAX_SAMPLE_COUNT: usize> ImplicitSampler<MAX_SAMPLE_COUNT> {
                         self.particles_b[i] = Particle {
                             position: new_position,
                             velocity: vector![0.0, 0.0, 0.0],
-                            normal: gradient(surface, new_position).normalize(),
+                            normal: particle.normal,
                             radius: new_radius,
+                            slot: particle.slot,
                         };
+                        moved.push(i);
 
+                        // The parent keeps its handle, the sibling is a new particle
+                        let sibling_i = self.index_allocator.insert();
                         let sibling_position = Point3::from(position - new_velocity);
                         let sibling = Particle {
                             position: sibling_position,
                             velocity: vector![0.0, 0.0, 0.0],
-                            normal: gradient(surface, sibling_position).normalize(),
+                            normal: particle.normal,
                             radius: new_radius,
+                            slot: self.handles.insert(sibling_i),
                         };
-                        let sibling_i = self.index_allocator.insert();
                         self.particles_b[sibling_i] = sibling;
                         self.living_particles.push(sibling_i);
+                        added.push(sibling_i);
                         continue;
                     }
                 }
 
                 let velocity = constrain_to_surface(
-                    surface,
-                    particle.position,
+                    surface_values[j],
                     particle.normal,
                     self.particle_velocity(particle.position, particle.radius, &neighbours),
                 );
 
                 let position = particle.position + velocity.scale(ITERATION_T_STEP);
 
-                let normal = gradient(surface, position).normalize();
-
                 let radius = self.particle_radius(position, particle.radius, energy, &neighbours);
 
                 self.particles_b[i] = Particle {
                     position,
                     velocity,
-                    normal,
+                    normal: particle.normal,
                     radius,
+                    slot: particle.slot,
+                };
+                moved.push(i);
+            }
+
+            let moved_positions: Vec<Point3<f32>> = moved
+                .iter()
+                .chain(&added)
+                .map(|i| self.particles_b[*i].position)
+                .collect();
+            let mut normals = vec![Vector3::zeros(); moved_positions.len()];
+            surface.gradient_batch(&moved_positions, &mut normals);
+
+            for (i, normal) in moved.iter().chain(&added).zip(normals) {
+                // Particles that drift somewhere the field is flat keep their old normal to find their way back
+                if let Some(normal) = normal.try_normalize(0.0) {
+                    self.particles_b[*i].normal = normal;
                 }
             }
 
             mem::swap(&mut self.particles_a, &mut self.particles_b);
 
-            self.position_index
-                .reindex(self.particles_a.as_slice(), self.living_particles.clone());
+            if self.position_index.incremental() {
+                // After the swap, particles_b is where everything was
+                for i in moved {
+                    self.position_index.move_item_index(
+                        self.particles_b.as_slice(),
+                        self.particles_a.as_slice(),
+                        i,
+                    )
+                }
+
+                for i in added {
+                    self.position_index
+                        .insert_item_index(self.particles_a.as_slice(), i)
+                }
+            } else {
+                self.position_index
+                    .reindex(self.particles_a.as_slice(), self.living_particles.clone());
+            }
         }
 
         self.t += ITERATION_T_STEP
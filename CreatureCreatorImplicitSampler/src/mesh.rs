@@ -0,0 +1,297 @@
+use std::cell::Cell;
+use std::f32::consts::PI;
+
+use nalgebra::{Point3, Vector3};
+
+use crate::bvh::Bvh;
+use crate::interval::Aabb;
+use crate::surface::Surface;
+
+// MeshSurface is the signed distance to a triangle mesh
+// The distance comes from the closest triangle, found with a BVH
+// Inside and outside come from the generalized winding number (Jacobson et al.), which still works for meshes
+// with holes or overlapping parts. Far away parts of the mesh are summed up with a dipole approximation (Barill et al.)
+
+// WINDING_ACCURACY is how many times further away than its own size a node has to be for its approximation to be used
+const WINDING_ACCURACY: f32 = 2.0;
+
+// A node of the BVH, summed up as if it were a single dipole for the winding number
+#[derive(Debug, Copy, Clone)]
+struct Dipole {
+    center: Point3<f32>,
+    // Sum of the triangles' normals scaled by their areas
+    area_normal: Vector3<f32>,
+    // Distance from the center to the furthest corner of the node
+    radius: f32,
+}
+
+pub struct MeshSurface {
+    vertices: Vec<Point3<f32>>,
+    triangles: Vec<[u32; 3]>,
+
+    bvh: Bvh,
+    dipoles: Vec<Dipole>,
+}
+
+impl MeshSurface {
+    // new takes an indexed triangle mesh, triangles are counter-clockwise when seen from outside
+    pub fn new(vertices: Vec<Point3<f32>>, triangles: Vec<[u32; 3]>) -> Self {
+        assert!(
+            MeshSurface::is_valid(&vertices, &triangles),
+            "mesh should have at least one triangle, finite vertices and indices in range"
+        );
+
+        let bounds = triangles
+            .iter()
+            .map(|t| {
+                let [a, b, c] = t.map(|i| vertices[i as usize]);
+                Aabb::new(a.inf(&b).inf(&c), a.sup(&b).sup(&c))
+            })
+            .collect();
+        let bvh = Bvh::new(bounds);
+
+        let mut mesh = MeshSurface {
+            vertices,
+            triangles,
+            bvh,
+            dipoles: vec![],
+        };
+
+        mesh.dipoles = (0..mesh.bvh.node_count())
+            .map(|node| mesh.dipole(node))
+            .collect();
+
+        mesh
+    }
+
+    // is_valid is whether `new` would accept the mesh, for checking meshes that come from outside first
+    pub fn is_valid(vertices: &[Point3<f32>], triangles: &[[u32; 3]]) -> bool {
+        !triangles.is_empty()
+            && triangles
+                .iter()
+                .flatten()
+                .all(|i| (*i as usize) < vertices.len())
+            && vertices.iter().all(|v| v.iter().all(|c| c.is_finite()))
+    }
+
+    // from_obj reads the vertices and faces out of a Wavefront OBJ file, everything else is ignored
+    // Faces with more than 3 vertices are split into fans
+    // Returns None if the file can't be parsed or doesn't make a valid mesh
+    pub fn from_obj(source: &str) -> Option<Self> {
+        let mut vertices = vec![];
+        let mut triangles = vec![];
+
+        for line in source.lines() {
+            let mut words = line.split_whitespace();
+
+            match words.next() {
+                Some("v") => {
+                    let mut coordinate = || words.next()?.parse::<f32>().ok();
+
+                    vertices.push(Point3::new(coordinate()?, coordinate()?, coordinate()?))
+                }
+                Some("f") => {
+                    // Only the vertex index is wanted out of "v/vt/vn", negative indices count back from the end
+                    let face = words
+                        .map(|w| {
+                            let index: i64 = w.split('/').next()?.parse().ok()?;
+                            let index = if index < 0 {
+                                vertices.len() as i64 + index
+                            } else {
+                                index - 1
+                            };
+
+                            u32::try_from(index).ok()
+                        })
+                        .collect::<Option<Vec<u32>>>()?;
+
+                    if face.len() < 3 {
+                        return None;
+                    }
+                    for i in 1..face.len() - 1 {
+                        triangles.push([face[0], face[i], face[i + 1]])
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        MeshSurface::is_valid(&vertices, &triangles).then(|| MeshSurface::new(vertices, triangles))
+    }
+
+    pub fn vertices(&self) -> &[Point3<f32>] {
+        &self.vertices
+    }
+
+    pub fn triangles(&self) -> &[[u32; 3]] {
+        &self.triangles
+    }
+
+    pub fn bounds(&self) -> &Aabb {
+        self.bvh.bounds().expect("mesh should have triangles")
+    }
+
+    fn triangle(&self, index: usize) -> [Point3<f32>; 3] {
+        self.triangles[index].map(|i| self.vertices[i as usize])
+    }
+
+    // area_normal is the triangle's normal scaled by its area
+    fn area_normal(&self, index: usize) -> Vector3<f32> {
+        let [a, b, c] = self.triangle(index);
+
+        (b - a).cross(&(c - a)) / 2.0
+    }
+
+    fn dipole(&self, node: usize) -> Dipole {
+        let items = self.bvh.node_items(node);
+
+        let mut area_normal = Vector3::zeros();
+        let mut weighted_center = Vector3::zeros();
+        let mut area = 0.0;
+        for i in items {
+            let [a, b, c] = self.triangle(*i);
+            let n = self.area_normal(*i);
+            let triangle_area = n.magnitude();
+
+            area_normal += n;
+            weighted_center += (a.coords + b.coords + c.coords) / 3.0 * triangle_area;
+            area += triangle_area;
+        }
+
+        let bounds = self.bvh.node_bounds(node);
+        let center = if area > 0.0 {
+            Point3::from(weighted_center / area)
+        } else {
+            bounds.center()
+        };
+
+        let furthest =
+            Vector3::from_fn(|i, _| (center[i] - bounds.min[i]).max(bounds.max[i] - center[i]));
+        let radius = furthest.magnitude();
+
+        Dipole {
+            center,
+            area_normal,
+            radius,
+        }
+    }
+
+    // solid_angle is the solid angle the triangle covers seen from `at`, negative if it's seen from behind
+    // (Van Oosterom and Strackee)
+    fn solid_angle(&self, index: usize, at: Point3<f32>) -> f32 {
+        let [a, b, c] = self.triangle(index).map(|v| v - at);
+        let (la, lb, lc) = (a.magnitude(), b.magnitude(), c.magnitude());
+
+        let numerator = a.dot(&b.cross(&c));
+        let denominator = la * lb * lc + a.dot(&b) * lc + b.dot(&c) * la + c.dot(&a) * lb;
+
+        2.0 * numerator.atan2(denominator)
+    }
+
+    // winding_number is about 1 inside the mesh and about 0 outside
+    pub fn winding_number(&self, at: Point3<f32>) -> f32 {
+        let total = Cell::new(0.0);
+
+        self.bvh.visit_nodes(
+            |node, _| {
+                let dipole = &self.dipoles[node];
+                let offset = dipole.center - at;
+                let distance = offset.magnitude();
+
+                if distance > dipole.radius * WINDING_ACCURACY {
+                    let far = dipole.area_normal.dot(&offset) / (distance * distance * distance);
+                    total.set(total.get() + far);
+                    return false;
+                }
+
+                true
+            },
+            |i| total.set(total.get() + self.solid_angle(i, at)),
+        );
+
+        total.get() / (4.0 * PI)
+    }
+
+    // closest_point returns the closest point on the mesh, and the triangle it's on
+    pub fn closest_point(&self, at: Point3<f32>) -> (Point3<f32>, usize) {
+        let (triangle, _) = self
+            .bvh
+            .nearest(at, |i| {
+                (closest_on_triangle(at, self.triangle(i)) - at).magnitude()
+            })
+            .expect("mesh should have triangles");
+
+        (closest_on_triangle(at, self.triangle(triangle)), triangle)
+    }
+
+    // evaluate returns the distance and its gradient together, sample and gradient each have to find
+    // the closest triangle and walk the winding number, so callers wanting both should use this
+    pub fn evaluate(&self, at: Point3<f32>) -> (f32, Vector3<f32>) {
+        let (closest, triangle) = self.closest_point(at);
+        let sign = if self.winding_number(at) > 0.5 {
+            -1.0
+        } else {
+            1.0
+        };
+
+        let offset = at - closest;
+        let distance = offset.magnitude();
+
+        // Right on the surface there's no direction to the closest point, so the face normal is used instead
+        let gradient = match offset.try_normalize(f32::EPSILON) {
+            Some(direction) => direction * sign,
+            None => self.area_normal(triangle).normalize(),
+        };
+
+        (distance * sign, gradient)
+    }
+}
+
+// closest_on_triangle is the closest point on the triangle to `p` (Ericson, Real-Time Collision Detection)
+fn closest_on_triangle(p: Point3<f32>, [a, b, c]: [Point3<f32>; 3]) -> Point3<f32> {
+    let (ab, ac, ap) = (b - a, c - a, p - a);
+    let (d1, d2) = (ab.dot(&ap), ac.dot(&ap));
+    if d1 <= 0.0 && d2 <= 0.0 {
+        return a;
+    }
+
+    let bp = p - b;
+    let (d3, d4) = (ab.dot(&bp), ac.dot(&bp));
+    if d3 >= 0.0 && d4 <= d3 {
+        return b;
+    }
+
+    let vc = d1 * d4 - d3 * d2;
+    if vc <= 0.0 && d1 >= 0.0 && d3 <= 0.0 {
+        return a + ab * (d1 / (d1 - d3));
+    }
+
+    let cp = p - c;
+    let (d5, d6) = (ab.dot(&cp), ac.dot(&cp));
+    if d6 >= 0.0 && d5 <= d6 {
+        return c;
+    }
+
+    let vb = d5 * d2 - d1 * d6;
+    if vb <= 0.0 && d2 >= 0.0 && d6 <= 0.0 {
+        return a + ac * (d2 / (d2 - d6));
+    }
+
+    let va = d3 * d6 - d5 * d4;
+    if va <= 0.0 && (d4 - d3) >= 0.0 && (d5 - d6) >= 0.0 {
+        return b + (c - b) * ((d4 - d3) / ((d4 - d3) + (d5 - d6)));
+    }
+
+    let denominator = 1.0 / (va + vb + vc);
+    a + ab * (vb * denominator) + ac * (vc * denominator)
+}
+
+impl Surface for MeshSurface {
+    fn sample(&self, at: Point3<f32>) -> f32 {
+        self.evaluate(at).0
+    }
+
+    fn gradient(&self, at: Point3<f32>) -> Vector3<f32> {
+        self.evaluate(at).1
+    }
+}
@@ -0,0 +1,328 @@
+use std::collections::HashMap;
+
+use nalgebra::{Point3, Vector3};
+use serde::{Deserialize, Serialize};
+
+use crate::surface::{find_crossing, Surface};
+
+// Blobby objects, every ball adds a contribution that falls off to nothing at its radius
+// The surface is where the summed contributions reach the threshold, so nearby balls melt into each other
+
+// Falloff is the kernel used to turn distance from a ball into its contribution
+// Every kernel is 1 at the center of a ball and 0 at its radius
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Falloff {
+    // Blinn's gaussian blobs, shifted and rescaled so they reach 0 at the radius instead of going on forever
+    // Higher blobbiness gives tighter blobs
+    Blinn { blobbiness: f32 },
+    // (1 - r²/R²)³ from Wyvill and Galin
+    Wyvill,
+    // The degree six "soft object" polynomial from Wyvill, McPheeters and Wyvill
+    SoftObject,
+}
+
+impl Falloff {
+    // is_valid is whether the kernel is well defined, Blinn blobs need a positive blobbiness or they divide by zero
+    pub fn is_valid(&self) -> bool {
+        match *self {
+            Falloff::Blinn { blobbiness } => blobbiness > 0.0 && blobbiness.is_finite(),
+            Falloff::Wyvill | Falloff::SoftObject => true,
+        }
+    }
+
+    // contribution takes t = r²/R² and returns the kernel value and its derivative with respect to t
+    fn contribution(&self, t: f32) -> (f32, f32) {
+        if t >= 1.0 {
+            return (0.0, 0.0);
+        }
+
+        match *self {
+            Falloff::Blinn { blobbiness } => {
+                let edge = (-blobbiness).exp();
+                let e = (-blobbiness * t).exp();
+
+                ((e - edge) / (1.0 - edge), -blobbiness * e / (1.0 - edge))
+            }
+            Falloff::Wyvill => {
+                let u = 1.0 - t;
+
+                (u * u * u, -3.0 * u * u)
+            }
+            Falloff::SoftObject => {
+                let (t2, t3) = (t * t, t * t * t);
+
+                (
+                    1.0 - (4.0 / 9.0) * t3 + (17.0 / 9.0) * t2 - (22.0 / 9.0) * t,
+                    -(4.0 / 3.0) * t2 + (34.0 / 9.0) * t - (22.0 / 9.0),
+                )
+            }
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Ball {
+    pub center: Point3<f32>,
+    pub radius: f32,
+    pub strength: f32,
+}
+
+impl Ball {
+    pub fn new(center: Point3<f32>, radius: f32, strength: f32) -> Self {
+        let ball = Ball {
+            center,
+            radius,
+            strength,
+        };
+        assert!(
+            ball.is_valid(),
+            "balls need a positive radius and finite values"
+        );
+
+        ball
+    }
+
+    // is_valid is whether the ball can be sampled, a radius of 0 would make every contribution NaN
+    pub fn is_valid(&self) -> bool {
+        self.radius > 0.0
+            && self.radius.is_finite()
+            && self.strength.is_finite()
+            && self.center.iter().all(|c| c.is_finite())
+    }
+}
+
+// BallGrid buckets balls into a uniform grid, every ball is listed in each cell its bounds overlap
+// A sample then only has to look at the balls listed in the one cell it falls in
+#[derive(Debug, Default)]
+struct BallGrid {
+    cell_size: f32,
+    cells: HashMap<[i32; 3], Vec<usize>>,
+}
+
+impl BallGrid {
+    fn new(balls: &[Ball]) -> Self {
+        if balls.is_empty() {
+            return BallGrid::default();
+        }
+
+        // Cells the size of an average ball keep both the number of cells per ball and balls per cell small
+        let cell_size = balls.iter().map(|b| b.radius).sum::<f32>() / balls.len() as f32;
+
+        let mut grid = BallGrid {
+            cell_size,
+            cells: HashMap::new(),
+        };
+
+        for (i, ball) in balls.iter().enumerate() {
+            let r = Vector3::repeat(ball.radius);
+            let (min, max) = (grid.cell(ball.center - r), grid.cell(ball.center + r));
+
+            for x in min[0]..=max[0] {
+                for y in min[1]..=max[1] {
+                    for z in min[2]..=max[2] {
+                        grid.cells.entry([x, y, z]).or_default().push(i)
+                    }
+                }
+            }
+        }
+
+        grid
+    }
+
+    fn cell(&self, at: Point3<f32>) -> [i32; 3] {
+        [0, 1, 2].map(|i| (at[i] / self.cell_size).floor() as i32)
+    }
+
+    fn balls_near(&self, at: Point3<f32>) -> &[usize] {
+        if self.cells.is_empty() {
+            return &[];
+        }
+
+        self.cells.get(&self.cell(at)).map_or(&[], Vec::as_slice)
+    }
+}
+
+fn assert_falloff(falloff: &Falloff, threshold: f32) {
+    assert!(falloff.is_valid(), "falloff should be valid");
+    assert!(
+        threshold > 0.0,
+        "threshold should be positive, otherwise all of space is inside"
+    );
+}
+
+pub struct Metaballs {
+    balls: Vec<Ball>,
+    falloff: Falloff,
+    threshold: f32,
+
+    index: BallGrid,
+}
+
+impl Metaballs {
+    pub fn new(falloff: Falloff, threshold: f32) -> Self {
+        assert_falloff(&falloff, threshold);
+
+        Metaballs {
+            balls: vec![],
+            falloff,
+            threshold,
+            index: BallGrid::default(),
+        }
+    }
+
+    pub fn from_balls(balls: Vec<Ball>, falloff: Falloff, threshold: f32) -> Self {
+        assert_falloff(&falloff, threshold);
+        assert!(
+            balls.iter().all(Ball::is_valid),
+            "every ball should be valid"
+        );
+
+        let index = BallGrid::new(&balls);
+
+        Metaballs {
+            balls,
+            falloff,
+            threshold,
+            index,
+        }
+    }
+
+    pub fn balls(&self) -> &[Ball] {
+        &self.balls
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.balls.is_empty()
+    }
+
+    // push adds a ball, reindex has to be called before the new ball shows up in samples
+    pub fn push(&mut self, ball: Ball) {
+        assert!(
+            ball.is_valid(),
+            "balls need a positive radius and finite values"
+        );
+
+        self.balls.push(ball)
+    }
+
+    pub fn clear(&mut self) {
+        self.balls.clear();
+        self.index = BallGrid::default();
+    }
+
+    // take moves the balls out into new Metaballs with the same falloff, leaving these empty
+    pub fn take(&mut self) -> Metaballs {
+        Metaballs {
+            balls: std::mem::take(&mut self.balls),
+            falloff: self.falloff,
+            threshold: self.threshold,
+            index: std::mem::take(&mut self.index),
+        }
+    }
+
+    pub fn falloff(&self) -> (Falloff, f32) {
+        (self.falloff, self.threshold)
+    }
+
+    pub fn set_falloff(&mut self, falloff: Falloff, threshold: f32) {
+        assert_falloff(&falloff, threshold);
+
+        self.falloff = falloff;
+        self.threshold = threshold;
+    }
+
+    // reindex rebuilds the spatial index over all the balls
+    pub fn reindex(&mut self) {
+        self.index = BallGrid::new(&self.balls);
+    }
+
+    // contributions calls `f` with each ball near `at`, along with its kernel value and derivative
+    fn contributions(&self, at: Point3<f32>, mut f: impl FnMut(&Ball, f32, f32)) {
+        for &i in self.index.balls_near(at) {
+            let ball = &self.balls[i];
+
+            let t = (at - ball.center).norm_squared() / (ball.radius * ball.radius);
+            if t < 1.0 {
+                let (k, dk) = self.falloff.contribution(t);
+                f(ball, k, dk)
+            }
+        }
+    }
+}
+
+impl Surface for Metaballs {
+    fn sample(&self, at: Point3<f32>) -> f32 {
+        let mut sum = 0.0;
+        self.contributions(at, |ball, k, _| sum += ball.strength * k);
+
+        self.threshold - sum
+    }
+
+    fn gradient(&self, at: Point3<f32>) -> Vector3<f32> {
+        let mut gradient = Vector3::zeros();
+        self.contributions(at, |ball, _, dk| {
+            // d(r²/R²) = 2(p - c)/R²
+            let dt = (at - ball.center) * (2.0 / (ball.radius * ball.radius));
+            gradient -= dt * (ball.strength * dk)
+        });
+
+        gradient
+    }
+
+    // The field is flat away from the balls, so searching from a random point would never find the surface
+    // Instead walk out from a ball's center until the field crosses the threshold
+    fn seed_hint(&self) -> Option<Point3<f32>> {
+        let ball = self.balls.iter().find(|b| self.sample(b.center) < 0.0)?;
+
+        Some(find_crossing(self, ball.center, Vector3::x(), ball.radius))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::surface::seed;
+    use crate::ImplicitSampler;
+
+    // A ball too weak to reach the threshold has no surface, the field is above zero everywhere
+    fn sub_threshold() -> Metaballs {
+        Metaballs::from_balls(
+            vec![Ball::new(Point3::origin(), 1.0, 0.3)],
+            Falloff::Wyvill,
+            0.5,
+        )
+    }
+
+    #[test]
+    fn sub_threshold_balls_have_no_seed() {
+        let metaballs = sub_threshold();
+
+        assert!(metaballs.sample(Point3::origin()) > 0.0);
+        assert_eq!(metaballs.seed_hint(), None);
+        assert_eq!(seed(&metaballs), None);
+    }
+
+    #[test]
+    fn sub_threshold_balls_sample_nothing() {
+        let metaballs = sub_threshold();
+
+        let mut sampler = ImplicitSampler::<1000>::new();
+        for _ in 0..10 {
+            sampler.update(0.1, &metaballs)
+        }
+
+        assert_eq!(sampler.samples().len(), 0);
+    }
+
+    #[test]
+    fn balls_over_the_threshold_are_seeded() {
+        let metaballs = Metaballs::from_balls(
+            vec![Ball::new(Point3::origin(), 1.0, 1.0)],
+            Falloff::Wyvill,
+            0.5,
+        );
+
+        let at = seed(&metaballs).expect("the ball reaches the threshold, so there's a surface");
+        assert!(metaballs.sample(at).abs() < 1e-3);
+    }
+}
@@ -0,0 +1,128 @@
+use nalgebra::{Point3, Vector3};
+
+use crate::plane::Plane;
+use crate::shapes::smooth_min;
+use crate::surface::Surface;
+use crate::tape::{Compile, TapeBuilder, Value};
+
+// Mirror is a surface unioned with its own reflection across a plane
+// Only one side of a symmetric creature has to be described, the other side comes from the reflection
+pub struct Mirror<S> {
+    pub surface: S,
+    pub plane: Plane,
+    // Size of the smooth min where the two halves meet, 0 for a plain union
+    pub blend: f32,
+}
+
+impl<S> Mirror<S> {
+    pub fn new(surface: S, plane: Plane, blend: f32) -> Self {
+        assert!(blend >= 0.0, "blend can't be negative");
+
+        Mirror {
+            surface,
+            plane,
+            blend,
+        }
+    }
+
+    fn combine(&self, a: f32, b: f32) -> f32 {
+        if self.blend > 0.0 {
+            smooth_min(a, b, self.blend)
+        } else {
+            a.min(b)
+        }
+    }
+
+    // weight is how much of the gradient comes from the unreflected side, same as SmoothUnion
+    fn weight(&self, a: f32, b: f32) -> f32 {
+        let h = if self.blend > 0.0 {
+            (self.blend - (a - b).abs()).max(0.0) / (2.0 * self.blend)
+        } else {
+            0.0
+        };
+
+        if a < b {
+            1.0 - h
+        } else {
+            h
+        }
+    }
+}
+
+impl<S: Surface> Surface for Mirror<S> {
+    fn sample(&self, at: Point3<f32>) -> f32 {
+        let reflected = self.plane.reflect_point(at);
+
+        self.combine(self.surface.sample(at), self.surface.sample(reflected))
+    }
+
+    // The reflected side's gradient is taken at the reflected point, then reflected back
+    fn gradient(&self, at: Point3<f32>) -> Vector3<f32> {
+        let reflected = self.plane.reflect_point(at);
+        let (a, b) = (self.surface.sample(at), self.surface.sample(reflected));
+        let weight = self.weight(a, b);
+
+        self.surface.gradient(at) * weight
+            + self.plane.reflect_vector(self.surface.gradient(reflected)) * (1.0 - weight)
+    }
+
+    fn sample_batch(&self, points: &[Point3<f32>], out: &mut [f32]) {
+        let reflected: Vec<_> = points
+            .iter()
+            .map(|p| self.plane.reflect_point(*p))
+            .collect();
+        let mut other = vec![0.0; points.len()];
+
+        self.surface.sample_batch(points, out);
+        self.surface.sample_batch(&reflected, &mut other);
+
+        for (o, b) in out.iter_mut().zip(other) {
+            *o = self.combine(*o, b)
+        }
+    }
+
+    fn gradient_batch(&self, points: &[Point3<f32>], out: &mut [Vector3<f32>]) {
+        let reflected: Vec<_> = points
+            .iter()
+            .map(|p| self.plane.reflect_point(*p))
+            .collect();
+
+        let (mut a, mut b) = (vec![0.0; points.len()], vec![0.0; points.len()]);
+        self.surface.sample_batch(points, &mut a);
+        self.surface.sample_batch(&reflected, &mut b);
+
+        let mut other = vec![Vector3::zeros(); points.len()];
+        self.surface.gradient_batch(points, out);
+        self.surface.gradient_batch(&reflected, &mut other);
+
+        for (i, o) in out.iter_mut().enumerate() {
+            let weight = self.weight(a[i], b[i]);
+
+            *o = *o * weight + self.plane.reflect_vector(other[i]) * (1.0 - weight)
+        }
+    }
+
+    // The unreflected side is still all there, so its hint still works
+    fn seed_hint(&self) -> Option<Point3<f32>> {
+        self.surface.seed_hint()
+    }
+}
+
+impl<S: Compile> Compile for Mirror<S> {
+    fn compile(&self, b: &mut TapeBuilder, at: [Value; 3]) -> Value {
+        let m = self.plane.reflection();
+        let reflected =
+            [0, 1, 2].map(|row| b.affine(at, [m[(row, 0)], m[(row, 1)], m[(row, 2)], m[(row, 3)]]));
+
+        let (fa, fb) = (
+            self.surface.compile(b, at),
+            self.surface.compile(b, reflected),
+        );
+
+        if self.blend > 0.0 {
+            b.smooth_min(fa, fb, self.blend)
+        } else {
+            b.min(fa, fb)
+        }
+    }
+}
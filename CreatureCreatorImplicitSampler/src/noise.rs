@@ -0,0 +1,505 @@
+use nalgebra::{Point3, Vector3};
+use serde::{Deserialize, Serialize};
+
+use crate::surface::Surface;
+
+// Gradient noise for adding small detail to a surface, like bumps, wrinkles and scales
+// Every noise is deterministic for a given seed, comes with an exact gradient, and stays within [-1, 1]
+// Each basis also has a known Lipschitz constant, so how much a displacement can bend a surface is known up front
+
+// Bounds on each basis are worked out per point of a cell by picking whichever of the 12 lattice gradients at each
+// corner pushes the value, or the slope, up the most. That's the worst any seed can do at that point, and the
+// bounds are the worst of those over the whole cell. They're all per unit distance in noise space, before frequency
+
+// Perlin peaks at 1.03635, just off the center of a cell, so it's scaled back down inside [-1, 1]
+const PERLIN_MAX: f32 = 1.0364;
+const PERLIN_SCALE: f32 = 1.0 / PERLIN_MAX;
+// Perlin's slope peaks right at the center of a cell, where the fade is steepest. Its 15/8 counts twice there
+const PERLIN_LIPSCHITZ: f32 = (15.0 / 4.0) * PERLIN_SCALE + 0.001;
+
+// Simplex contributions are scaled by this to bring the noise up to about [-1, 1], it peaks at 0.98855
+const SIMPLEX_SCALE: f32 = 76.0;
+// The steepest simplex gets is 6.8872, between the corners of a simplex
+const SIMPLEX_LIPSCHITZ: f32 = 6.9;
+
+// Worley is a distance, so its slope is exactly its scale
+const WORLEY_LIPSCHITZ: f32 = 2.0 / WORLEY_RANGE;
+
+// The closest feature point can be as far as the far corner of the cell, but that needs all eight cells around a
+// corner to put their points as far away as they can. Over 20 million random points the furthest was 1.263, and only
+// one in a thousand was past 0.992, so distances are capped at 1 and that's what's scaled to [-1, 1]
+const WORLEY_RANGE: f32 = 1.0;
+
+// MAX_SLOPE is the most a displacement is allowed to change the gradient of the surface under it
+// With |∇F| = 1 the displaced gradient stays within [1 - MAX_SLOPE, 1 + MAX_SLOPE], so particle spacing barely changes
+pub const MAX_SLOPE: f32 = 0.5;
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Basis {
+    // Improved Perlin noise, with gradients along the 12 edges of a cube
+    Perlin,
+    // Simplex noise, smoother and without Perlin's axis-aligned look
+    Simplex,
+    // Distance to the closest of one random point per cell, capped at 1 and scaled to [-1, 1]. Gives a cellular look,
+    // like scales
+    Worley,
+}
+
+// Fractal noise adds up octaves of the basis, each one at a higher frequency and usually a lower weight
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Fractal {
+    Single,
+    // Fractional Brownian motion, just the sum of the octaves
+    Fbm {
+        octaves: u32,
+        lacunarity: f32,
+        gain: f32,
+    },
+    // Every octave is folded into 1 - 2|n|, so the zeros of the noise turn into sharp creases, like wrinkles
+    Ridged {
+        octaves: u32,
+        lacunarity: f32,
+        gain: f32,
+    },
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Noise {
+    pub basis: Basis,
+    pub fractal: Fractal,
+    pub seed: u32,
+}
+
+impl Noise {
+    pub fn new(basis: Basis, fractal: Fractal, seed: u32) -> Self {
+        let noise = Noise {
+            basis,
+            fractal,
+            seed,
+        };
+        assert!(
+            noise.is_valid(),
+            "fractal noise should have at least one octave, and positive lacunarity and gain"
+        );
+
+        noise
+    }
+
+    // is_valid is whether the noise can be evaluated, with no octaves the weights add up to 0 and it's all NaN
+    pub fn is_valid(&self) -> bool {
+        match self.fractal {
+            Fractal::Single => true,
+            Fractal::Fbm {
+                octaves,
+                lacunarity,
+                gain,
+            }
+            | Fractal::Ridged {
+                octaves,
+                lacunarity,
+                gain,
+            } => {
+                octaves >= 1
+                    && lacunarity > 0.0
+                    && lacunarity.is_finite()
+                    && gain > 0.0
+                    && gain.is_finite()
+            }
+        }
+    }
+
+    pub fn sample(&self, at: Point3<f32>) -> f32 {
+        self.evaluate(at).0
+    }
+
+    pub fn gradient(&self, at: Point3<f32>) -> Vector3<f32> {
+        self.evaluate(at).1
+    }
+
+    // evaluate returns the noise and its gradient
+    pub fn evaluate(&self, at: Point3<f32>) -> (f32, Vector3<f32>) {
+        match self.fractal {
+            Fractal::Single => self.octave(at, self.seed),
+            Fractal::Fbm {
+                octaves,
+                lacunarity,
+                gain,
+            } => self.octaves(at, octaves, lacunarity, gain, |n| n),
+            Fractal::Ridged {
+                octaves,
+                lacunarity,
+                gain,
+            } => self.octaves(at, octaves, lacunarity, gain, |(n, g)| {
+                (1.0 - 2.0 * n.abs(), g * (-2.0 * n.signum()))
+            }),
+        }
+    }
+
+    // lipschitz is the most the noise can change per unit distance
+    pub fn lipschitz(&self) -> f32 {
+        let basis = match self.basis {
+            Basis::Perlin => PERLIN_LIPSCHITZ,
+            Basis::Simplex => SIMPLEX_LIPSCHITZ,
+            Basis::Worley => WORLEY_LIPSCHITZ,
+        };
+
+        // Each octave's slope is scaled by its frequency and weight, and the sum is divided by the total weight
+        let octaves = |octaves: u32, lacunarity: f32, gain: f32| {
+            let (mut slope, mut total) = (0.0, 0.0);
+            for i in 0..octaves as i32 {
+                slope += gain.powi(i) * lacunarity.powi(i);
+                total += gain.powi(i);
+            }
+
+            slope / total
+        };
+
+        match self.fractal {
+            Fractal::Single => basis,
+            Fractal::Fbm {
+                octaves: n,
+                lacunarity,
+                gain,
+            } => basis * octaves(n, lacunarity, gain),
+            Fractal::Ridged {
+                octaves: n,
+                lacunarity,
+                gain,
+            } => 2.0 * basis * octaves(n, lacunarity, gain),
+        }
+    }
+
+    // octaves sums up `shape` applied to every octave, weighted so the total stays within [-1, 1]
+    fn octaves(
+        &self,
+        at: Point3<f32>,
+        octaves: u32,
+        lacunarity: f32,
+        gain: f32,
+        shape: impl Fn((f32, Vector3<f32>)) -> (f32, Vector3<f32>),
+    ) -> (f32, Vector3<f32>) {
+        let mut value = 0.0;
+        let mut gradient = Vector3::zeros();
+        let mut total = 0.0;
+
+        let (mut frequency, mut weight) = (1.0, 1.0);
+        for i in 0..octaves {
+            // Every octave gets its own seed, otherwise they'd all line up at the origin
+            let (n, g) = shape(self.octave(at * frequency, self.seed.wrapping_add(i)));
+
+            value += n * weight;
+            gradient += g * (weight * frequency);
+            total += weight;
+
+            frequency *= lacunarity;
+            weight *= gain;
+        }
+
+        (value / total, gradient / total)
+    }
+
+    // The bounds leave no room for rounding, so values are clamped to keep the noise inside [-1, 1]
+    // Clamping can't make the noise any steeper
+    fn octave(&self, at: Point3<f32>, seed: u32) -> (f32, Vector3<f32>) {
+        let (value, gradient) = match self.basis {
+            Basis::Perlin => perlin(at, seed),
+            Basis::Simplex => simplex(at, seed),
+            Basis::Worley => worley(at, seed),
+        };
+
+        (value.clamp(-1.0, 1.0), gradient)
+    }
+}
+
+// hash mixes a lattice point and a seed into 32 random looking bits (the murmur3 finalizer)
+fn hash(x: i32, y: i32, z: i32, seed: u32) -> u32 {
+    let mut h = (x as u32).wrapping_mul(0x8da6_b343)
+        ^ (y as u32).wrapping_mul(0xd816_3841)
+        ^ (z as u32).wrapping_mul(0xcb1a_b31f)
+        ^ seed.wrapping_mul(0x9e37_79b9);
+
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x85eb_ca6b);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0xc2b2_ae35);
+    h ^= h >> 16;
+
+    h
+}
+
+// lattice_gradient picks one of the 12 edge directions of a cube for a lattice point
+fn lattice_gradient(x: i32, y: i32, z: i32, seed: u32) -> Vector3<f32> {
+    const GRADIENTS: [[f32; 3]; 12] = [
+        [1.0, 1.0, 0.0],
+        [-1.0, 1.0, 0.0],
+        [1.0, -1.0, 0.0],
+        [-1.0, -1.0, 0.0],
+        [1.0, 0.0, 1.0],
+        [-1.0, 0.0, 1.0],
+        [1.0, 0.0, -1.0],
+        [-1.0, 0.0, -1.0],
+        [0.0, 1.0, 1.0],
+        [0.0, -1.0, 1.0],
+        [0.0, 1.0, -1.0],
+        [0.0, -1.0, -1.0],
+    ];
+
+    // The top bits are the best mixed
+    Vector3::from(GRADIENTS[((hash(x, y, z, seed) >> 8) % 12) as usize])
+}
+
+fn perlin(at: Point3<f32>, seed: u32) -> (f32, Vector3<f32>) {
+    let cell = at.map(f32::floor);
+    let f = at - cell;
+    let [cx, cy, cz] = [cell.x as i32, cell.y as i32, cell.z as i32];
+
+    // Quintic fade, so the second derivative is continuous across cells
+    let u = f.map(|t| t * t * t * (t * (t * 6.0 - 15.0) + 10.0));
+    let du = f.map(|t| 30.0 * t * t * (t * (t - 2.0) + 1.0));
+
+    let mut value = 0.0;
+    let mut gradient = Vector3::zeros();
+    for corner in 0..8 {
+        let c = Vector3::new(corner & 1, (corner >> 1) & 1, (corner >> 2) & 1);
+
+        let g = lattice_gradient(cx + c.x, cy + c.y, cz + c.z, seed);
+        let n = g.dot(&(f - c.map(|i| i as f32)));
+
+        // Each axis weighs the corner by u or 1 - u, depending on which side of the cell it's on
+        let w = Vector3::from_fn(|i, _| if c[i] == 1 { u[i] } else { 1.0 - u[i] });
+        let dw = Vector3::from_fn(|i, _| if c[i] == 1 { du[i] } else { -du[i] });
+
+        value += w.x * w.y * w.z * n;
+        gradient += g * (w.x * w.y * w.z)
+            + Vector3::new(dw.x * w.y * w.z, w.x * dw.y * w.z, w.x * w.y * dw.z) * n;
+    }
+
+    (value * PERLIN_SCALE, gradient * PERLIN_SCALE)
+}
+
+// Simplex noise with analytic derivatives (Gustavson, "Simplex noise demystified")
+// The kernel radius is 0.5 rather than the usual 0.6, so contributions reach zero before the next simplex
+fn simplex(at: Point3<f32>, seed: u32) -> (f32, Vector3<f32>) {
+    const SKEW: f32 = 1.0 / 3.0;
+    const UNSKEW: f32 = 1.0 / 6.0;
+
+    // Find the cube the point is in after skewing, then which of its 6 tetrahedra
+    let s = (at.x + at.y + at.z) * SKEW;
+    let cell = (at.coords + Vector3::repeat(s)).map(f32::floor);
+    let t = (cell.x + cell.y + cell.z) * UNSKEW;
+    let d0 = at.coords - (cell - Vector3::repeat(t));
+
+    let (o1, o2) = if d0.x >= d0.y {
+        if d0.y >= d0.z {
+            ([1, 0, 0], [1, 1, 0])
+        } else if d0.x >= d0.z {
+            ([1, 0, 0], [1, 0, 1])
+        } else {
+            ([0, 0, 1], [1, 0, 1])
+        }
+    } else if d0.y < d0.z {
+        ([0, 0, 1], [0, 1, 1])
+    } else if d0.x < d0.z {
+        ([0, 1, 0], [0, 1, 1])
+    } else {
+        ([0, 1, 0], [1, 1, 0])
+    };
+
+    let [cx, cy, cz] = [cell.x as i32, cell.y as i32, cell.z as i32];
+
+    let mut value = 0.0;
+    let mut gradient = Vector3::zeros();
+    for (i, o) in [[0, 0, 0], o1, o2, [1, 1, 1]].into_iter().enumerate() {
+        let offset = Vector3::new(o[0] as f32, o[1] as f32, o[2] as f32);
+        let d = d0 - offset + Vector3::repeat(i as f32 * UNSKEW);
+
+        let falloff = 0.5 - d.norm_squared();
+        if falloff <= 0.0 {
+            continue;
+        }
+
+        let g = lattice_gradient(cx + o[0], cy + o[1], cz + o[2], seed);
+        let n = g.dot(&d);
+        let (f2, f3) = (falloff * falloff, falloff * falloff * falloff);
+
+        value += f2 * f2 * n;
+        gradient += g * (f2 * f2) - d * (8.0 * f3 * n);
+    }
+
+    (value * SIMPLEX_SCALE, gradient * SIMPLEX_SCALE)
+}
+
+fn worley(at: Point3<f32>, seed: u32) -> (f32, Vector3<f32>) {
+    let cell = at.map(f32::floor);
+    let [cx, cy, cz] = [cell.x as i32, cell.y as i32, cell.z as i32];
+
+    let mut closest = f32::MAX;
+    let mut direction = Vector3::zeros();
+    for z in cz - 1..=cz + 1 {
+        for y in cy - 1..=cy + 1 {
+            for x in cx - 1..=cx + 1 {
+                // Three more hashes place the feature point somewhere in its cell
+                let h = hash(x, y, z, seed);
+                let jitter = Vector3::new(
+                    (h >> 8) as f32,
+                    (hash(x, y, z, h) >> 8) as f32,
+                    (hash(x, y, z, h ^ 0x5bd1_e995) >> 8) as f32,
+                ) / (1 << 24) as f32;
+
+                let feature = Vector3::new(x as f32, y as f32, z as f32) + jitter;
+                let offset = at.coords - feature;
+                let distance = offset.magnitude();
+
+                if distance < closest {
+                    closest = distance;
+                    direction = offset;
+                }
+            }
+        }
+    }
+
+    // Past the cap the noise is flat
+    if closest >= WORLEY_RANGE {
+        return (1.0, Vector3::zeros());
+    }
+
+    let scale = 2.0 / WORLEY_RANGE;
+    let gradient = direction.try_normalize(0.0).unwrap_or_else(Vector3::zeros);
+
+    (closest * scale - 1.0, gradient * scale)
+}
+
+// displacement_slope is the most displacing by `noise` adds to the Lipschitz constant of a surface
+// Displaced won't take anything over MAX_SLOPE
+pub fn displacement_slope(noise: &Noise, amplitude: f32, frequency: f32) -> f32 {
+    amplitude * frequency * noise.lipschitz()
+}
+
+// Displaced pushes a surface in and out by noise, `amplitude` is the furthest the surface can move
+// `frequency` scales points going into the noise, so higher frequency gives smaller detail
+pub struct Displaced<S> {
+    surface: S,
+    noise: Noise,
+    amplitude: f32,
+    frequency: f32,
+}
+
+impl<S> Displaced<S> {
+    pub fn new(surface: S, noise: Noise, amplitude: f32, frequency: f32) -> Self {
+        assert!(noise.is_valid(), "noise should be valid");
+        assert!(amplitude >= 0.0, "amplitude can't be negative");
+        assert!(frequency > 0.0, "frequency should be positive");
+        assert!(
+            displacement_slope(&noise, amplitude, frequency) <= MAX_SLOPE,
+            "displacement is too steep, amplitude should be at most {}",
+            Self::max_amplitude(&noise, frequency)
+        );
+
+        Displaced {
+            surface,
+            noise,
+            amplitude,
+            frequency,
+        }
+    }
+
+    // max_amplitude is the largest amplitude that keeps the displacement within MAX_SLOPE at `frequency`
+    pub fn max_amplitude(noise: &Noise, frequency: f32) -> f32 {
+        MAX_SLOPE / (noise.lipschitz() * frequency)
+    }
+
+    // slope is the most the displacement can add to the Lipschitz constant of the surface under it
+    pub fn slope(&self) -> f32 {
+        displacement_slope(&self.noise, self.amplitude, self.frequency)
+    }
+
+    pub fn surface(&self) -> &S {
+        &self.surface
+    }
+
+    pub fn noise(&self) -> &Noise {
+        &self.noise
+    }
+
+    pub fn amplitude(&self) -> f32 {
+        self.amplitude
+    }
+
+    pub fn frequency(&self) -> f32 {
+        self.frequency
+    }
+
+    pub(crate) fn displacement(&self, at: Point3<f32>) -> (f32, Vector3<f32>) {
+        let (n, g) = self.noise.evaluate(at * self.frequency);
+
+        (n * self.amplitude, g * (self.amplitude * self.frequency))
+    }
+}
+
+impl<S: Surface> Surface for Displaced<S> {
+    fn sample(&self, at: Point3<f32>) -> f32 {
+        self.surface.sample(at) + self.displacement(at).0
+    }
+
+    fn gradient(&self, at: Point3<f32>) -> Vector3<f32> {
+        self.surface.gradient(at) + self.displacement(at).1
+    }
+
+    fn sample_batch(&self, points: &[Point3<f32>], out: &mut [f32]) {
+        self.surface.sample_batch(points, out);
+
+        for (p, o) in points.iter().zip(out.iter_mut()) {
+            *o += self.displacement(*p).0
+        }
+    }
+
+    fn gradient_batch(&self, points: &[Point3<f32>], out: &mut [Vector3<f32>]) {
+        self.surface.gradient_batch(points, out);
+
+        for (p, o) in points.iter().zip(out.iter_mut()) {
+            *o += self.displacement(*p).1
+        }
+    }
+
+    // The surface only moves by the amplitude, so the search from the hint still finds it
+    fn seed_hint(&self) -> Option<Point3<f32>> {
+        self.surface.seed_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use super::*;
+
+    // Every basis should reach close to both ends of [-1, 1] without going past them
+    #[test]
+    fn bases_span_their_range() {
+        let mut rng = StdRng::seed_from_u64(7);
+
+        for basis in [Basis::Perlin, Basis::Simplex, Basis::Worley] {
+            let noise = Noise::new(basis, Fractal::Single, 3);
+
+            let (mut low, mut high) = (f32::MAX, f32::MIN);
+            for _ in 0..20_000 {
+                let at = Point3::new(
+                    rng.gen_range(-50.0..50.0),
+                    rng.gen_range(-50.0..50.0),
+                    rng.gen_range(-50.0..50.0),
+                );
+                let value = noise.sample(at);
+
+                low = low.min(value);
+                high = high.max(value);
+            }
+
+            assert!((-1.0..=1.0).contains(&low) && (-1.0..=1.0).contains(&high));
+            assert!(
+                low < -0.8 && high > 0.8,
+                "{basis:?} only spans [{low}, {high}]"
+            );
+        }
+    }
+}
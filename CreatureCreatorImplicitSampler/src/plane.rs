@@ -0,0 +1,84 @@
+use std::f32::consts::PI;
+
+use nalgebra::{Matrix3, Matrix4, point, Point2, Point3, vector, Vector3};
+
+#[derive(Debug, Copy, Clone)]
+pub struct Plane {
+    o: Point3<f32>,
+    n: Vector3<f32>,
+    u: Vector3<f32>,
+    v: Vector3<f32>,
+}
+
+impl Plane {
+    pub fn from_origin_normal(o: Point3<f32>, n: Vector3<f32>) -> Self {
+        let n = n.normalize();
+
+        // The axis the normal points along the least, so the cross product can never vanish
+        let mut cardinal = vector![0.0, 0.0, 0.0];
+        cardinal[n.iamin()] = 1.0;
+
+        let u = n.cross(&cardinal).normalize();
+        let v = u.cross(&n).normalize();
+
+        Plane { o, n, u, v }
+    }
+
+    pub fn origin(&self) -> Point3<f32> {
+        self.o
+    }
+
+    pub fn normal(&self) -> Vector3<f32> {
+        self.n
+    }
+
+    // u and v are the plane's 2D axes, both unit length and at right angles to each other and the normal
+    pub fn u(&self) -> Vector3<f32> {
+        self.u
+    }
+
+    pub fn v(&self) -> Vector3<f32> {
+        self.v
+    }
+
+    pub fn from(&self, p: Point2<f32>) -> Point3<f32> {
+        self.o + (self.u * p.x) + (self.v * p.y)
+    }
+
+    // Positive on the side the normal points to
+    pub fn signed_distance(&self, p: Point3<f32>) -> f32 {
+        (p - self.o).dot(&self.n)
+    }
+
+    pub fn reflect_point(&self, p: Point3<f32>) -> Point3<f32> {
+        p - self.n * (2.0 * self.signed_distance(p))
+    }
+
+    pub fn reflect_vector(&self, d: Vector3<f32>) -> Vector3<f32> {
+        d - self.n * (2.0 * d.dot(&self.n))
+    }
+
+    // reflection is reflect_point as a matrix, it's its own inverse
+    pub fn reflection(&self) -> Matrix4<f32> {
+        let n = self.n;
+        let linear = Matrix3::identity() - n * n.transpose() * 2.0;
+        let translation = n * (2.0 * self.o.coords.dot(&n));
+
+        let mut m = linear.to_homogeneous();
+        m.fixed_view_mut::<3, 1>(0, 3).copy_from(&translation);
+
+        m
+    }
+
+    pub fn circle_points(&self, segments: usize, radius: f32) -> Vec<Point3<f32>> {
+        let segment_theta = (2.0 * PI) / (segments as f32);
+
+        (0..segments)
+            .map(|i| {
+                let angle = segment_theta * (i as f32);
+
+                self.from(point![angle.cos(), angle.sin()] * radius)
+            })
+            .collect()
+    }
+}
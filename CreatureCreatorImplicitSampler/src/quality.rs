@@ -0,0 +1,170 @@
+use nalgebra::{Point3, Vector3};
+
+use crate::interval::Aabb;
+use crate::surface::Surface;
+
+// The particle constraint treats F as if it were a distance, so it works best when |∇F| is close to 1 near the surface
+// These help find fields where it isn't, and fix them up
+
+// ZERO_GRADIENT is the gradient magnitude under which a field is considered flat
+pub const ZERO_GRADIENT: f32 = 1e-6;
+
+#[derive(Debug, Clone)]
+pub struct FieldReport {
+    // How many points of the grid were sampled, and how many points on the zero set were found between them
+    pub grid_points: usize,
+    pub surface_points: usize,
+
+    // |∇F| on the zero set, percentiles are [5th, 25th, 50th, 75th, 95th]
+    pub gradient_min: f32,
+    pub gradient_max: f32,
+    pub gradient_mean: f32,
+    pub gradient_percentiles: [f32; 5],
+
+    // The largest change in F per unit distance between neighbouring grid points
+    // This can only underestimate the true Lipschitz constant, it gets closer as the resolution goes up
+    pub lipschitz: f32,
+
+    // Points on the zero set where the gradient vanishes, normals can't be found there
+    pub zero_gradient_points: Vec<Point3<f32>>,
+}
+
+// analyze samples `surface` on a grid over `region` with `resolution` points along each side,
+// then looks at the gradient wherever the zero set passes between grid points
+pub fn analyze<S: Surface + ?Sized>(surface: &S, region: &Aabb, resolution: usize) -> FieldReport {
+    assert!(
+        resolution >= 2,
+        "the grid needs at least 2 points along each side"
+    );
+
+    let n = resolution;
+    let step = region.size() / (n - 1) as f32;
+    let index = |x: usize, y: usize, z: usize| x + n * (y + n * z);
+    let position = |x: usize, y: usize, z: usize| {
+        region.min + step.component_mul(&Vector3::new(x as f32, y as f32, z as f32))
+    };
+
+    let mut points = Vec::with_capacity(n * n * n);
+    for z in 0..n {
+        for y in 0..n {
+            for x in 0..n {
+                points.push(position(x, y, z))
+            }
+        }
+    }
+
+    let mut values = vec![0.0; points.len()];
+    surface.sample_batch(&points, &mut values);
+
+    // Every edge between neighbouring grid points gives a slope, and a point on the zero set if the sign changes
+    let mut lipschitz: f32 = 0.0;
+    let mut crossings = vec![];
+    for z in 0..n {
+        for y in 0..n {
+            for x in 0..n {
+                let i = index(x, y, z);
+
+                let neighbours = [
+                    (x + 1 < n).then(|| index(x + 1, y, z)),
+                    (y + 1 < n).then(|| index(x, y + 1, z)),
+                    (z + 1 < n).then(|| index(x, y, z + 1)),
+                ];
+
+                for (axis, j) in neighbours.into_iter().enumerate() {
+                    let Some(j) = j else { continue };
+                    let (a, b) = (values[i], values[j]);
+
+                    lipschitz = lipschitz.max((a - b).abs() / step[axis]);
+
+                    if (a < 0.0) != (b < 0.0) {
+                        // Linear interpolation along the edge to where F crosses zero
+                        let t = a / (a - b);
+                        crossings.push(points[i] + (points[j] - points[i]) * t);
+                    }
+                }
+            }
+        }
+    }
+
+    let mut gradients = vec![Vector3::zeros(); crossings.len()];
+    surface.gradient_batch(&crossings, &mut gradients);
+
+    let mut magnitudes: Vec<f32> = gradients.iter().map(|g| g.magnitude()).collect();
+    let zero_gradient_points = crossings
+        .iter()
+        .zip(&magnitudes)
+        .filter(|(_, m)| **m <= ZERO_GRADIENT)
+        .map(|(p, _)| *p)
+        .collect();
+
+    magnitudes.sort_unstable_by(f32::total_cmp);
+    let percentile = |p: f32| {
+        if magnitudes.is_empty() {
+            return 0.0;
+        }
+
+        magnitudes[((magnitudes.len() - 1) as f32 * p).round() as usize]
+    };
+
+    FieldReport {
+        grid_points: points.len(),
+        surface_points: crossings.len(),
+
+        gradient_min: magnitudes.first().copied().unwrap_or(0.0),
+        gradient_max: magnitudes.last().copied().unwrap_or(0.0),
+        gradient_mean: magnitudes.iter().sum::<f32>() / magnitudes.len().max(1) as f32,
+        gradient_percentiles: [0.05, 0.25, 0.5, 0.75, 0.95].map(percentile),
+
+        lipschitz,
+
+        zero_gradient_points,
+    }
+}
+
+// Normalized divides a field by its gradient magnitude, F/|∇F|
+// That's a first order estimate of the distance to the zero set, so |∇F| ends up as 1 on the surface
+// The magnitude is clamped to at least ZERO_GRADIENT, so the field stays continuous where the gradient vanishes
+pub struct Normalized<S>(pub S);
+
+impl<S: Surface> Normalized<S> {
+    fn normalize(value: f32, gradient: Vector3<f32>) -> f32 {
+        value / gradient.magnitude().max(ZERO_GRADIENT)
+    }
+
+    fn normalize_gradient(gradient: Vector3<f32>) -> Vector3<f32> {
+        gradient / gradient.magnitude().max(ZERO_GRADIENT)
+    }
+}
+
+impl<S: Surface> Surface for Normalized<S> {
+    fn sample(&self, at: Point3<f32>) -> f32 {
+        Self::normalize(self.0.sample(at), self.0.gradient(at))
+    }
+
+    // The full gradient needs second derivatives, but on the zero set it's exactly the normalized gradient of F
+    fn gradient(&self, at: Point3<f32>) -> Vector3<f32> {
+        Self::normalize_gradient(self.0.gradient(at))
+    }
+
+    fn sample_batch(&self, points: &[Point3<f32>], out: &mut [f32]) {
+        let mut gradients = vec![Vector3::zeros(); points.len()];
+        self.0.sample_batch(points, out);
+        self.0.gradient_batch(points, &mut gradients);
+
+        for (o, g) in out.iter_mut().zip(gradients) {
+            *o = Self::normalize(*o, g)
+        }
+    }
+
+    fn gradient_batch(&self, points: &[Point3<f32>], out: &mut [Vector3<f32>]) {
+        self.0.gradient_batch(points, out);
+
+        for g in out.iter_mut() {
+            *g = Self::normalize_gradient(*g)
+        }
+    }
+
+    fn seed_hint(&self) -> Option<Point3<f32>> {
+        self.0.seed_hint()
+    }
+}
@@ -0,0 +1,430 @@
+use std::collections::{HashMap, VecDeque};
+use std::f32::consts::PI;
+
+use nalgebra::{Point3, Vector3};
+
+use crate::mesh::MeshSurface;
+use crate::spatial_index::kd_indexer::KdIndexer;
+use crate::spatial_index::SpatialIndexer;
+
+// Rebuilds a triangle mesh from oriented samples with the ball pivoting algorithm (Bernardini et al. 1999)
+// A ball is dropped onto three samples to make the first triangle, then rolled over each edge of the mesh until it
+// lands on another sample, and each sample it lands on makes a new triangle. Edges it can't roll over are holes.
+// The ball's size follows the samples' radii, so it copes with the sampler's spacing changing over the surface
+
+// BALL_RADIUS is how big the ball is, relative to the radii of the samples it's rolling over
+// Settled samples end up about 2.5 radii apart, which makes triangles with a circumradius of about 1.5 radii. The
+// ball has to be bigger than any of the triangles, but the bigger it is the more it bridges over small details
+const BALL_RADIUS: f32 = 2.0;
+// EMPTY_TOLERANCE is how far inside a ball a sample can be and still count as on its surface, relative to its size
+// Samples on a lattice often sit four to a ball, none of them should stop the ball being empty
+const EMPTY_TOLERANCE: f32 = 1e-3;
+
+// Reconstruction is the mesh built from the samples, along with where it isn't a closed manifold
+#[derive(Debug, Clone)]
+pub struct Reconstruction {
+    // Vertices are the samples, in the order they were given
+    pub vertices: Vec<Point3<f32>>,
+    pub normals: Vec<Vector3<f32>>,
+    // Counter-clockwise seen from outside, like MeshSurface
+    pub triangles: Vec<[u32; 3]>,
+
+    // Each hole is the loop of vertices around it, in order
+    // A boundary that runs into a non-manifold vertex might not make it all the way round
+    pub holes: Vec<Vec<u32>>,
+    // Edges with more than two triangles, or two triangles facing opposite ways
+    pub non_manifold_edges: Vec<[u32; 2]>,
+    // Vertices where more than one hole meets, so the triangles around them don't make a single fan
+    pub non_manifold_vertices: Vec<u32>,
+
+    // How many samples didn't end up in any triangle
+    pub unused: usize,
+}
+
+impl Reconstruction {
+    pub fn is_closed_manifold(&self) -> bool {
+        self.holes.is_empty() && self.non_manifold_edges.is_empty() && self.non_manifold_vertices.is_empty()
+    }
+
+    // mesh_surface turns the reconstruction into a surface, which works even with holes
+    // None if there aren't any triangles to make it from
+    pub fn mesh_surface(&self) -> Option<MeshSurface> {
+        MeshSurface::is_valid(&self.vertices, &self.triangles)
+            .then(|| MeshSurface::new(self.vertices.clone(), self.triangles.clone()))
+    }
+}
+
+// Where a ball rests on a triangle, kept for each edge so the ball can be rolled over it later
+#[derive(Debug, Copy, Clone)]
+struct Resting {
+    opposite: u32,
+    center: Point3<f32>,
+}
+
+struct Pivoting {
+    positions: Vec<Point3<f32>>,
+    normals: Vec<Vector3<f32>>,
+    radii: Vec<f32>,
+    index: KdIndexer,
+
+    triangles: Vec<[u32; 3]>,
+    // Every directed edge of every triangle, edges only go one way round a triangle so each is in at most one
+    edges: HashMap<(u32, u32), Resting>,
+    // How many edges at each vertex only have a triangle on one side
+    open_edges: Vec<u32>,
+    used: Vec<bool>,
+
+    // Edges the ball still has to be rolled over
+    front: VecDeque<(u32, u32)>,
+}
+
+impl Pivoting {
+    fn new(samples: impl IntoIterator<Item = (Point3<f32>, Vector3<f32>, f32)>) -> Self {
+        let (mut positions, mut normals, mut radii) = (vec![], vec![], vec![]);
+        for (position, normal, radius) in samples {
+            positions.push(position);
+            normals.push(normal);
+            radii.push(radius);
+        }
+
+        let mut index = KdIndexer::new();
+        index.reindex(&positions, (0..positions.len()).collect());
+
+        let count = positions.len();
+        Pivoting {
+            positions,
+            normals,
+            radii,
+            index,
+
+            triangles: vec![],
+            edges: HashMap::new(),
+            open_edges: vec![0; count],
+            used: vec![false; count],
+
+            front: VecDeque::new(),
+        }
+    }
+
+    fn position(&self, i: u32) -> Point3<f32> {
+        self.positions[i as usize]
+    }
+
+    fn ball_radius(&self, vertices: &[u32]) -> f32 {
+        let radius = vertices
+            .iter()
+            .map(|i| self.radii[*i as usize])
+            .fold(0.0, f32::max);
+
+        radius * BALL_RADIUS
+    }
+
+    // ball_center is where a ball of `radius` touching all three corners rests, on the side the triangle faces
+    fn ball_center(&self, [a, b, c]: [u32; 3], radius: f32) -> Option<Point3<f32>> {
+        let (a, b, c) = (self.position(a), self.position(b), self.position(c));
+        let (ab, ac) = (b - a, c - a);
+
+        let normal = ab.cross(&ac);
+        let normal_squared = normal.norm_squared();
+        if normal_squared <= f32::EPSILON * ab.norm_squared() * ac.norm_squared() {
+            // The corners are in a line
+            return None;
+        }
+
+        let to_circumcenter = (ac.norm_squared() * normal.cross(&ab) + ab.norm_squared() * ac.cross(&normal))
+            / (2.0 * normal_squared);
+        let height_squared = radius * radius - to_circumcenter.norm_squared();
+        if height_squared < 0.0 {
+            // The triangle's too big for the ball
+            return None;
+        }
+
+        Some(a + to_circumcenter + normal / normal_squared.sqrt() * height_squared.sqrt())
+    }
+
+    // faces_out is whether the triangle faces the same way as the samples at its corners
+    fn faces_out(&self, [a, b, c]: [u32; 3]) -> bool {
+        let (pa, pb, pc) = (self.position(a), self.position(b), self.position(c));
+        let normal = (pb - pa).cross(&(pc - pa));
+
+        normal.dot(&(self.normals[a as usize] + self.normals[b as usize] + self.normals[c as usize])) > 0.0
+    }
+
+    fn is_empty(&self, center: Point3<f32>, radius: f32, corners: [u32; 3]) -> bool {
+        let mut empty = true;
+        self.index
+            .visit_indices_within(&self.positions, center, radius * (1.0 - EMPTY_TOLERANCE), |i| {
+                if !corners.contains(&(i as u32)) {
+                    empty = false
+                }
+            });
+
+        empty
+    }
+
+    // inner is whether a vertex is already surrounded by triangles, so no more can be added to it
+    fn inner(&self, i: u32) -> bool {
+        self.used[i as usize] && self.open_edges[i as usize] == 0
+    }
+
+    fn add_triangle(&mut self, triangle: [u32; 3], center: Point3<f32>) {
+        let [a, b, c] = triangle;
+
+        for (from, to, opposite) in [(a, b, c), (b, c, a), (c, a, b)] {
+            let previous = self.edges.insert((from, to), Resting { opposite, center });
+            assert!(previous.is_none(), "edges should only be in one triangle each way");
+
+            if self.edges.contains_key(&(to, from)) {
+                // Both sides of the edge have a triangle now
+                self.open_edges[from as usize] -= 1;
+                self.open_edges[to as usize] -= 1;
+            } else {
+                self.open_edges[from as usize] += 1;
+                self.open_edges[to as usize] += 1;
+                self.front.push_back((from, to));
+            }
+
+            self.used[from as usize] = true;
+        }
+
+        self.triangles.push(triangle)
+    }
+
+    // seed looks for a first triangle around `a`, with a ball resting on it that has no other samples inside
+    fn seed(&mut self, a: u32) -> bool {
+        let radius = self.ball_radius(&[a]);
+
+        let mut neighbours = vec![];
+        self.index
+            .visit_indices_within(&self.positions, self.position(a), radius * 2.0, |i| {
+                if i as u32 != a && !self.used[i] {
+                    neighbours.push(i as u32)
+                }
+            });
+        neighbours.sort_by(|i, j| {
+            let distance = |i: &u32| (self.position(*i) - self.position(a)).norm_squared();
+            distance(i).total_cmp(&distance(j))
+        });
+
+        for (n, b) in neighbours.iter().enumerate() {
+            for c in &neighbours[n + 1..] {
+                let mut triangle = [a, *b, *c];
+                if !self.faces_out(triangle) {
+                    triangle = [a, *c, *b];
+                }
+
+                let radius = self.ball_radius(&triangle);
+                let Some(center) = self.ball_center(triangle, radius) else {
+                    continue;
+                };
+
+                if self.is_empty(center, radius, triangle) {
+                    self.add_triangle(triangle, center);
+                    return true;
+                }
+            }
+        }
+
+        false
+    }
+
+    // pivot rolls the ball over the edge from `a` to `b`, away from the triangle it's resting on
+    // Returns the triangle on the other side of the edge, if the ball lands somewhere it can make one
+    fn pivot(&self, a: u32, b: u32) -> Option<([u32; 3], Point3<f32>)> {
+        let resting = self.edges[&(a, b)];
+        let (pa, pb) = (self.position(a), self.position(b));
+
+        let axis = (pb - pa).normalize();
+        let middle = nalgebra::center(&pa, &pb);
+        let from = resting.center - middle;
+
+        // Every ball touching both ends of the edge is centered on a circle around it. Any sample the ball could
+        // land on is within 2 radii of the middle of the edge
+        let radius = self.ball_radius(&[a, b]);
+        let mut landing: Option<(f32, u32, Point3<f32>)> = None;
+        self.index
+            .visit_indices_within(&self.positions, middle, radius * 2.0, |i| {
+                let c = i as u32;
+                if c == a || c == b || c == resting.opposite {
+                    return;
+                }
+
+                // The new triangle goes round the edge the other way
+                let Some(center) = self.ball_center([b, a, c], radius) else {
+                    return;
+                };
+
+                let to = center - middle;
+                let mut angle = axis.dot(&from.cross(&to)).atan2(from.dot(&to));
+                if angle < 0.0 {
+                    angle += 2.0 * PI
+                }
+
+                if landing.is_none_or(|(closest, _, _)| angle < closest) {
+                    landing = Some((angle, c, center))
+                }
+            });
+
+        let (_, c, center) = landing?;
+        let triangle = [b, a, c];
+
+        // The first sample the ball hits is where it lands, if a triangle can't be made there the edge is left open
+        let fits = self.faces_out(triangle)
+            && !self.inner(c)
+            && !self.edges.contains_key(&(a, c))
+            && !self.edges.contains_key(&(c, b));
+
+        fits.then_some((triangle, center))
+    }
+
+    fn run(&mut self) {
+        for a in 0..self.positions.len() as u32 {
+            if self.used[a as usize] || !self.seed(a) {
+                continue;
+            }
+
+            while let Some((a, b)) = self.front.pop_front() {
+                // The edge might have been closed up from the other side since it went on the front
+                if self.edges.contains_key(&(b, a)) {
+                    continue;
+                }
+
+                if let Some((triangle, center)) = self.pivot(a, b) {
+                    self.add_triangle(triangle, center)
+                }
+            }
+        }
+    }
+
+    fn finish(self) -> Reconstruction {
+        let mut non_manifold_edges = vec![];
+        let mut sides: HashMap<(u32, u32), usize> = HashMap::new();
+        for triangle in &self.triangles {
+            for (from, to) in [(0, 1), (1, 2), (2, 0)].map(|(i, j)| (triangle[i], triangle[j])) {
+                *sides.entry((from.min(to), from.max(to))).or_default() += 1;
+            }
+        }
+
+        for (&(from, to), &count) in &sides {
+            // Two triangles facing opposite ways would share the edge going the same way round
+            let opposed = count == 2 && !(self.edges.contains_key(&(from, to)) && self.edges.contains_key(&(to, from)));
+            if count > 2 || opposed {
+                non_manifold_edges.push([from, to])
+            }
+        }
+
+        // The open edges are followed round into loops
+        let mut open: HashMap<u32, Vec<u32>> = HashMap::new();
+        for &(from, to) in self.edges.keys() {
+            if !self.edges.contains_key(&(to, from)) {
+                open.entry(from).or_default().push(to)
+            }
+        }
+
+        let mut non_manifold_vertices: Vec<u32> = open
+            .iter()
+            .filter(|(_, next)| next.len() > 1)
+            .map(|(vertex, _)| *vertex)
+            .collect();
+        non_manifold_vertices.sort_unstable();
+
+        let mut holes = vec![];
+        let mut starts: Vec<u32> = open.keys().copied().collect();
+        starts.sort_unstable();
+        for start in starts {
+            let mut hole = vec![];
+            let mut at = start;
+
+            while let Some(next) = open.get_mut(&at).and_then(Vec::pop) {
+                hole.push(at);
+                at = next;
+
+                if at == start {
+                    break;
+                }
+            }
+
+            if !hole.is_empty() {
+                holes.push(hole)
+            }
+        }
+
+        let unused = self.used.iter().filter(|used| !**used).count();
+
+        Reconstruction {
+            vertices: self.positions,
+            normals: self.normals,
+            triangles: self.triangles,
+
+            holes,
+            non_manifold_edges,
+            non_manifold_vertices,
+
+            unused,
+        }
+    }
+}
+
+// reconstruct builds a mesh from samples, like the ones from ImplicitSampler::samples
+pub fn reconstruct(samples: impl IntoIterator<Item = (Point3<f32>, Vector3<f32>, f32)>) -> Reconstruction {
+    let mut pivoting = Pivoting::new(samples);
+    pivoting.run();
+    pivoting.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::shapes::Sphere;
+    use crate::ImplicitSampler;
+
+    // sphere_samples are the samples from a unit sphere, once they've settled
+    fn sphere_samples() -> Vec<(Point3<f32>, Vector3<f32>, f32)> {
+        let mut sampler = ImplicitSampler::<10000>::new();
+        while sampler.t < 3.0 {
+            sampler.update(0.25, &Sphere::new(1.0))
+        }
+
+        sampler.samples().collect()
+    }
+
+    // euler_characteristic counts only the vertices that are in a triangle
+    fn euler_characteristic(reconstruction: &Reconstruction) -> isize {
+        let vertices: HashSet<u32> = reconstruction.triangles.iter().flatten().copied().collect();
+        let edges: HashSet<[u32; 2]> = reconstruction
+            .triangles
+            .iter()
+            .flat_map(|&[a, b, c]| [[a, b], [b, c], [c, a]])
+            .map(|[a, b]| [a.min(b), a.max(b)])
+            .collect();
+
+        vertices.len() as isize - edges.len() as isize + reconstruction.triangles.len() as isize
+    }
+
+    #[test]
+    fn sphere_is_closed() {
+        let reconstruction = reconstruct(sphere_samples());
+
+        assert!(reconstruction.is_closed_manifold(), "{:?}", reconstruction.holes);
+        assert_eq!(euler_characteristic(&reconstruction), 2);
+        assert!(reconstruction.mesh_surface().is_some());
+    }
+
+    #[test]
+    fn cut_sphere_has_one_hole() {
+        // Taking the cap off leaves a single hole where it was
+        let samples = sphere_samples().into_iter().filter(|(p, _, _)| p.z < 0.7);
+        let reconstruction = reconstruct(samples);
+
+        assert_eq!(reconstruction.holes.len(), 1);
+        assert!(reconstruction.non_manifold_edges.is_empty());
+        assert_eq!(euler_characteristic(&reconstruction), 1);
+    }
+
+    #[test]
+    fn nothing_has_no_mesh_surface() {
+        assert!(reconstruct([]).mesh_surface().is_none());
+    }
+}
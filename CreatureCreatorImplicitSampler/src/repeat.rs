@@ -0,0 +1,348 @@
+use std::f32::consts::PI;
+
+use nalgebra::{Point3, Rotation3, Unit, UnitQuaternion, Vector3};
+use serde::{Deserialize, Serialize};
+
+use crate::surface::Surface;
+
+// Repeat places `count` copies of a surface in a row, around an axis, or along a curve
+// Only the instance closest to a point and its two neighbours are ever evaluated, so the cost doesn't grow with count
+// That relies on instances not reaching further than their neighbours, which holds as long as they're spaced
+// at least as far apart as they are big
+
+// Layout is where the instances go. Each instance is the surface moved so that its origin lands on the instance,
+// so the surface should be built around its own origin
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Layout {
+    // Instances `spacing` apart, starting at the origin and moving along `direction`
+    Linear {
+        direction: Vector3<f32>,
+        spacing: f32,
+    },
+    // Instances rotated by `angle` radians from each other around `axis`, which passes through the origin
+    // Instance 0 is at `start`, which should be off the axis
+    Radial {
+        axis: Vector3<f32>,
+        start: Vector3<f32>,
+        angle: f32,
+    },
+    // Instances `spacing` apart along a polyline, with their x axis along it
+    // The surface's origin goes onto the curve, past the end the curve carries on in a straight line
+    Curve {
+        points: Vec<Point3<f32>>,
+        spacing: f32,
+    },
+}
+
+impl Layout {
+    // is_valid is whether instances can be placed along the layout
+    // Directions have to be normalized and steps divided by, so none of them can be zero
+    pub fn is_valid(&self) -> bool {
+        let finite = |v: &Vector3<f32>| v.iter().all(|c| c.is_finite());
+
+        match self {
+            Layout::Linear { direction, spacing } => {
+                finite(direction) && direction.norm() > 0.0 && *spacing > 0.0 && spacing.is_finite()
+            }
+            Layout::Radial { axis, start, angle } => {
+                finite(axis)
+                    && finite(start)
+                    && axis.norm() > 0.0
+                    && start.cross(axis).norm() > 0.0
+                    && *angle != 0.0
+                    && angle.is_finite()
+            }
+            Layout::Curve { points, spacing } => {
+                *spacing > 0.0
+                    && spacing.is_finite()
+                    && points.iter().all(|p| finite(&p.coords))
+                    && points.len() >= 2
+                    && points
+                        .windows(2)
+                        .all(|pair| (pair[1] - pair[0]).magnitude() > 0.0)
+            }
+        }
+    }
+}
+
+// Curve is a polyline prepared for finding instances along it
+struct Curve {
+    points: Vec<Point3<f32>>,
+    // Arc length at the start of each point
+    lengths: Vec<f32>,
+    // Orientation of each segment, parallel transported from the first so the instances don't twist
+    frames: Vec<UnitQuaternion<f32>>,
+}
+
+impl Curve {
+    fn new(points: &[Point3<f32>]) -> Self {
+        assert!(points.len() >= 2, "curve should have at least 2 points");
+
+        let mut lengths = vec![0.0];
+        for pair in points.windows(2) {
+            let length = (pair[1] - pair[0]).magnitude();
+            assert!(length > 0.0, "curve shouldn't have repeated points");
+
+            lengths.push(lengths.last().unwrap() + length)
+        }
+
+        // The first frame turns x onto the first segment, every one after only turns as much as the curve does
+        let mut frames: Vec<UnitQuaternion<f32>> = vec![];
+        let mut tangent = Vector3::x();
+        for pair in points.windows(2) {
+            let next = (pair[1] - pair[0]).normalize();
+            let turn = UnitQuaternion::rotation_between(&tangent, &next).unwrap_or_else(|| {
+                // Turning all the way around, any perpendicular axis works
+                let mut cardinal = Vector3::zeros();
+                cardinal[tangent.iamin()] = 1.0;
+
+                UnitQuaternion::from_axis_angle(&Unit::new_normalize(tangent.cross(&cardinal)), PI)
+            });
+
+            frames.push(turn * frames.last().copied().unwrap_or_default());
+            tangent = next;
+        }
+
+        Curve {
+            points: points.to_vec(),
+            lengths,
+            frames,
+        }
+    }
+
+    fn length(&self) -> f32 {
+        *self.lengths.last().unwrap()
+    }
+
+    // at returns the point and frame at arc length `s`
+    fn at(&self, s: f32) -> (Point3<f32>, UnitQuaternion<f32>) {
+        let segment = self
+            .lengths
+            .partition_point(|l| *l <= s)
+            .clamp(1, self.points.len() - 1)
+            - 1;
+
+        let (start, end) = (self.points[segment], self.points[segment + 1]);
+        let direction = (end - start).normalize();
+
+        (
+            start + direction * (s - self.lengths[segment]),
+            self.frames[segment],
+        )
+    }
+
+    // closest returns the arc length of the closest point on the curve
+    fn closest(&self, p: Point3<f32>) -> f32 {
+        let mut best = (f32::MAX, 0.0);
+
+        for (i, pair) in self.points.windows(2).enumerate() {
+            let axis = pair[1] - pair[0];
+            let t = ((p - pair[0]).dot(&axis) / axis.norm_squared()).clamp(0.0, 1.0);
+            let distance = (pair[0] + axis * t - p).norm_squared();
+
+            if distance < best.0 {
+                best = (distance, self.lengths[i] + t * axis.magnitude())
+            }
+        }
+
+        best.1
+    }
+}
+
+// Placement is the prepared form of a Layout
+enum Placement {
+    Linear {
+        step: Vector3<f32>,
+    },
+    Radial {
+        axis: Unit<Vector3<f32>>,
+        start: Vector3<f32>,
+        angle: f32,
+        // Whether the last instance comes back around next to the first
+        closed: bool,
+    },
+    Curve {
+        curve: Curve,
+        spacing: f32,
+    },
+}
+
+// `scale` is called with each instance's index, it's how variations like tapering are made
+pub struct Repeat<S, F> {
+    surface: S,
+    layout: Layout,
+    placement: Placement,
+    count: usize,
+    scale: F,
+}
+
+impl<S, F: Fn(usize) -> f32> Repeat<S, F> {
+    pub fn new(surface: S, layout: Layout, count: usize, scale: F) -> Self {
+        assert!(count >= 1, "there should be at least one instance");
+        assert!(layout.is_valid(), "layout should be valid");
+
+        let placement = match &layout {
+            Layout::Linear { direction, spacing } => Placement::Linear {
+                step: direction.normalize() * *spacing,
+            },
+            Layout::Radial { axis, start, angle } => Placement::Radial {
+                axis: Unit::new_normalize(*axis),
+                start: *start,
+                angle: *angle,
+                closed: angle.abs() * count as f32 >= 2.0 * PI - 1e-4,
+            },
+            Layout::Curve { points, spacing } => Placement::Curve {
+                curve: Curve::new(points),
+                spacing: *spacing,
+            },
+        };
+
+        Repeat {
+            surface,
+            layout,
+            placement,
+            count,
+            scale,
+        }
+    }
+
+    pub fn surface(&self) -> &S {
+        &self.surface
+    }
+
+    pub fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    // instance returns where instance `i` is and how it's rotated
+    fn instance(&self, i: usize) -> (Point3<f32>, Rotation3<f32>) {
+        match &self.placement {
+            Placement::Linear { step } => (Point3::from(step * i as f32), Rotation3::identity()),
+            Placement::Radial {
+                axis, start, angle, ..
+            } => {
+                let rotation = Rotation3::from_axis_angle(axis, angle * i as f32);
+
+                (Point3::from(rotation * start), rotation)
+            }
+            Placement::Curve { curve, spacing } => {
+                let (position, frame) = curve.at(spacing * i as f32);
+
+                (position, frame.to_rotation_matrix())
+            }
+        }
+    }
+
+    // nearest is the index of the instance closest to `at`, going by where instances are placed
+    fn nearest(&self, at: Point3<f32>) -> isize {
+        let last = self.count as isize - 1;
+
+        match &self.placement {
+            Placement::Linear { step } => {
+                let along = at.coords.dot(step) / step.norm_squared();
+
+                (along.round() as isize).clamp(0, last)
+            }
+            Placement::Radial {
+                axis,
+                start,
+                angle,
+                closed,
+            } => {
+                // The angle of `at` around the axis, measured from instance 0
+                let reference = start - axis.into_inner() * start.dot(axis);
+                let across = axis.cross(&reference);
+                let theta = at.coords.dot(&across).atan2(at.coords.dot(&reference));
+
+                let i = (theta / angle).round() as isize;
+                if *closed {
+                    i.rem_euclid(self.count as isize)
+                } else {
+                    // Going the other way around might land closer to the last instance
+                    let other = ((theta + 2.0 * PI * angle.signum()) / angle).round() as isize;
+                    if (0..=last).contains(&other) && !(0..=last).contains(&i) {
+                        other
+                    } else {
+                        i.clamp(0, last)
+                    }
+                }
+            }
+            Placement::Curve { curve, spacing } => {
+                let s = curve.closest(at);
+
+                // Points off the end of the curve might belong to an instance placed past it
+                let s = if s >= curve.length() {
+                    curve.length() + (at - curve.at(curve.length()).0).magnitude()
+                } else {
+                    s
+                };
+
+                ((s / spacing).round() as isize).clamp(0, last)
+            }
+        }
+    }
+
+    // candidates are the nearest instance and its neighbours, wrapping around for closed radial layouts
+    fn candidates(&self, at: Point3<f32>) -> impl Iterator<Item = usize> {
+        let nearest = self.nearest(at);
+        let count = self.count as isize;
+        let wraps = matches!(self.placement, Placement::Radial { closed: true, .. });
+
+        (nearest - 1..=nearest + 1).filter_map(move |i| {
+            if wraps {
+                Some(i.rem_euclid(count) as usize)
+            } else {
+                (0..count).contains(&i).then_some(i as usize)
+            }
+        })
+    }
+
+    // local takes a point into instance `i`'s space, along with the instance's scale and rotation
+    fn local(&self, i: usize, at: Point3<f32>) -> (Point3<f32>, f32, Rotation3<f32>) {
+        let (position, rotation) = self.instance(i);
+        let scale = (self.scale)(i);
+
+        (
+            Point3::from(rotation.inverse() * (at - position) / scale),
+            scale,
+            rotation,
+        )
+    }
+}
+
+impl<S: Surface, F: Fn(usize) -> f32> Surface for Repeat<S, F> {
+    // Scaling the field along with the points keeps distances in world units
+    fn sample(&self, at: Point3<f32>) -> f32 {
+        self.candidates(at)
+            .map(|i| {
+                let (local, scale, _) = self.local(i, at);
+
+                self.surface.sample(local) * scale
+            })
+            .fold(f32::MAX, f32::min)
+    }
+
+    fn gradient(&self, at: Point3<f32>) -> Vector3<f32> {
+        let (local, _, rotation) = self
+            .candidates(at)
+            .map(|i| self.local(i, at))
+            .min_by(|(a, sa, _), (b, sb, _)| {
+                (self.surface.sample(*a) * sa).total_cmp(&(self.surface.sample(*b) * sb))
+            })
+            .expect("there should always be an instance nearby");
+
+        rotation * self.surface.gradient(local)
+    }
+
+    // The hint is in instance 0's space
+    fn seed_hint(&self) -> Option<Point3<f32>> {
+        let hint = self.surface.seed_hint()?;
+        let (position, rotation) = self.instance(0);
+
+        Some(position + rotation * (hint.coords * (self.scale)(0)))
+    }
+}
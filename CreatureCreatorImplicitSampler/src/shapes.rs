@@ -0,0 +1,250 @@
+use nalgebra::{Matrix4, Point3, Vector3};
+
+use crate::autodiff::{value_and_gradient, Scalar};
+use crate::surface::Surface;
+use crate::tape::{Compile, TapeBuilder, Value};
+
+// Primitives and combinators for building surface trees
+// Each one is a small Surface, so trees can be sampled directly or compiled into a Tape
+
+// smooth_min is generic so the same blend is used for plain samples, derivatives and tapes
+pub fn smooth_min<T: Scalar>(a: T, b: T, k: f32) -> T {
+    // No blending at all is just min, and dividing by k would give NaN
+    if k <= 0.0 {
+        return a.min(b);
+    }
+
+    let h = (T::constant(k) - (a - b).abs()).max(T::constant(0.0));
+
+    a.min(b) - (h * h).scale(0.25 / k)
+}
+
+// smooth_min_gradient is smooth_min along with its gradient, from the values and gradients of both sides
+pub fn smooth_min_gradient(
+    a: (f32, Vector3<f32>),
+    b: (f32, Vector3<f32>),
+    k: f32,
+) -> (f32, Vector3<f32>) {
+    // How much of the gradient comes from `a`, this goes from 1 to 0 across the blend region
+    let h = if k > 0.0 {
+        (k - (a.0 - b.0).abs()).max(0.0) / (2.0 * k)
+    } else {
+        0.0
+    };
+    let weight = if a.0 < b.0 { 1.0 - h } else { h };
+
+    (smooth_min(a.0, b.0, k), a.1 * weight + b.1 * (1.0 - weight))
+}
+
+pub struct Sphere {
+    pub radius: f32,
+}
+
+impl Sphere {
+    pub fn new(radius: f32) -> Self {
+        Sphere { radius }
+    }
+}
+
+impl Surface for Sphere {
+    fn sample(&self, at: Point3<f32>) -> f32 {
+        at.coords.magnitude() - self.radius
+    }
+
+    fn gradient(&self, at: Point3<f32>) -> Vector3<f32> {
+        value_and_gradient(self, at).1
+    }
+}
+
+impl Compile for Sphere {
+    fn compile(&self, b: &mut TapeBuilder, at: [Value; 3]) -> Value {
+        let magnitude = b.length(at);
+
+        b.add_imm(magnitude, -self.radius)
+    }
+}
+
+// Ellipsoid uses the same algebraic field as the ellipsoids drawn by the pipeline
+pub struct Ellipsoid {
+    pub size: Vector3<f32>,
+}
+
+impl Ellipsoid {
+    pub fn new(size: Vector3<f32>) -> Self {
+        Ellipsoid { size }
+    }
+}
+
+impl Surface for Ellipsoid {
+    fn sample(&self, at: Point3<f32>) -> f32 {
+        (at.x * at.x) / (self.size.x * self.size.x)
+            + (at.y * at.y) / (self.size.y * self.size.y)
+            + (at.z * at.z) / (self.size.z * self.size.z)
+            - 1.0
+    }
+
+    fn gradient(&self, at: Point3<f32>) -> Vector3<f32> {
+        value_and_gradient(self, at).1
+    }
+}
+
+impl Compile for Ellipsoid {
+    fn compile(&self, b: &mut TapeBuilder, at: [Value; 3]) -> Value {
+        let s = self.size.component_mul(&self.size);
+
+        b.weighted_squares(at, [1.0 / s.x, 1.0 / s.y, 1.0 / s.z, -1.0])
+    }
+}
+
+// Transformed samples a surface through an affine transform
+// Only the inverse is kept, since that's what takes points into the surface's space
+pub struct Transformed<S> {
+    pub surface: S,
+    pub inverse: Matrix4<f32>,
+}
+
+impl<S> Transformed<S> {
+    pub fn new(surface: S, transform: Matrix4<f32>) -> Self {
+        let inverse = transform
+            .try_inverse()
+            .expect("transform should be invertible");
+
+        Transformed { surface, inverse }
+    }
+
+    pub fn from_inverse(surface: S, inverse: Matrix4<f32>) -> Self {
+        Transformed { surface, inverse }
+    }
+}
+
+impl<S: Surface> Surface for Transformed<S> {
+    fn sample(&self, at: Point3<f32>) -> f32 {
+        self.surface.sample(self.inverse.transform_point(&at))
+    }
+
+    // The gradient is taken in the surface's space and brought back out with the chain rule
+    fn gradient(&self, at: Point3<f32>) -> Vector3<f32> {
+        let inner = self.surface.gradient(self.inverse.transform_point(&at));
+
+        self.inverse.fixed_view::<3, 3>(0, 0).tr_mul(&inner)
+    }
+}
+
+impl<S: Compile> Compile for Transformed<S> {
+    fn compile(&self, b: &mut TapeBuilder, at: [Value; 3]) -> Value {
+        // The transform is assumed to be affine, so the bottom row is ignored
+        let m = &self.inverse;
+        let transformed =
+            [0, 1, 2].map(|row| b.affine(at, [m[(row, 0)], m[(row, 1)], m[(row, 2)], m[(row, 3)]]));
+
+        self.surface.compile(b, transformed)
+    }
+}
+
+pub struct Union<A, B>(pub A, pub B);
+
+impl<A: Surface, B: Surface> Surface for Union<A, B> {
+    fn sample(&self, at: Point3<f32>) -> f32 {
+        self.0.sample(at).min(self.1.sample(at))
+    }
+
+    fn gradient(&self, at: Point3<f32>) -> Vector3<f32> {
+        if self.0.sample(at) <= self.1.sample(at) {
+            self.0.gradient(at)
+        } else {
+            self.1.gradient(at)
+        }
+    }
+}
+
+impl<A: Compile, B: Compile> Compile for Union<A, B> {
+    fn compile(&self, b: &mut TapeBuilder, at: [Value; 3]) -> Value {
+        let (fa, fb) = (self.0.compile(b, at), self.1.compile(b, at));
+
+        b.min(fa, fb)
+    }
+}
+
+pub struct Intersection<A, B>(pub A, pub B);
+
+impl<A: Surface, B: Surface> Surface for Intersection<A, B> {
+    fn sample(&self, at: Point3<f32>) -> f32 {
+        self.0.sample(at).max(self.1.sample(at))
+    }
+
+    fn gradient(&self, at: Point3<f32>) -> Vector3<f32> {
+        if self.0.sample(at) >= self.1.sample(at) {
+            self.0.gradient(at)
+        } else {
+            self.1.gradient(at)
+        }
+    }
+}
+
+impl<A: Compile, B: Compile> Compile for Intersection<A, B> {
+    fn compile(&self, b: &mut TapeBuilder, at: [Value; 3]) -> Value {
+        let (fa, fb) = (self.0.compile(b, at), self.1.compile(b, at));
+
+        b.max(fa, fb)
+    }
+}
+
+// Difference removes the second surface from the first
+pub struct Difference<A, B>(pub A, pub B);
+
+impl<A: Surface, B: Surface> Surface for Difference<A, B> {
+    fn sample(&self, at: Point3<f32>) -> f32 {
+        self.0.sample(at).max(-self.1.sample(at))
+    }
+
+    fn gradient(&self, at: Point3<f32>) -> Vector3<f32> {
+        if self.0.sample(at) >= -self.1.sample(at) {
+            self.0.gradient(at)
+        } else {
+            -self.1.gradient(at)
+        }
+    }
+}
+
+impl<A: Compile, B: Compile> Compile for Difference<A, B> {
+    fn compile(&self, b: &mut TapeBuilder, at: [Value; 3]) -> Value {
+        let (fa, fb) = (self.0.compile(b, at), self.1.compile(b, at));
+        let nfb = b.neg(fb);
+
+        b.max(fa, nfb)
+    }
+}
+
+// SmoothUnion blends two surfaces together, k controls the size of the blend region
+pub struct SmoothUnion<A, B> {
+    pub a: A,
+    pub b: B,
+    pub k: f32,
+}
+
+impl<A, B> SmoothUnion<A, B> {
+    pub fn new(a: A, b: B, k: f32) -> Self {
+        SmoothUnion { a, b, k }
+    }
+}
+
+impl<A: Surface, B: Surface> Surface for SmoothUnion<A, B> {
+    fn sample(&self, at: Point3<f32>) -> f32 {
+        smooth_min(self.a.sample(at), self.b.sample(at), self.k)
+    }
+
+    fn gradient(&self, at: Point3<f32>) -> Vector3<f32> {
+        let a = (self.a.sample(at), self.a.gradient(at));
+        let b = (self.b.sample(at), self.b.gradient(at));
+
+        smooth_min_gradient(a, b, self.k).1
+    }
+}
+
+impl<A: Compile, B: Compile> Compile for SmoothUnion<A, B> {
+    fn compile(&self, b: &mut TapeBuilder, at: [Value; 3]) -> Value {
+        let (fa, fb) = (self.a.compile(b, at), self.b.compile(b, at));
+
+        b.smooth_min(fa, fb, self.k)
+    }
+}
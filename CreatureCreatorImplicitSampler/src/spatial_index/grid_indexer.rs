@@ -0,0 +1,306 @@
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::hash::{BuildHasherDefault, Hasher};
+
+use nalgebra::{Point3, Vector3};
+
+use crate::spatial_index::{NearestQueue, Positioned, SpatialIndexer};
+
+// DEFAULT_CELL_SIZE is used until there have been queries to size the cells from
+const DEFAULT_CELL_SIZE: f32 = 0.1;
+// CELL_QUERY_RATIO is how big cells are made compared to the average query radius
+// Cells twice the radius mean a query only ever touches two cells along each axis, which was quickest in testing,
+// smaller cells check fewer items but the extra lookups cost more than that saves
+const CELL_QUERY_RATIO: f32 = 2.0;
+
+type CellKey = [i32; 3];
+// Positions are kept next to the indices as they were when indexed, so queries don't have to jump around the items
+// to check distances
+type Cells = HashMap<CellKey, Vec<(usize, Point3<f32>)>, BuildHasherDefault<CellHasher>>;
+
+// CellHasher is a multiplicative hash, the default hasher is built to resist attacks and is far slower than
+// this needs. Every query hashes dozens of cells, so it adds up
+#[derive(Default)]
+struct CellHasher(u64);
+
+impl Hasher for CellHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.write_u64(*b as u64)
+        }
+    }
+
+    fn write_i32(&mut self, i: i32) {
+        self.write_u64(i as u32 as u64)
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        self.0 = (self.0.rotate_left(5) ^ i).wrapping_mul(0x51_7c_c1_b7_27_22_0a_95)
+    }
+}
+
+// GridIndexer hashes items into a uniform grid of cubes
+// Queries only look at the cells their sphere overlaps, so it works best when the cells are sized to the queries.
+// The sampler's queries all scale with the particle radius, so by default the cells are resized on every reindex
+// to fit the queries made since the last one
+pub struct GridIndexer {
+    cell_size: f32,
+    // Whether the cell size was given, instead of following the queries
+    fixed: bool,
+
+    cells: Cells,
+
+    queries: Cell<QueryStats>,
+    // How many indices a query is expected to find, so the vec can be allocated once
+    expected_found: usize,
+}
+
+// QueryStats is kept on every query since the last reindex
+#[derive(Debug, Default, Copy, Clone)]
+struct QueryStats {
+    radius_sum: f32,
+    radius_count: usize,
+    found_sum: usize,
+    found_count: usize,
+}
+
+impl Default for GridIndexer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl GridIndexer {
+    pub fn new() -> Self {
+        GridIndexer {
+            cell_size: DEFAULT_CELL_SIZE,
+            fixed: false,
+            cells: HashMap::default(),
+            queries: Cell::new(QueryStats::default()),
+            expected_found: 0,
+        }
+    }
+
+    // with_cell_size keeps the cells at `cell_size`, for when the query radius is known up front
+    // Twice the radius is a good size
+    pub fn with_cell_size(cell_size: f32) -> Self {
+        assert!(cell_size > 0.0, "cells should have a positive size");
+
+        GridIndexer {
+            cell_size,
+            fixed: true,
+            ..Self::new()
+        }
+    }
+
+    pub fn cell_size(&self) -> f32 {
+        self.cell_size
+    }
+
+    fn key(&self, p: Point3<f32>) -> CellKey {
+        (p.coords / self.cell_size).map(|c| c.floor() as i32).into()
+    }
+
+    fn record_query(&self, radius: f32, found: Option<usize>) {
+        let mut stats = self.queries.get();
+
+        stats.radius_sum += radius;
+        stats.radius_count += 1;
+
+        if let Some(found) = found {
+            stats.found_sum += found;
+            stats.found_count += 1;
+        }
+
+        self.queries.set(stats)
+    }
+
+    // visit calls `f` with every cell that overlaps the cube around `origin`, stopping early if it returns true
+    fn visit(
+        &self,
+        origin: Point3<f32>,
+        radius: f32,
+        mut f: impl FnMut(&[(usize, Point3<f32>)]) -> bool,
+    ) -> bool {
+        let (min, max) = (
+            self.key(origin - Vector3::repeat(radius)),
+            self.key(origin + Vector3::repeat(radius)),
+        );
+
+        let span = |axis: usize| (max[axis] as i64 - min[axis] as i64 + 1) as usize;
+        let cell_count = span(0).saturating_mul(span(1)).saturating_mul(span(2));
+
+        // A query much bigger than the cells covers more cells than there are, it's quicker to look at all of them
+        if cell_count > self.cells.len() {
+            return self.cells.values().any(|items| f(items));
+        }
+
+        for x in min[0]..=max[0] {
+            for y in min[1]..=max[1] {
+                for z in min[2]..=max[2] {
+                    if let Some(items) = self.cells.get(&[x, y, z]) {
+                        if f(items) {
+                            return true;
+                        }
+                    }
+                }
+            }
+        }
+
+        false
+    }
+}
+
+impl<P: Positioned> SpatialIndexer<P> for GridIndexer {
+    fn reindex(&mut self, items: &[P], indices: Vec<usize>) {
+        let stats = self.queries.take();
+        if !self.fixed && stats.radius_count > 0 && stats.radius_sum > 0.0 {
+            self.cell_size = CELL_QUERY_RATIO * stats.radius_sum / stats.radius_count as f32;
+        }
+        if let Some(expected) = stats.found_sum.checked_div(stats.found_count) {
+            self.expected_found = expected;
+        }
+
+        // Cells that are still occupied after the rebuild keep their vecs, so rebuilding every iteration doesn't
+        // reallocate them all
+        for items in self.cells.values_mut() {
+            items.clear()
+        }
+
+        for index in indices {
+            let position = items[index].position();
+            self.cells
+                .entry(self.key(position))
+                .or_default()
+                .push((index, position))
+        }
+
+        self.cells.retain(|_, items| !items.is_empty());
+    }
+
+    fn insert_item_index(&mut self, items: &[P], index: usize) {
+        let position = items[index].position();
+        self.cells
+            .entry(self.key(position))
+            .or_default()
+            .push((index, position))
+    }
+
+    fn remove_item_index(&mut self, items: &[P], index: usize) {
+        // The item has to be where it was when it was indexed, same as any other indexer
+        let key = self.key(items[index].position());
+        let cell = self.cells.get_mut(&key).expect("item should be indexed");
+
+        let i = cell
+            .iter()
+            .position(|(i, _)| *i == index)
+            .expect("item should be indexed");
+        cell.swap_remove(i);
+
+        if cell.is_empty() {
+            self.cells.remove(&key);
+        }
+    }
+
+    fn get_indices_within(&self, _items: &[P], origin: Point3<f32>, radius: f32) -> Vec<usize> {
+        let mut found = Vec::with_capacity(self.expected_found * 2);
+        self.visit(origin, radius, |cell| {
+            found.extend(
+                cell.iter()
+                    .filter(|(_, p)| (p - origin).magnitude() <= radius)
+                    .map(|(i, _)| *i),
+            );
+
+            false
+        });
+
+        self.record_query(radius, Some(found.len()));
+
+        found
+    }
+
+    fn any_indices_within(&self, _items: &[P], origin: Point3<f32>, radius: f32) -> bool {
+        self.record_query(radius, None);
+
+        self.visit(origin, radius, |cell| {
+            cell.iter().any(|(_, p)| (p - origin).magnitude() <= radius)
+        })
+    }
+
+    fn visit_indices_within(
+        &self,
+        _items: &[P],
+        origin: Point3<f32>,
+        radius: f32,
+        mut f: impl FnMut(usize),
+    ) {
+        self.record_query(radius, None);
+
+        self.visit(origin, radius, |cell| {
+            for (i, p) in cell {
+                if (p - origin).magnitude() <= radius {
+                    f(*i)
+                }
+            }
+
+            false
+        });
+    }
+
+    // k_nearest looks through shells of cells around the one `origin` is in, until the next shell is too far away
+    // to hold anything closer than what's been found
+    fn k_nearest(&self, _items: &[P], origin: Point3<f32>, k: usize) -> Vec<(usize, f32)> {
+        let mut nearest = NearestQueue::new(k);
+        let center = self.key(origin);
+
+        let offer = |nearest: &mut NearestQueue, cell: &[(usize, Point3<f32>)]| {
+            for (i, p) in cell {
+                nearest.offer(*i, (p - origin).magnitude())
+            }
+        };
+
+        let (mut looked, mut seen) = (0, 0);
+        for shell in 0i32.. {
+            // Every cell in the shell is at least this far from `origin`
+            let closest = (shell - 1).max(0) as f32 * self.cell_size;
+            if seen == self.cells.len() || closest >= nearest.bound() {
+                break;
+            }
+
+            // Far from everything the shells have more cells than there are, like in visit it's quicker to look at
+            // all of them
+            if looked > self.cells.len() {
+                nearest = NearestQueue::new(k);
+                for cell in self.cells.values() {
+                    offer(&mut nearest, cell)
+                }
+
+                break;
+            }
+
+            for x in -shell..=shell {
+                for y in -shell..=shell {
+                    // Only the faces of the shell, the inside was covered by the smaller shells
+                    let on_face = x.abs() == shell || y.abs() == shell;
+                    let step = if on_face { 1 } else { 2 * shell as usize };
+
+                    for z in (-shell..=shell).step_by(step) {
+                        looked += 1;
+
+                        let key = [center[0] + x, center[1] + y, center[2] + z];
+                        if let Some(cell) = self.cells.get(&key) {
+                            seen += 1;
+                            offer(&mut nearest, cell)
+                        }
+                    }
+                }
+            }
+        }
+
+        nearest.into_sorted()
+    }
+}
@@ -5,10 +5,20 @@ use std::ops::Index;
 
 use nalgebra::Point3;
 
-use crate::spatial_index::{Positioned, SpatialIndexer};
+use crate::spatial_index::{NearestQueue, Positioned, SpatialIndexer};
 
 // KD_LEAF_SIZE controls the max size of leaf nodes. 100 was chosen after some testing
 const KD_LEAF_SIZE: usize = 100;
+// KD_REBALANCE_RATIO is the share of a node's items one side can hold before the node is rebuilt
+// Splits are at the median, so a fresh node is half and half unless lots of items are level with each other
+const KD_REBALANCE_RATIO: f32 = 0.8;
+// KD_REBALANCE_CHANGES is the share of a node's items that have to be added, removed or moved across it before it
+// can be rebuilt again. Nodes that can't be balanced, like when most items sit on one plane, would otherwise be
+// rebuilt on every change
+const KD_REBALANCE_CHANGES: f32 = 0.25;
+// KD_REBALANCE_MIN is how many items a node needs before it's worth rebalancing, smaller ones are cheap to search
+// however lopsided they are
+const KD_REBALANCE_MIN: usize = KD_LEAF_SIZE * 4;
 
 #[derive(Debug, Copy, Clone)]
 enum SplitAxis {
@@ -35,9 +45,14 @@ impl SplitAxis {
     }
 }
 
+// Leaves keep each item's position as it was when indexed, next to its index. It saves looking the item up in
+// queries, and means the tree can always be rebuilt or rearranged without the items having to be where the tree
+// thinks they are
+type KdEntry = (usize, Point3<f32>);
+
 #[derive(Debug)]
 enum KdTree {
-    Leaf(Vec<usize>),
+    Leaf(Vec<KdEntry>),
     Node(KdNode),
 }
 
@@ -45,140 +60,266 @@ enum KdTree {
 struct KdNode {
     axis: SplitAxis,
     midpoint: f32,
+    // How many items are under this node
+    count: usize,
+    // How many items have gone in, out or across this node since it was built
+    changes: usize,
 
     right: Box<KdTree>,
     left: Box<KdTree>,
 }
 
-fn _construct<T: Positioned + Debug + Sync>(
-    item_arena: &[T],
-    items: Vec<usize>,
-    axis: SplitAxis,
-) -> KdTree {
-    if items.len() < KD_LEAF_SIZE {
-        return KdTree::Leaf(items);
+impl KdTree {
+    fn count(&self) -> usize {
+        match self {
+            KdTree::Leaf(l) => l.len(),
+            KdTree::Node(n) => n.count,
+        }
+    }
+}
+
+impl KdNode {
+    fn side(&mut self, position: &Point3<f32>) -> &mut KdTree {
+        if self.axis.component(position) > self.midpoint {
+            self.right.as_mut()
+        } else {
+            self.left.as_mut()
+        }
+    }
+
+    // unbalanced is whether one side has so much more than the other that the node should be rebuilt
+    // Items drift away from where the midpoints were put, and leaves on the crowded side end up splitting again and
+    // again, making the tree deeper than it needs to be
+    fn unbalanced(&self) -> bool {
+        self.count >= KD_REBALANCE_MIN
+            && self.changes as f32 >= self.count as f32 * KD_REBALANCE_CHANGES
+            && self.left.count().max(self.right.count()) as f32 > self.count as f32 * KD_REBALANCE_RATIO
+    }
+}
+
+fn _construct(entries: Vec<KdEntry>, axis: SplitAxis) -> KdTree {
+    if entries.len() < KD_LEAF_SIZE {
+        return KdTree::Leaf(entries);
     }
 
-    let (midpoint, left, right) = _split(item_arena, items, axis);
+    let count = entries.len();
+    let (axis, (midpoint, left, right)) = match _split_any(entries, axis) {
+        Ok(split) => split,
+        Err(entries) => return KdTree::Leaf(entries),
+    };
 
     let (left_node, right_node) = (
-        _construct(item_arena, left, axis.next()),
-        _construct(item_arena, right, axis.next()),
+        _construct(left, axis.next()),
+        _construct(right, axis.next()),
     );
 
     KdTree::Node(KdNode {
         axis,
         midpoint,
+        count,
+        changes: 0,
         right: Box::new(right_node),
         left: Box::new(left_node),
     })
 }
 
-fn _split<T: Positioned + Debug>(
-    item_arena: &[T],
-    mut items: Vec<usize>,
+// Split is a midpoint with the entries on either side of it
+type Split = (f32, Vec<KdEntry>, Vec<KdEntry>);
+
+// _split gives the entries back if they can't be split, because they're all in the same place along the axis
+// Splitting at the median leaves half on each side, however far out some of the items are
+fn _split(
+    mut entries: Vec<KdEntry>,
     axis: SplitAxis,
-) -> (f32, Vec<usize>, Vec<usize>) {
-    // TODO: This could probably be fast using select and split_off, but construction isn't a bottleneck
-    let item_count = items.len();
-    let midpoint = {
-        let mut total = 0.0;
-        for idx in &mut *items {
-            total += axis.component(&item_arena[*idx].position())
-        }
+) -> Result<Split, Vec<KdEntry>> {
+    let median = (entries.len() - 1) / 2;
+    entries.select_nth_unstable_by(median, |(_, a), (_, b)| {
+        axis.component(a).total_cmp(&axis.component(b))
+    });
+    let mut midpoint = axis.component(&entries[median].1);
+
+    // Items level with the midpoint go left, so if nothing's past it the split moves back to the next one down
+    if !entries
+        .iter()
+        .any(|(_, position)| axis.component(position) > midpoint)
+    {
+        midpoint = match entries
+            .iter()
+            .map(|(_, position)| axis.component(position))
+            .filter(|c| *c < midpoint)
+            .max_by(f32::total_cmp)
+        {
+            Some(below) => below,
+            None => return Err(entries),
+        };
+    }
 
-        total / (item_count as f32)
-    };
+    // Everything that isn't past the midpoint goes left, including NaN, same as `side`
+    let (right, left) = entries
+        .into_iter()
+        .partition(|(_, position)| axis.component(position) > midpoint);
 
-    let mut left = vec![];
-    let mut right = vec![];
+    Ok((midpoint, left, right))
+}
 
-    for idx in items.drain(..) {
-        if axis.component(&item_arena[idx].position()) > midpoint {
-            right.push(idx)
-        } else {
-            left.push(idx)
+// _split_any tries the other axes if the entries are all level along `axis`, like a flat patch of surface would be
+fn _split_any(
+    mut entries: Vec<KdEntry>,
+    axis: SplitAxis,
+) -> Result<(SplitAxis, Split), Vec<KdEntry>> {
+    for axis in [axis, axis.next(), axis.next().next()] {
+        match _split(entries, axis) {
+            Ok(split) => return Ok((axis, split)),
+            Err(unsplit) => entries = unsplit,
         }
     }
 
-    (midpoint, left, right)
+    Err(entries)
 }
 
-fn _insert_item_index<T: Positioned + Debug>(
-    item_arena: &[T],
-    tree: &mut KdTree,
-    parent_axis: SplitAxis,
-    index: usize,
-) {
+fn _collect(tree: KdTree, entries: &mut Vec<KdEntry>) {
+    match tree {
+        KdTree::Leaf(l) => entries.extend(l),
+        KdTree::Node(n) => {
+            _collect(*n.left, entries);
+            _collect(*n.right, entries);
+        }
+    }
+}
+
+// _rebuild constructs a tree again from everything under it
+fn _rebuild(tree: &mut KdTree, axis: SplitAxis) {
+    let mut entries = Vec::with_capacity(tree.count());
+    _collect(std::mem::replace(tree, KdTree::Leaf(vec![])), &mut entries);
+
+    *tree = _construct(entries, axis)
+}
+
+// _tidy is called on each node on the way back up from an insert or remove
+fn _tidy(tree: &mut KdTree) {
+    let KdTree::Node(n) = tree else {
+        return;
+    };
+
+    // Few enough items go back into a single leaf, which is also what gets rid of empty leaves
+    if n.count < KD_LEAF_SIZE / 2 || n.unbalanced() {
+        let axis = n.axis;
+        _rebuild(tree, axis)
+    }
+}
+
+fn _insert_entry(tree: &mut KdTree, parent_axis: SplitAxis, entry: KdEntry) {
     match tree {
         KdTree::Leaf(l) => {
-            l.push(index);
+            l.push(entry);
 
             let leaf_size = l.len();
             if leaf_size >= KD_LEAF_SIZE {
                 // This leaf has gotten too large, we must split it into a node
 
-                let axis = parent_axis.next();
-                let (midpoint, left, right) = _split(item_arena, l.clone(), axis);
-
-                *tree = KdTree::Node(KdNode {
-                    axis,
-                    midpoint,
-                    right: Box::new(KdTree::Leaf(right)),
-                    left: Box::new(KdTree::Leaf(left)),
-                })
+                match _split_any(std::mem::take(l), parent_axis.next()) {
+                    Ok((axis, (midpoint, left, right))) => {
+                        *tree = KdTree::Node(KdNode {
+                            axis,
+                            midpoint,
+                            count: leaf_size,
+                            changes: 0,
+                            right: Box::new(KdTree::Leaf(right)),
+                            left: Box::new(KdTree::Leaf(left)),
+                        })
+                    }
+                    // Everything's in the same place, it stays a leaf until it can be split
+                    Err(entries) => *l = entries,
+                }
             }
         }
         KdTree::Node(n) => {
+            n.count += 1;
+            n.changes += 1;
+
             // Point needs to be inserted into one side
-            if n.axis.component(&item_arena[index].position()) > n.midpoint {
-                _insert_item_index(item_arena, n.right.as_mut(), n.axis, index);
-            } else {
-                _insert_item_index(item_arena, n.left.as_mut(), n.axis, index);
-            }
+            let axis = n.axis;
+            _insert_entry(n.side(&entry.1), axis, entry);
+
+            _tidy(tree)
         }
     }
 }
-
-fn _remove_item_index<T: Positioned + Debug>(item_arena: &[T], tree: &mut KdTree, index: usize) {
+// _remove_entry removes `index`, which has to be at `position`, where it was last indexed
+fn _remove_entry(tree: &mut KdTree, index: usize, position: Point3<f32>) {
     match tree {
         KdTree::Leaf(l) => {
-            // Find the index of the index
-            // TODO: If the index was sorted, we could use a binary_search
-            let mut index_index: isize = -1;
-            for (i, value) in l.iter().enumerate() {
-                if *value == index {
-                    index_index = i as isize;
-                }
-            }
+            let i = l
+                .iter()
+                .position(|(i, _)| *i == index)
+                .expect("item should be indexed where it is");
 
-            l.remove(index_index as usize);
+            l.swap_remove(i);
+        }
+        KdTree::Node(n) => {
+            n.count -= 1;
+            n.changes += 1;
 
-            if l.is_empty() {
-                panic!("handle empty leaf!")
-            }
+            _remove_entry(n.side(&position), index, position);
+
+            _tidy(tree)
+        }
+    }
+}
+
+// _move_entry follows both positions down the tree, the item only has to be taken out and put back in below the
+// node where they go different ways. Most moves are small enough to stay in the same leaf
+fn _move_entry(tree: &mut KdTree, index: usize, old: Point3<f32>, new: Point3<f32>) {
+    match tree {
+        KdTree::Leaf(l) => {
+            let entry = l
+                .iter_mut()
+                .find(|(i, _)| *i == index)
+                .expect("item should be indexed where it was");
+
+            entry.1 = new
         }
         KdTree::Node(n) => {
-            // Point needs to be inserted into one side
-            if n.axis.component(&item_arena[index].position()) > n.midpoint {
-                _remove_item_index(item_arena, n.right.as_mut(), index);
-            } else {
-                _remove_item_index(item_arena, n.left.as_mut(), index);
+            let (old_right, new_right) = (
+                n.axis.component(&old) > n.midpoint,
+                n.axis.component(&new) > n.midpoint,
+            );
+
+            if old_right == new_right {
+                return _move_entry(n.side(&new), index, old, new);
             }
+
+            // The node's count doesn't change, only which side has the item
+            n.changes += 1;
+            let axis = n.axis;
+            _remove_entry(n.side(&old), index, old);
+            _insert_entry(n.side(&new), axis, (index, new));
+
+            _tidy(tree)
         }
     }
 }
 
-fn _any_indices_within<T: Positioned + Debug>(
-    item_arena: &[T],
-    tree: &KdTree,
-    origin: Point3<f32>,
-    radius: f32,
-) -> bool {
+// _remap_entry follows the position down to the item's leaf and changes its index there, nothing else has to change
+fn _remap_entry(tree: &mut KdTree, old: usize, new: usize, position: Point3<f32>) {
+    match tree {
+        KdTree::Leaf(l) => {
+            let entry = l
+                .iter_mut()
+                .find(|(i, _)| *i == old)
+                .expect("item should be indexed where it is");
+
+            entry.0 = new
+        }
+        KdTree::Node(n) => _remap_entry(n.side(&position), old, new, position),
+    }
+}
+
+fn _any_indices_within(tree: &KdTree, origin: Point3<f32>, radius: f32) -> bool {
     match tree {
         KdTree::Leaf(l) => {
-            for point in l {
-                if (item_arena[*point].position() - origin).magnitude() <= radius {
+            for (_, position) in l {
+                if (position - origin).magnitude() <= radius {
                     return true;
                 }
             }
@@ -189,36 +330,61 @@ fn _any_indices_within<T: Positioned + Debug>(
             let component = n.axis.component(&origin);
 
             ((component - radius <= n.midpoint)
-                && _any_indices_within(item_arena, n.left.as_ref(), origin, radius))
+                && _any_indices_within(n.left.as_ref(), origin, radius))
                 || ((component + radius > n.midpoint)
-                && _any_indices_within(item_arena, n.right.as_ref(), origin, radius))
+                && _any_indices_within(n.right.as_ref(), origin, radius))
         }
     }
 }
 
-fn _get_indices_within<T: Positioned + Debug>(
-    item_arena: &[T],
+fn _visit_indices_within(
     tree: &KdTree,
     origin: Point3<f32>,
     radius: f32,
-    items: &mut Vec<usize>,
+    f: &mut impl FnMut(usize),
 ) {
     match tree {
         KdTree::Leaf(l) => {
-            items.extend(
-                l.iter()
-                    .filter(|i| (item_arena[**i].position() - origin).magnitude() <= radius),
-            );
+            for (i, position) in l {
+                if (position - origin).magnitude() <= radius {
+                    f(*i)
+                }
+            }
         }
         KdTree::Node(n) => {
             let component = n.axis.component(&origin);
 
             if component - radius <= n.midpoint {
-                _get_indices_within(item_arena, &n.left, origin, radius, items)
+                _visit_indices_within(&n.left, origin, radius, f)
             }
 
             if component + radius > n.midpoint {
-                _get_indices_within(item_arena, &n.right, origin, radius, items)
+                _visit_indices_within(&n.right, origin, radius, f)
+            }
+        }
+    }
+}
+
+fn _k_nearest(tree: &KdTree, origin: Point3<f32>, nearest: &mut NearestQueue) {
+    match tree {
+        KdTree::Leaf(l) => {
+            for (i, position) in l {
+                nearest.offer(*i, (position - origin).magnitude())
+            }
+        }
+        KdTree::Node(n) => {
+            let offset = n.axis.component(&origin) - n.midpoint;
+            let (near, far) = if offset > 0.0 {
+                (&n.right, &n.left)
+            } else {
+                (&n.left, &n.right)
+            };
+
+            // The side `origin` is on goes first, so by the time the other side is reached it can usually be skipped
+            _k_nearest(near, origin, nearest);
+
+            if offset.abs() < nearest.bound() {
+                _k_nearest(far, origin, nearest)
             }
         }
     }
@@ -243,6 +409,15 @@ impl<T> Index<usize> for KdContainer<T>
     }
 }
 
+impl<T> Default for KdContainer<T>
+    where
+        T: Positioned + Debug + Copy + Sync + Send,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<T> KdContainer<T>
     where
         T: Positioned + Debug + Copy + Sync + Send,
@@ -259,27 +434,45 @@ impl<T> KdContainer<T>
 
         let index = self.items.len() - 1;
 
-        _insert_item_index(&self.items, &mut self.tree, SplitAxis::X, index)
+        _insert_entry(&mut self.tree, SplitAxis::X, (index, point.position()))
     }
 
-    pub fn append(&mut self, points: Vec<T>) {
-        for point in points {
-            self.push(point)
-        }
+    pub fn any_items_in_radius(&self, point: Point3<f32>, radius: f32) -> bool {
+        _any_indices_within(&self.tree, point, radius)
     }
 
-    pub fn any_items_in_radius(&self, point: Point3<f32>, radius: f32) -> bool {
-        _any_indices_within(&self.items, &self.tree, point, radius)
+    // visit_items_in_radius calls `f` with the index of every item within `radius` of `point`
+    pub fn visit_items_in_radius(&self, point: Point3<f32>, radius: f32, mut f: impl FnMut(usize)) {
+        _visit_indices_within(&self.tree, point, radius, &mut f)
+    }
+
+    pub fn nearest_item(&self, point: Point3<f32>) -> Option<(usize, f32)> {
+        self.k_nearest_items(point, 1).pop()
+    }
+
+    // k_nearest_items gives the index of the `k` closest items and their distances, closest first
+    pub fn k_nearest_items(&self, point: Point3<f32>, k: usize) -> Vec<(usize, f32)> {
+        let mut nearest = NearestQueue::new(k);
+        _k_nearest(&self.tree, point, &mut nearest);
+
+        nearest.into_sorted()
     }
 }
 
 // KdIndexer uses a KdTree to provide spatial indexing
+// It's kept up to date as items move, nodes that get too lopsided or too empty are rebuilt as it goes
 pub struct KdIndexer {
     avg_query_size_samples: RefCell<VecDeque<isize>>,
 
     root: KdTree,
 }
 
+impl Default for KdIndexer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl KdIndexer {
     pub fn new() -> Self {
         KdIndexer {
@@ -295,7 +488,7 @@ impl KdIndexer {
     fn avg_query_size(&self) -> usize {
         let samples = self.avg_query_size_samples.borrow();
 
-        if samples.len() == 0 {
+        if samples.is_empty() {
             return 0;
         }
 
@@ -315,29 +508,70 @@ impl KdIndexer {
 
 impl<P: Positioned + Debug + Sync> SpatialIndexer<P> for KdIndexer {
     fn reindex(&mut self, items: &[P], indices: Vec<usize>) {
-        // (0..items.len()).collect()
-        self.root = _construct(items, indices, SplitAxis::X)
+        let entries = indices.into_iter().map(|i| (i, items[i].position())).collect();
+
+        self.root = _construct(entries, SplitAxis::X)
     }
 
     fn insert_item_index(&mut self, items: &[P], index: usize) {
-        _insert_item_index(items, &mut self.root, SplitAxis::X, index)
+        _insert_entry(&mut self.root, SplitAxis::X, (index, items[index].position()))
     }
 
     fn remove_item_index(&mut self, items: &[P], index: usize) {
-        _remove_item_index(items, &mut self.root, index)
+        _remove_entry(&mut self.root, index, items[index].position())
     }
 
-    fn get_indices_within(&self, items: &[P], origin: Point3<f32>, radius: f32) -> Vec<usize> {
+    fn move_item_index(&mut self, old: &[P], new: &[P], index: usize) {
+        _move_entry(&mut self.root, index, old[index].position(), new[index].position())
+    }
+
+    fn remap_item_index(&mut self, items: &[P], old: usize, new: usize) {
+        _remap_entry(&mut self.root, old, new, items[new].position())
+    }
+
+    fn incremental(&self) -> bool {
+        true
+    }
+
+    fn get_indices_within(&self, _items: &[P], origin: Point3<f32>, radius: f32) -> Vec<usize> {
         let mut indicies = Vec::with_capacity(self.avg_query_size() * 2);
 
-        _get_indices_within(items, &self.root, origin, radius, &mut indicies);
+        _visit_indices_within(&self.root, origin, radius, &mut |i| indicies.push(i));
 
         self.sample_query_size(indicies.len());
 
         indicies
     }
 
-    fn any_indices_within(&self, items: &[P], origin: Point3<f32>, radius: f32) -> bool {
-        _any_indices_within(items, &self.root, origin, radius)
+    fn any_indices_within(&self, _items: &[P], origin: Point3<f32>, radius: f32) -> bool {
+        _any_indices_within(&self.root, origin, radius)
+    }
+
+    fn visit_indices_within(
+        &self,
+        _items: &[P],
+        origin: Point3<f32>,
+        radius: f32,
+        mut f: impl FnMut(usize),
+    ) {
+        _visit_indices_within(&self.root, origin, radius, &mut f)
+    }
+
+    fn k_nearest(&self, _items: &[P], origin: Point3<f32>, k: usize) -> Vec<(usize, f32)> {
+        let mut nearest = NearestQueue::new(k);
+        _k_nearest(&self.root, origin, &mut nearest);
+
+        nearest.into_sorted()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spatial_index::tests::check_incremental;
+
+    #[test]
+    fn incremental_matches_reindex() {
+        check_incremental(KdIndexer::new(), KdIndexer::new)
     }
 }
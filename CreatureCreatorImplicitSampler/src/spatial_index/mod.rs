@@ -1,6 +1,8 @@
 use nalgebra::Point3;
 
+pub mod grid_indexer;
 pub mod kd_indexer;
+pub mod octree_indexer;
 
 // TODO: Implement other spatial indexing algorithms (Octree, HNSW, more?)
 
@@ -25,9 +27,190 @@ pub trait SpatialIndexer<P: Positioned> {
     // remove_item_index will remove the index for items[index], so it can no longer be queried for
     fn remove_item_index(&mut self, items: &[P], index: usize);
 
+    // move_item_index updates the index for an item that was at old[index] and is now at new[index]
+    fn move_item_index(&mut self, old: &[P], new: &[P], index: usize) {
+        self.remove_item_index(old, index);
+        self.insert_item_index(new, index)
+    }
+
+    // remap_item_index updates the index for an item that's been copied from items[old] to items[new] without moving
+    fn remap_item_index(&mut self, items: &[P], old: usize, new: usize) {
+        self.remove_item_index(items, old);
+        self.insert_item_index(items, new)
+    }
+
+    // incremental is whether the index is best kept up to date with the methods above as items move,
+    // rather than being rebuilt with reindex
+    fn incremental(&self) -> bool {
+        false
+    }
+
     // get_indices_within will return the index of all items within `radius` of `origin`
     fn get_indices_within(&self, items: &[P], origin: Point3<f32>, radius: f32) -> Vec<usize>;
 
     // any_indices_within will return true if there any items within `radius` of `origin`
     fn any_indices_within(&self, items: &[P], origin: Point3<f32>, radius: f32) -> bool;
+
+    // visit_indices_within calls `f` with the index of every item within `radius` of `origin`, in no particular
+    // order. It's get_indices_within without having to allocate a vec for the results
+    fn visit_indices_within(
+        &self,
+        items: &[P],
+        origin: Point3<f32>,
+        radius: f32,
+        f: impl FnMut(usize),
+    );
+
+    // nearest will return the index of the closest item to `origin` and how far away it is
+    fn nearest(&self, items: &[P], origin: Point3<f32>) -> Option<(usize, f32)> {
+        self.k_nearest(items, origin, 1).pop()
+    }
+
+    // k_nearest will return the index of the `k` closest items to `origin` and how far away each is, closest first
+    // There are fewer than `k` when there aren't that many items
+    fn k_nearest(&self, items: &[P], origin: Point3<f32>, k: usize) -> Vec<(usize, f32)>;
+}
+
+// NearestQueue keeps the closest items it's offered, for the indexers' k_nearest
+pub(crate) struct NearestQueue {
+    k: usize,
+
+    // Sorted by distance, closest first
+    found: Vec<(usize, f32)>,
+}
+
+impl NearestQueue {
+    pub fn new(k: usize) -> Self {
+        NearestQueue {
+            k,
+            found: Vec::with_capacity(k),
+        }
+    }
+
+    // bound is how far away an item can be and still be one of the closest, anything further can be skipped
+    pub fn bound(&self) -> f32 {
+        if self.found.len() < self.k {
+            return f32::INFINITY;
+        }
+
+        self.found.last().map_or(f32::NEG_INFINITY, |(_, d)| *d)
+    }
+
+    pub fn offer(&mut self, index: usize, distance: f32) {
+        if distance.is_nan() || distance >= self.bound() {
+            return;
+        }
+
+        let at = self.found.partition_point(|(_, d)| *d <= distance);
+        self.found.insert(at, (index, distance));
+        self.found.truncate(self.k)
+    }
+
+    pub fn into_sorted(self) -> Vec<(usize, f32)> {
+        self.found
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use nalgebra::Vector3;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use super::*;
+
+    const ITEMS: usize = 2000;
+
+    // Most points are on a flat patch, like a patch of surface, so indexers that split space have to cope with
+    // everything being level along one axis
+    fn random_point(rng: &mut StdRng) -> Point3<f32> {
+        let z = if rng.gen_bool(0.5) { 0.0 } else { rng.gen_range(-1.0..1.0) };
+
+        Point3::new(rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0), z)
+    }
+
+    // moved_point is mostly a small step, like a particle update, but sometimes a jump or somewhere that isn't finite
+    fn moved_point(rng: &mut StdRng, from: Point3<f32>) -> Point3<f32> {
+        match rng.gen_range(0..10) {
+            0 => Point3::from(Vector3::repeat(f32::NAN)),
+            1 => Point3::new(rng.gen_range(-1.0..1.0), f32::INFINITY, f32::NEG_INFINITY),
+            2 | 3 => random_point(rng),
+            _ if !from.iter().all(|c| c.is_finite()) => random_point(rng),
+            _ => from + Vector3::from_fn(|_, _| rng.gen_range(-0.05..0.05)),
+        }
+    }
+
+    // check_incremental applies random inserts, removes, moves and remaps to `index`, the way the sampler does, and
+    // checks its queries against `fresh` reindexed over the same items after every round
+    pub(crate) fn check_incremental<I: SpatialIndexer<Point3<f32>>>(mut index: I, fresh: impl Fn() -> I) {
+        let mut rng = StdRng::seed_from_u64(7);
+
+        // Most of the items go in up front, so there are enough for the index to have split a few times
+        let mut items: Vec<Point3<f32>> = (0..ITEMS).map(|_| random_point(&mut rng)).collect();
+        let mut living: Vec<usize> = (0..ITEMS * 3 / 4).collect();
+        let mut free: Vec<usize> = (ITEMS * 3 / 4..ITEMS).rev().collect();
+        for i in &living {
+            index.insert_item_index(&items, *i)
+        }
+
+        // Where the moved item was, like the sampler's other buffer. Only the moved item's position is read from it
+        let mut previous = items.clone();
+
+        for _ in 0..30 {
+            for _ in 0..200 {
+                let picked = (!living.is_empty()).then(|| rng.gen_range(0..living.len()));
+
+                match (rng.gen_range(0..4), picked, free.last()) {
+                    (0, _, Some(_)) => {
+                        let i = free.pop().unwrap();
+                        items[i] = random_point(&mut rng);
+                        index.insert_item_index(&items, i);
+                        living.push(i)
+                    }
+                    (1, Some(j), _) => {
+                        let i = living.swap_remove(j);
+                        index.remove_item_index(&items, i);
+                        free.push(i)
+                    }
+                    (2, Some(j), _) => {
+                        let i = living[j];
+                        previous[i] = items[i];
+                        items[i] = moved_point(&mut rng, items[i]);
+                        index.move_item_index(&previous, &items, i)
+                    }
+                    (3, Some(j), Some(_)) => {
+                        let (i, n) = (living[j], free.pop().unwrap());
+                        items[n] = items[i];
+                        index.remap_item_index(&items, i, n);
+                        living[j] = n;
+                        free.push(i)
+                    }
+                    _ => {}
+                }
+            }
+
+            let mut rebuilt = fresh();
+            rebuilt.reindex(&items, living.clone());
+
+            for _ in 0..50 {
+                let origin = random_point(&mut rng);
+                let radius = rng.gen_range(0.05..0.5);
+
+                let mut found = index.get_indices_within(&items, origin, radius);
+                let mut expected = rebuilt.get_indices_within(&items, origin, radius);
+                found.sort_unstable();
+                expected.sort_unstable();
+                assert_eq!(found, expected, "items within {radius} of {origin}");
+
+                // Items the same distance away can come back in either order, so only the distances are compared
+                let k = rng.gen_range(1..10);
+                let distances = |nearest: Vec<(usize, f32)>| nearest.into_iter().map(|(_, d)| d).collect::<Vec<_>>();
+                assert_eq!(
+                    distances(index.k_nearest(&items, origin, k)),
+                    distances(rebuilt.k_nearest(&items, origin, k)),
+                    "{k} nearest to {origin}"
+                );
+            }
+        }
+    }
 }
@@ -0,0 +1,520 @@
+use nalgebra::{Point3, Vector3};
+
+use crate::spatial_index::{NearestQueue, Positioned, SpatialIndexer};
+
+// DEFAULT_LEAF_SIZE is how many items a leaf holds before it's split
+const DEFAULT_LEAF_SIZE: usize = 16;
+// LOOSENESS is how much bigger a node's loose bounds are than its cell
+// Items go into the cell they're in, but only have to be moved once they leave the loose bounds, so particles
+// jittering around a cell boundary don't get moved back and forth every iteration
+const LOOSENESS: f32 = 2.0;
+// MAX_DEPTH stops items sitting on top of each other from splitting leaves forever
+const MAX_DEPTH: usize = 16;
+
+// A node is a leaf when it has no children, only leaves hold items
+#[derive(Debug)]
+struct Node {
+    center: Point3<f32>,
+    half_size: f32,
+    depth: usize,
+    parent: Option<usize>,
+
+    // The first of the 8 consecutive nodes that are its children, ordered by octant
+    children: Option<usize>,
+    items: Vec<usize>,
+    // How many items are in this node and everything under it
+    count: usize,
+}
+
+impl Node {
+    fn new(center: Point3<f32>, half_size: f32, depth: usize, parent: Option<usize>) -> Self {
+        Node {
+            center,
+            half_size,
+            depth,
+            parent,
+            children: None,
+            items: vec![],
+            count: 0,
+        }
+    }
+
+    fn octant(&self, p: Point3<f32>) -> usize {
+        (p.x > self.center.x) as usize
+            | ((p.y > self.center.y) as usize) << 1
+            | ((p.z > self.center.z) as usize) << 2
+    }
+
+    fn octant_center(&self, octant: usize) -> Point3<f32> {
+        let offset = |bit: usize| if octant & bit != 0 { 0.5 } else { -0.5 };
+
+        self.center + Vector3::new(offset(1), offset(2), offset(4)) * self.half_size
+    }
+
+    fn contains(&self, p: Point3<f32>) -> bool {
+        (p - self.center).amax() <= self.half_size
+    }
+
+    fn loosely_contains(&self, p: Point3<f32>) -> bool {
+        (p - self.center).amax() <= self.half_size * LOOSENESS
+    }
+
+    // overlaps is whether the loose bounds overlap the cube around `origin`
+    fn overlaps(&self, origin: Point3<f32>, radius: f32) -> bool {
+        (origin - self.center).amax() <= self.half_size * LOOSENESS + radius
+    }
+
+    // distance is how far `origin` is from the loose bounds, nothing in the node can be closer
+    fn distance(&self, origin: Point3<f32>) -> f32 {
+        ((origin - self.center).abs() - Vector3::repeat(self.half_size * LOOSENESS))
+            .map(|d| d.max(0.0))
+            .magnitude()
+    }
+}
+
+// OctreeIndexer is a loose octree, which can be kept up to date as items move instead of being rebuilt
+pub struct OctreeIndexer {
+    leaf_size: usize,
+
+    // nodes[0] is always the root, children are allocated 8 at a time
+    nodes: Vec<Node>,
+    // The first node of each block of 8 that's been freed by merging
+    free_blocks: Vec<usize>,
+
+    // Which leaf each item is in, by item index
+    locations: Vec<Option<usize>>,
+    // Items whose position isn't finite don't go in any cell, the root would grow forever trying to reach them
+    // Nothing's ever within range of them, so they're just kept here until they're moved somewhere finite or removed
+    unplaced: Vec<usize>,
+}
+
+impl Default for OctreeIndexer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl OctreeIndexer {
+    pub fn new() -> Self {
+        Self::with_leaf_size(DEFAULT_LEAF_SIZE)
+    }
+
+    pub fn with_leaf_size(leaf_size: usize) -> Self {
+        assert!(leaf_size > 0, "leaves should hold at least one item");
+
+        OctreeIndexer {
+            leaf_size,
+            nodes: vec![Node::new(Point3::origin(), 1.0, 0, None)],
+            free_blocks: vec![],
+            locations: vec![],
+            unplaced: vec![],
+        }
+    }
+
+    pub fn leaf_size(&self) -> usize {
+        self.leaf_size
+    }
+
+    fn clear(&mut self, center: Point3<f32>, half_size: f32) {
+        self.nodes.clear();
+        self.nodes.push(Node::new(center, half_size, 0, None));
+        self.free_blocks.clear();
+        self.locations.clear();
+        self.unplaced.clear();
+    }
+
+    // add_count changes the count of `node` and everything above it
+    fn add_count(&mut self, mut node: usize, delta: isize) {
+        loop {
+            let n = &mut self.nodes[node];
+            n.count = n
+                .count
+                .checked_add_signed(delta)
+                .expect("node count shouldn't go negative");
+
+            match n.parent {
+                Some(parent) => node = parent,
+                None => break,
+            }
+        }
+    }
+
+    fn set_location(&mut self, index: usize, leaf: Option<usize>) {
+        if index >= self.locations.len() {
+            self.locations.resize(index + 1, None)
+        }
+
+        self.locations[index] = leaf
+    }
+
+    // grow_root doubles the root towards `p` until it's inside
+    // The old root becomes one of the new root's children, so nothing under it moves
+    fn grow_root(&mut self, p: Point3<f32>) {
+        while !self.nodes[0].contains(p) {
+            let (center, half_size) = (self.nodes[0].center, self.nodes[0].half_size);
+            let towards = (p - center).map(|c| if c > 0.0 { 1.0 } else { -1.0 });
+
+            let root = Node::new(center + towards * half_size, half_size * 2.0, 0, None);
+            let old_root = std::mem::replace(&mut self.nodes[0], root);
+            let block = self.allocate_block(0);
+
+            // The old root moves into the child slot it takes up in the new root
+            let old = block + self.nodes[0].octant(center);
+            self.nodes[0].count = old_root.count;
+            self.nodes[old] = old_root;
+
+            self.reparent(old, 0);
+        }
+    }
+
+    // reparent fixes up everything under a node that's just been moved to `node`
+    fn reparent(&mut self, node: usize, parent: usize) {
+        self.nodes[node].parent = Some(parent);
+
+        // The depths under the old root all went up by one, and its children and items need to know where it went
+        let mut stack = vec![node];
+        while let Some(n) = stack.pop() {
+            self.nodes[n].depth = self.nodes[self.nodes[n].parent.unwrap()].depth + 1;
+
+            match self.nodes[n].children {
+                Some(children) => {
+                    for c in children..children + 8 {
+                        self.nodes[c].parent = Some(n);
+                        stack.push(c)
+                    }
+                }
+                None => {
+                    for i in self.nodes[n].items.clone() {
+                        self.locations[i] = Some(n)
+                    }
+                }
+            }
+        }
+    }
+
+    // allocate_block gives `parent` 8 empty children, and returns the first
+    fn allocate_block(&mut self, parent: usize) -> usize {
+        let p = &self.nodes[parent];
+        let children: Vec<Node> = (0..8)
+            .map(|octant| {
+                Node::new(
+                    p.octant_center(octant),
+                    p.half_size / 2.0,
+                    p.depth + 1,
+                    Some(parent),
+                )
+            })
+            .collect();
+
+        let block = match self.free_blocks.pop() {
+            Some(block) => {
+                for (i, child) in children.into_iter().enumerate() {
+                    self.nodes[block + i] = child
+                }
+
+                block
+            }
+            None => {
+                let block = self.nodes.len();
+                self.nodes.extend(children);
+
+                block
+            }
+        };
+
+        self.nodes[parent].children = Some(block);
+
+        block
+    }
+
+    fn insert<P: Positioned>(&mut self, items: &[P], index: usize) {
+        let p = items[index].position();
+        if !p.iter().all(|c| c.is_finite()) {
+            self.unplaced.push(index);
+            return;
+        }
+
+        self.grow_root(p);
+
+        let mut node = 0;
+        while let Some(children) = self.nodes[node].children {
+            node = children + self.nodes[node].octant(p)
+        }
+
+        self.nodes[node].items.push(index);
+        self.set_location(index, Some(node));
+        self.add_count(node, 1);
+
+        if self.nodes[node].items.len() > self.leaf_size && self.nodes[node].depth < MAX_DEPTH {
+            self.split(items, node)
+        }
+    }
+
+    fn split<P: Positioned>(&mut self, items: &[P], node: usize) {
+        let block = self.allocate_block(node);
+        let moving = std::mem::take(&mut self.nodes[node].items);
+
+        // Items that have wandered out of the node's cell, but not its loose bounds, might not fit in any of the
+        // children's loose bounds. They're put back in from the top instead
+        let mut strays = vec![];
+        for i in moving {
+            let p = items[i].position();
+            if !self.nodes[node].contains(p) {
+                strays.push(i);
+                continue;
+            }
+
+            let child = block + self.nodes[node].octant(p);
+
+            self.nodes[child].items.push(i);
+            self.nodes[child].count += 1;
+            self.locations[i] = Some(child)
+        }
+
+        // The strays come off the counts before anything is put back in. Putting one back can grow the root, and if
+        // this node is the root it moves, so `node` wouldn't point at it anymore
+        self.add_count(node, -(strays.len() as isize));
+
+        // Everything might have gone into the same child
+        for c in block..block + 8 {
+            if self.nodes[c].items.len() > self.leaf_size && self.nodes[c].depth < MAX_DEPTH {
+                self.split(items, c)
+            }
+        }
+
+        for i in strays {
+            self.insert(items, i)
+        }
+    }
+
+    fn remove(&mut self, index: usize) {
+        if let Some(i) = self.unplaced.iter().position(|i| *i == index) {
+            self.unplaced.swap_remove(i);
+            return;
+        }
+
+        let leaf = self
+            .locations
+            .get(index)
+            .copied()
+            .flatten()
+            .expect("item should be indexed");
+
+        let items = &mut self.nodes[leaf].items;
+        let i = items
+            .iter()
+            .position(|i| *i == index)
+            .expect("item should be in its leaf");
+        items.swap_remove(i);
+
+        self.locations[index] = None;
+        self.add_count(leaf, -1);
+
+        // Once a node's children hold few enough items they're folded back into it
+        let mut node = self.nodes[leaf].parent;
+        while let Some(n) = node {
+            if self.nodes[n].count > self.leaf_size / 2 {
+                break;
+            }
+
+            self.merge(n);
+            node = self.nodes[n].parent;
+        }
+    }
+
+    fn merge(&mut self, node: usize) {
+        let Some(block) = self.nodes[node].children.take() else {
+            return;
+        };
+
+        for c in block..block + 8 {
+            self.merge(c);
+
+            for i in std::mem::take(&mut self.nodes[c].items) {
+                self.nodes[node].items.push(i);
+                self.locations[i] = Some(node)
+            }
+        }
+
+        self.free_blocks.push(block)
+    }
+
+    fn visit(
+        &self,
+        node: usize,
+        origin: Point3<f32>,
+        radius: f32,
+        f: &mut impl FnMut(usize) -> bool,
+    ) -> bool {
+        let n = &self.nodes[node];
+        if n.count == 0 || !n.overlaps(origin, radius) {
+            return false;
+        }
+
+        match n.children {
+            Some(children) => (children..children + 8).any(|c| self.visit(c, origin, radius, f)),
+            None => n.items.iter().any(|i| f(*i)),
+        }
+    }
+
+    fn k_nearest<P: Positioned>(
+        &self,
+        items: &[P],
+        node: usize,
+        origin: Point3<f32>,
+        nearest: &mut NearestQueue,
+    ) {
+        let n = &self.nodes[node];
+        if n.count == 0 || n.distance(origin) >= nearest.bound() {
+            return;
+        }
+
+        match n.children {
+            Some(children) => {
+                // Closer children first, so the further ones can usually be skipped
+                let mut order: [(f32, usize); 8] =
+                    std::array::from_fn(|i| (self.nodes[children + i].distance(origin), children + i));
+                order.sort_unstable_by(|a, b| a.0.total_cmp(&b.0));
+
+                for (_, c) in order {
+                    self.k_nearest(items, c, origin, nearest)
+                }
+            }
+            None => {
+                for i in &n.items {
+                    nearest.offer(*i, (items[*i].position() - origin).magnitude())
+                }
+            }
+        }
+    }
+}
+
+impl<P: Positioned> SpatialIndexer<P> for OctreeIndexer {
+    fn reindex(&mut self, items: &[P], indices: Vec<usize>) {
+        // Starting the root around everything means it never has to grow, unplaced items aren't in it anyway
+        let (min, max) = indices
+            .iter()
+            .map(|i| items[*i].position())
+            .filter(|p| p.iter().all(|c| c.is_finite()))
+            .fold(
+                (
+                    Point3::from(Vector3::repeat(f32::MAX)),
+                    Point3::from(Vector3::repeat(f32::MIN)),
+                ),
+                |(min, max), p| (min.inf(&p), max.sup(&p)),
+            );
+
+        if min.x > max.x {
+            self.clear(Point3::origin(), 1.0)
+        } else {
+            let half_size = ((max - min).amax() / 2.0).max(f32::EPSILON);
+            self.clear(nalgebra::center(&min, &max), half_size)
+        }
+
+        for i in indices {
+            self.insert(items, i)
+        }
+    }
+
+    fn insert_item_index(&mut self, items: &[P], index: usize) {
+        self.insert(items, index)
+    }
+
+    fn remove_item_index(&mut self, _items: &[P], index: usize) {
+        self.remove(index)
+    }
+
+    // Items only have to go somewhere else once they've left their leaf's loose bounds, unplaced items have no leaf
+    fn move_item_index(&mut self, _old: &[P], new: &[P], index: usize) {
+        let leaf = self.locations.get(index).copied().flatten();
+
+        if leaf.is_none_or(|leaf| !self.nodes[leaf].loosely_contains(new[index].position())) {
+            self.remove(index);
+            self.insert(new, index)
+        }
+    }
+
+    // The item stays in the same leaf under its new index
+    fn remap_item_index(&mut self, _items: &[P], old: usize, new: usize) {
+        if let Some(item) = self.unplaced.iter_mut().find(|i| **i == old) {
+            *item = new;
+            return;
+        }
+
+        let leaf = self.locations[old].expect("item should be indexed");
+
+        let item = self.nodes[leaf]
+            .items
+            .iter_mut()
+            .find(|i| **i == old)
+            .expect("item should be in its leaf");
+        *item = new;
+
+        self.locations[old] = None;
+        self.set_location(new, Some(leaf))
+    }
+
+    fn incremental(&self) -> bool {
+        true
+    }
+
+    fn get_indices_within(&self, items: &[P], origin: Point3<f32>, radius: f32) -> Vec<usize> {
+        let mut found = vec![];
+
+        self.visit(0, origin, radius, &mut |i| {
+            if (items[i].position() - origin).magnitude() <= radius {
+                found.push(i)
+            }
+
+            false
+        });
+
+        found
+    }
+
+    fn any_indices_within(&self, items: &[P], origin: Point3<f32>, radius: f32) -> bool {
+        self.visit(0, origin, radius, &mut |i| {
+            (items[i].position() - origin).magnitude() <= radius
+        })
+    }
+
+    fn visit_indices_within(
+        &self,
+        items: &[P],
+        origin: Point3<f32>,
+        radius: f32,
+        mut f: impl FnMut(usize),
+    ) {
+        self.visit(0, origin, radius, &mut |i| {
+            if (items[i].position() - origin).magnitude() <= radius {
+                f(i)
+            }
+
+            false
+        });
+    }
+
+    fn k_nearest(&self, items: &[P], origin: Point3<f32>, k: usize) -> Vec<(usize, f32)> {
+        let mut nearest = NearestQueue::new(k);
+        OctreeIndexer::k_nearest(self, items, 0, origin, &mut nearest);
+
+        nearest.into_sorted()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spatial_index::tests::check_incremental;
+
+    #[test]
+    fn incremental_matches_reindex() {
+        check_incremental(OctreeIndexer::new(), OctreeIndexer::new)
+    }
+
+    // Tiny leaves split and merge all the time
+    #[test]
+    fn incremental_matches_reindex_with_small_leaves() {
+        check_incremental(OctreeIndexer::with_leaf_size(4), || OctreeIndexer::with_leaf_size(4))
+    }
+}
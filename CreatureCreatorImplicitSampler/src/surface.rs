@@ -1,47 +1,154 @@
 use nalgebra::{point, Point3, vector, Vector3};
 
+// GRADIENT_STEP is the distance used for finite differences
+pub const GRADIENT_STEP: f32 = 0.0001;
+
 pub trait Surface {
     // sample should return the signed distance to the surface at the given point
     // < 0 == Inside; > = == Outside;
     fn sample(&self, at: Point3<f32>) -> f32;
+
+    // gradient defaults to finite differences, surfaces that can do better should override it
+    fn gradient(&self, at: Point3<f32>) -> Vector3<f32> {
+        let h = GRADIENT_STEP;
+
+        let sp = self.sample(at);
+
+        let dx = (self.sample(point![at.x + h, at.y, at.z]) - sp) / h;
+        let dy = (self.sample(point![at.x, at.y + h, at.z]) - sp) / h;
+        let dz = (self.sample(point![at.x, at.y, at.z + h]) - sp) / h;
+
+        vector![dx, dy, dz]
+    }
+
+    // sample_batch writes the value at each point to `out`
+    // Surfaces that can evaluate many points faster than one at a time should override it
+    fn sample_batch(&self, points: &[Point3<f32>], out: &mut [f32]) {
+        assert_eq!(points.len(), out.len(), "every point needs somewhere to go");
+
+        for (p, o) in points.iter().zip(out.iter_mut()) {
+            *o = self.sample(*p)
+        }
+    }
+
+    // gradient_batch writes the gradient at each point to `out`
+    fn gradient_batch(&self, points: &[Point3<f32>], out: &mut [Vector3<f32>]) {
+        assert_eq!(points.len(), out.len(), "every point needs somewhere to go");
+
+        for (p, o) in points.iter().zip(out.iter_mut()) {
+            *o = self.gradient(*p)
+        }
+    }
+
+    // seed_hint can give a point close to the surface to start searching from
+    // Surfaces with a flat field away from the surface need this, since a search started there goes nowhere
+    fn seed_hint(&self) -> Option<Point3<f32>> {
+        None
+    }
 }
 
-pub fn seed<S: Surface>(surface: &S) -> Point3<f32> {
-    let mut seed_point = point![rand::random(), rand::random(), rand::random()];
+impl<S: Surface + ?Sized> Surface for &S {
+    fn sample(&self, at: Point3<f32>) -> f32 {
+        (**self).sample(at)
+    }
+
+    fn gradient(&self, at: Point3<f32>) -> Vector3<f32> {
+        (**self).gradient(at)
+    }
+
+    fn sample_batch(&self, points: &[Point3<f32>], out: &mut [f32]) {
+        (**self).sample_batch(points, out)
+    }
+
+    fn gradient_batch(&self, points: &[Point3<f32>], out: &mut [Vector3<f32>]) {
+        (**self).gradient_batch(points, out)
+    }
+
+    fn seed_hint(&self) -> Option<Point3<f32>> {
+        (**self).seed_hint()
+    }
+}
+
+impl<S: Surface + ?Sized> Surface for Box<S> {
+    fn sample(&self, at: Point3<f32>) -> f32 {
+        (**self).sample(at)
+    }
+
+    fn gradient(&self, at: Point3<f32>) -> Vector3<f32> {
+        (**self).gradient(at)
+    }
+
+    fn sample_batch(&self, points: &[Point3<f32>], out: &mut [f32]) {
+        (**self).sample_batch(points, out)
+    }
+
+    fn gradient_batch(&self, points: &[Point3<f32>], out: &mut [Vector3<f32>]) {
+        (**self).gradient_batch(points, out)
+    }
+
+    fn seed_hint(&self) -> Option<Point3<f32>> {
+        (**self).seed_hint()
+    }
+}
+
+// seed finds a point on the surface to start sampling from, None if Newton's method can't get to one
+// That's usually because there's no surface at all, like metaballs too weak to reach the threshold, where the
+// field is flat everywhere
+pub fn seed<S: Surface>(surface: &S) -> Option<Point3<f32>> {
+    let mut seed_point = surface
+        .seed_hint()
+        .unwrap_or_else(|| point![rand::random(), rand::random(), rand::random()]);
 
     for _ in 0..100 {
         let grad = gradient(surface, seed_point);
 
+        // A flat field has nowhere to step to
         let gdg = grad.dot(&grad);
-        if gdg.is_nan() {
-            panic!("NANANANANANA")
+        if gdg.is_nan() || gdg == 0.0 {
+            return None;
         }
 
         seed_point -= grad.scale(surface.sample(seed_point) / gdg);
 
         if on_surface(surface, seed_point) {
-            return seed_point;
+            return Some(seed_point);
         }
     }
 
-    if !on_surface(surface, seed_point) {
-        dbg!(seed_point);
-        panic!("could not find a seed point")
-    }
-
-    seed_point
+    None
 }
 
-pub fn gradient<S: Surface>(surface: &S, p: Point3<f32>) -> Vector3<f32> {
-    let h = 0.0001;
+// find_crossing walks from `inside` along `direction` until it leaves the surface, then bisects for the crossing
+// `step` is the first distance tried, it's doubled until the walk gets outside
+pub(crate) fn find_crossing<S: Surface + ?Sized>(
+    surface: &S,
+    inside: Point3<f32>,
+    direction: Vector3<f32>,
+    step: f32,
+) -> Point3<f32> {
+    assert!(step > 0.0, "step should be positive");
 
-    let sp = surface.sample(p);
+    let (mut near, mut far) = (0.0, step);
+    while surface.sample(inside + direction * far) < 0.0 {
+        near = far;
+        far *= 2.0;
+    }
 
-    let dx = (surface.sample(point![p.x + h, p.y, p.z]) - sp) / h;
-    let dy = (surface.sample(point![p.x, p.y + h, p.z]) - sp) / h;
-    let dz = (surface.sample(point![p.x, p.y, p.z + h]) - sp) / h;
+    // The crossing has to be found fairly closely, Newton steps from `seed` overshoot wherever the field flattens out
+    for _ in 0..32 {
+        let middle = (near + far) / 2.0;
+        if surface.sample(inside + direction * middle) < 0.0 {
+            near = middle
+        } else {
+            far = middle
+        }
+    }
 
-    vector![dx, dy, dz]
+    inside + direction * ((near + far) / 2.0)
+}
+
+pub fn gradient<S: Surface>(surface: &S, p: Point3<f32>) -> Vector3<f32> {
+    surface.gradient(p)
 }
 
 pub fn on_surface<S: Surface>(surface: &S, point: Point3<f32>) -> bool {
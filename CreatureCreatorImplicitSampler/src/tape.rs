@@ -0,0 +1,651 @@
+use std::collections::HashMap;
+
+use nalgebra::{Point3, Vector3};
+
+use crate::autodiff::value_and_gradient;
+use crate::shapes::smooth_min;
+use crate::surface::Surface;
+
+// A Tape is a surface tree flattened into a linear list of instructions, similar to libfive
+// Evaluating a tape is a single loop over a small register file, instead of a chain of nested trait calls
+
+// Registers are kept on the stack when a tape needs this many or fewer
+pub(crate) const INLINE_REGISTERS: usize = 32;
+
+// Batches are evaluated this many points at a time, each register holds one value per lane
+const BATCH_LANES: usize = 64;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Opcode {
+    X,
+    Y,
+    Z,
+    Const,
+
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Min,
+    Max,
+    Neg,
+    Abs,
+    Square,
+    Sqrt,
+    AddImm,
+    MulImm,
+
+    // The rest are the common building blocks of shapes, so that most primitives are only a few instructions
+    // Dispatch is the main cost of running a tape, so fewer bigger instructions go a long way
+
+    // a * imm[0] + b * imm[1] + c * imm[2] + imm[3]
+    Affine,
+    // a² * imm[0] + b² * imm[1] + c² * imm[2] + imm[3]
+    WeightedSquares,
+    // sqrt(a² + b² + c²)
+    Length,
+    // smooth_min(a, b, imm[0])
+    SmoothMin,
+}
+
+impl Opcode {
+    pub fn arity(&self) -> usize {
+        match self {
+            Opcode::X | Opcode::Y | Opcode::Z | Opcode::Const => 0,
+            Opcode::Neg
+            | Opcode::Abs
+            | Opcode::Square
+            | Opcode::Sqrt
+            | Opcode::AddImm
+            | Opcode::MulImm => 1,
+            Opcode::Add
+            | Opcode::Sub
+            | Opcode::Mul
+            | Opcode::Div
+            | Opcode::Min
+            | Opcode::Max
+            | Opcode::SmoothMin => 2,
+            Opcode::Affine | Opcode::WeightedSquares | Opcode::Length => 3,
+        }
+    }
+
+    fn is_commutative(&self) -> bool {
+        matches!(
+            self,
+            Opcode::Add | Opcode::Mul | Opcode::Min | Opcode::Max | Opcode::SmoothMin
+        )
+    }
+
+    // apply evaluates every opcode apart from the inputs, which don't have operands
+    #[inline(always)]
+    fn apply(&self, a: f32, b: f32, c: f32, imm: [f32; 4]) -> f32 {
+        match self {
+            Opcode::X | Opcode::Y | Opcode::Z => unreachable!("inputs are loaded, not applied"),
+            Opcode::Const => imm[0],
+            Opcode::Add => a + b,
+            Opcode::Sub => a - b,
+            Opcode::Mul => a * b,
+            Opcode::Div => a / b,
+            Opcode::Min => a.min(b),
+            Opcode::Max => a.max(b),
+            Opcode::Neg => -a,
+            Opcode::Abs => a.abs(),
+            Opcode::Square => a * a,
+            Opcode::Sqrt => a.sqrt(),
+            Opcode::AddImm => a + imm[0],
+            Opcode::MulImm => a * imm[0],
+            Opcode::Affine => a * imm[0] + b * imm[1] + c * imm[2] + imm[3],
+            Opcode::WeightedSquares => a * a * imm[0] + b * b * imm[1] + c * c * imm[2] + imm[3],
+            Opcode::Length => (a * a + b * b + c * c).sqrt(),
+            Opcode::SmoothMin => smooth_min(a, b, imm[0]),
+        }
+    }
+}
+
+// Instruction reads the registers its opcode needs from `args` and writes the result to `out`
+#[derive(Debug, Copy, Clone)]
+pub struct Instruction {
+    pub opcode: Opcode,
+    pub out: u16,
+    pub args: [u16; 3],
+    pub imm: [f32; 4],
+}
+
+// Value is a handle to a node in the graph being built, it's only meaningful to the builder that made it
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Value(u32);
+
+#[derive(Debug, Copy, Clone)]
+struct Node {
+    opcode: Opcode,
+    args: [u32; 3],
+    imm: [f32; 4],
+}
+
+impl Node {
+    fn operands(&self) -> &[u32] {
+        &self.args[..self.opcode.arity()]
+    }
+}
+
+// Compile is implemented by surfaces that can be flattened into a Tape
+pub trait Compile {
+    // compile should emit the instructions for this surface sampled at `at`, returning the value of the field
+    fn compile(&self, builder: &mut TapeBuilder, at: [Value; 3]) -> Value;
+}
+
+impl<S: Compile + ?Sized> Compile for &S {
+    fn compile(&self, builder: &mut TapeBuilder, at: [Value; 3]) -> Value {
+        (**self).compile(builder, at)
+    }
+}
+
+impl<S: Compile + ?Sized> Compile for Box<S> {
+    fn compile(&self, builder: &mut TapeBuilder, at: [Value; 3]) -> Value {
+        (**self).compile(builder, at)
+    }
+}
+
+// TapeBuilder collects an SSA graph of operations
+// Identical nodes are shared, and operations on constants are folded as they're added
+pub struct TapeBuilder {
+    nodes: Vec<Node>,
+    dedup: HashMap<(Opcode, [u32; 3], [u32; 4]), Value>,
+}
+
+impl TapeBuilder {
+    fn new() -> Self {
+        TapeBuilder {
+            nodes: vec![],
+            dedup: HashMap::new(),
+        }
+    }
+
+    fn push(&mut self, opcode: Opcode, args: [Value; 3], imm: [f32; 4]) -> Value {
+        let mut args = args.map(|v| v.0);
+        if opcode.is_commutative() && args[0] > args[1] {
+            args.swap(0, 1);
+        }
+
+        // Fold the operation away if every operand is already known
+        let operands = &args[..opcode.arity()];
+        if !operands.is_empty()
+            && operands
+                .iter()
+                .all(|a| self.constant_value(Value(*a)).is_some())
+        {
+            let [a, b, c] = args.map(|a| self.constant_value(Value(a)).unwrap_or(0.0));
+
+            return self.constant(opcode.apply(a, b, c, imm));
+        }
+
+        let key = (opcode, args, imm.map(f32::to_bits));
+        if let Some(v) = self.dedup.get(&key) {
+            return *v;
+        }
+
+        let v = Value(self.nodes.len() as u32);
+        self.nodes.push(Node { opcode, args, imm });
+        self.dedup.insert(key, v);
+
+        v
+    }
+
+    fn constant_value(&self, v: Value) -> Option<f32> {
+        let node = self.nodes[v.0 as usize];
+
+        match node.opcode {
+            Opcode::Const => Some(node.imm[0]),
+            _ => None,
+        }
+    }
+
+    fn unary(&mut self, opcode: Opcode, a: Value, imm: f32) -> Value {
+        self.push(opcode, [a, a, a], [imm, 0.0, 0.0, 0.0])
+    }
+
+    fn binary(&mut self, opcode: Opcode, a: Value, b: Value) -> Value {
+        self.push(opcode, [a, b, a], [0.0; 4])
+    }
+
+    pub fn inputs(&mut self) -> [Value; 3] {
+        let unused = Value(0);
+
+        [Opcode::X, Opcode::Y, Opcode::Z].map(|op| self.push(op, [unused; 3], [0.0; 4]))
+    }
+
+    pub fn constant(&mut self, value: f32) -> Value {
+        let unused = Value(0);
+
+        self.push(Opcode::Const, [unused; 3], [value, 0.0, 0.0, 0.0])
+    }
+
+    pub fn add(&mut self, a: Value, b: Value) -> Value {
+        match (self.constant_value(a), self.constant_value(b)) {
+            (Some(ca), None) => self.add_imm(b, ca),
+            (None, Some(cb)) => self.add_imm(a, cb),
+            _ => self.binary(Opcode::Add, a, b),
+        }
+    }
+
+    pub fn sub(&mut self, a: Value, b: Value) -> Value {
+        match (self.constant_value(a), self.constant_value(b)) {
+            (Some(ca), None) => {
+                let nb = self.neg(b);
+                self.add_imm(nb, ca)
+            }
+            (None, Some(cb)) => self.add_imm(a, -cb),
+            _ => self.binary(Opcode::Sub, a, b),
+        }
+    }
+
+    pub fn mul(&mut self, a: Value, b: Value) -> Value {
+        match (self.constant_value(a), self.constant_value(b)) {
+            (Some(ca), None) => self.mul_imm(b, ca),
+            (None, Some(cb)) => self.mul_imm(a, cb),
+            (None, None) if a == b => self.square(a),
+            _ => self.binary(Opcode::Mul, a, b),
+        }
+    }
+
+    pub fn div(&mut self, a: Value, b: Value) -> Value {
+        match (self.constant_value(a), self.constant_value(b)) {
+            (None, Some(cb)) => self.mul_imm(a, 1.0 / cb),
+            _ => self.binary(Opcode::Div, a, b),
+        }
+    }
+
+    pub fn min(&mut self, a: Value, b: Value) -> Value {
+        if a == b {
+            return a;
+        }
+
+        self.binary(Opcode::Min, a, b)
+    }
+
+    pub fn max(&mut self, a: Value, b: Value) -> Value {
+        if a == b {
+            return a;
+        }
+
+        self.binary(Opcode::Max, a, b)
+    }
+
+    pub fn neg(&mut self, a: Value) -> Value {
+        self.unary(Opcode::Neg, a, 0.0)
+    }
+
+    pub fn abs(&mut self, a: Value) -> Value {
+        self.unary(Opcode::Abs, a, 0.0)
+    }
+
+    pub fn square(&mut self, a: Value) -> Value {
+        self.unary(Opcode::Square, a, 0.0)
+    }
+
+    pub fn sqrt(&mut self, a: Value) -> Value {
+        self.unary(Opcode::Sqrt, a, 0.0)
+    }
+
+    pub fn add_imm(&mut self, a: Value, imm: f32) -> Value {
+        if imm == 0.0 {
+            return a;
+        }
+
+        self.unary(Opcode::AddImm, a, imm)
+    }
+
+    pub fn mul_imm(&mut self, a: Value, imm: f32) -> Value {
+        if imm == 1.0 {
+            return a;
+        }
+        if imm == 0.0 {
+            return self.constant(0.0);
+        }
+
+        self.unary(Opcode::MulImm, a, imm)
+    }
+
+    // affine returns at · weights[0..3] + weights[3]
+    pub fn affine(&mut self, at: [Value; 3], weights: [f32; 4]) -> Value {
+        // Rows of axis aligned transforms only touch one input, which doesn't need the full instruction
+        let used: Vec<usize> = (0..3).filter(|i| weights[*i] != 0.0).collect();
+        if used.len() <= 1 {
+            let scaled = match used.first() {
+                Some(i) => self.mul_imm(at[*i], weights[*i]),
+                None => self.constant(0.0),
+            };
+
+            return self.add_imm(scaled, weights[3]);
+        }
+
+        self.push(Opcode::Affine, at, weights)
+    }
+
+    // weighted_squares returns at² · weights[0..3] + weights[3]
+    pub fn weighted_squares(&mut self, at: [Value; 3], weights: [f32; 4]) -> Value {
+        self.push(Opcode::WeightedSquares, at, weights)
+    }
+
+    // length returns the magnitude of the vector made of `at`
+    pub fn length(&mut self, at: [Value; 3]) -> Value {
+        self.push(Opcode::Length, at, [0.0; 4])
+    }
+
+    pub fn smooth_min(&mut self, a: Value, b: Value, k: f32) -> Value {
+        self.push(Opcode::SmoothMin, [a, b, a], [k, 0.0, 0.0, 0.0])
+    }
+
+    // finish runs liveness analysis on everything reachable from `result` and allocates registers
+    fn finish(self, result: Value) -> Tape {
+        let node_count = self.nodes.len();
+
+        // Nodes are created after their operands, so walking backwards visits consumers first
+        let mut reachable = vec![false; node_count];
+        let mut last_use = vec![0usize; node_count];
+        reachable[result.0 as usize] = true;
+        last_use[result.0 as usize] = usize::MAX;
+
+        for n in (0..node_count).rev() {
+            if !reachable[n] {
+                continue;
+            }
+
+            for operand in self.nodes[n].operands() {
+                let o = *operand as usize;
+                if !reachable[o] {
+                    reachable[o] = true;
+                    last_use[o] = n;
+                }
+            }
+        }
+
+        let mut registers = vec![0u16; node_count];
+        let mut free_registers: Vec<u16> = vec![];
+        let mut register_count = 0usize;
+        let mut instructions = vec![];
+
+        for (n, node) in self.nodes.iter().enumerate() {
+            if !reachable[n] {
+                continue;
+            }
+
+            let mut args = [0u16; 3];
+            for (i, operand) in node.operands().iter().enumerate() {
+                args[i] = registers[*operand as usize];
+            }
+
+            // Operands that die here can be reused for the output, instructions read before they write
+            for (i, operand) in node.operands().iter().enumerate() {
+                let first_occurrence = !node.operands()[..i].contains(operand);
+                if first_occurrence && last_use[*operand as usize] == n {
+                    free_registers.push(args[i]);
+                }
+            }
+
+            let out = match free_registers.pop() {
+                Some(r) => r,
+                None => {
+                    assert!(
+                        register_count < u16::MAX as usize,
+                        "tape needs too many registers"
+                    );
+
+                    register_count += 1;
+                    (register_count - 1) as u16
+                }
+            };
+            registers[n] = out;
+
+            instructions.push(Instruction {
+                opcode: node.opcode,
+                out,
+                args,
+                imm: node.imm,
+            });
+        }
+
+        // run skips bounds checks, so nothing it reads or writes can be past the end of the registers
+        // Operands that aren't used are register 0, which every tape has since it always has a result
+        debug_assert!(instructions.iter().all(|i| {
+            (i.out as usize) < register_count
+                && i.args.iter().all(|r| (*r as usize) < register_count)
+        }));
+
+        Tape {
+            instructions,
+            register_count,
+            result: registers[result.0 as usize],
+        }
+    }
+}
+
+pub struct Tape {
+    instructions: Vec<Instruction>,
+    register_count: usize,
+    result: u16,
+}
+
+impl Tape {
+    pub fn compile<S: Compile + ?Sized>(surface: &S) -> Self {
+        let mut builder = TapeBuilder::new();
+
+        let at = builder.inputs();
+        let result = surface.compile(&mut builder, at);
+
+        builder.finish(result)
+    }
+
+    pub fn instructions(&self) -> &[Instruction] {
+        &self.instructions
+    }
+
+    pub fn register_count(&self) -> usize {
+        self.register_count
+    }
+
+    // result_register is the register holding the value of the field once every instruction has run
+    pub fn result_register(&self) -> usize {
+        self.result as usize
+    }
+
+    fn run(&self, registers: &mut [f32], at: Point3<f32>) -> f32 {
+        assert!(registers.len() >= self.register_count);
+
+        for i in &self.instructions {
+            // Safety: every register in the tape is below register_count, which is checked above
+            let [a, b, c] = i
+                .args
+                .map(|r| unsafe { *registers.get_unchecked(r as usize) });
+
+            let value = match i.opcode {
+                Opcode::X => at.x,
+                Opcode::Y => at.y,
+                Opcode::Z => at.z,
+                op => op.apply(a, b, c, i.imm),
+            };
+
+            unsafe { *registers.get_unchecked_mut(i.out as usize) = value }
+        }
+
+        registers[self.result as usize]
+    }
+
+    // run_lanes evaluates the tape for up to LANES points at once
+    // Each instruction is applied across every lane, which the compiler is able to vectorize
+    fn run_lanes<const LANES: usize>(
+        &self,
+        registers: &mut [[f32; LANES]],
+        points: &[Point3<f32>],
+    ) -> [f32; LANES] {
+        assert!(registers.len() >= self.register_count);
+        assert!(points.len() <= LANES);
+
+        for i in &self.instructions {
+            let [a, b, c] = i.args.map(|r| registers[r as usize]);
+            let m = i.imm;
+            let lanes = &mut registers[i.out as usize];
+
+            match i.opcode {
+                Opcode::X => lanes.iter_mut().zip(points).for_each(|(l, p)| *l = p.x),
+                Opcode::Y => lanes.iter_mut().zip(points).for_each(|(l, p)| *l = p.y),
+                Opcode::Z => lanes.iter_mut().zip(points).for_each(|(l, p)| *l = p.z),
+                Opcode::Const => lanes.fill(m[0]),
+                Opcode::Add => (0..LANES).for_each(|l| lanes[l] = a[l] + b[l]),
+                Opcode::Sub => (0..LANES).for_each(|l| lanes[l] = a[l] - b[l]),
+                Opcode::Mul => (0..LANES).for_each(|l| lanes[l] = a[l] * b[l]),
+                Opcode::Div => (0..LANES).for_each(|l| lanes[l] = a[l] / b[l]),
+                Opcode::Min => (0..LANES).for_each(|l| lanes[l] = a[l].min(b[l])),
+                Opcode::Max => (0..LANES).for_each(|l| lanes[l] = a[l].max(b[l])),
+                Opcode::Neg => (0..LANES).for_each(|l| lanes[l] = -a[l]),
+                Opcode::Abs => (0..LANES).for_each(|l| lanes[l] = a[l].abs()),
+                Opcode::Square => (0..LANES).for_each(|l| lanes[l] = a[l] * a[l]),
+                Opcode::Sqrt => (0..LANES).for_each(|l| lanes[l] = a[l].sqrt()),
+                Opcode::AddImm => (0..LANES).for_each(|l| lanes[l] = a[l] + m[0]),
+                Opcode::MulImm => (0..LANES).for_each(|l| lanes[l] = a[l] * m[0]),
+                Opcode::Affine => (0..LANES)
+                    .for_each(|l| lanes[l] = a[l] * m[0] + b[l] * m[1] + c[l] * m[2] + m[3]),
+                Opcode::WeightedSquares => (0..LANES).for_each(|l| {
+                    lanes[l] = a[l] * a[l] * m[0] + b[l] * b[l] * m[1] + c[l] * c[l] * m[2] + m[3]
+                }),
+                Opcode::Length => (0..LANES)
+                    .for_each(|l| lanes[l] = (a[l] * a[l] + b[l] * b[l] + c[l] * c[l]).sqrt()),
+                Opcode::SmoothMin => {
+                    (0..LANES).for_each(|l| lanes[l] = smooth_min(a[l], b[l], m[0]))
+                }
+            }
+        }
+
+        registers[self.result as usize]
+    }
+
+    pub fn eval(&self, at: Point3<f32>) -> f32 {
+        if self.register_count <= INLINE_REGISTERS {
+            let mut registers = [0.0; INLINE_REGISTERS];
+            self.run(&mut registers, at)
+        } else {
+            let mut registers = vec![0.0; self.register_count];
+            self.run(&mut registers, at)
+        }
+    }
+
+    // eval_batch writes the value of the field at each point to `out`
+    pub fn eval_batch(&self, points: &[Point3<f32>], out: &mut [f32]) {
+        assert_eq!(points.len(), out.len(), "every point needs somewhere to go");
+
+        let mut registers = vec![[0.0f32; BATCH_LANES]; self.register_count];
+
+        for (batch, batch_out) in points.chunks(BATCH_LANES).zip(out.chunks_mut(BATCH_LANES)) {
+            let result = self.run_lanes(&mut registers, batch);
+            batch_out.copy_from_slice(&result[..batch_out.len()]);
+        }
+    }
+}
+
+impl Surface for Tape {
+    fn sample(&self, at: Point3<f32>) -> f32 {
+        self.eval(at)
+    }
+
+    fn gradient(&self, at: Point3<f32>) -> Vector3<f32> {
+        value_and_gradient(self, at).1
+    }
+
+    fn sample_batch(&self, points: &[Point3<f32>], out: &mut [f32]) {
+        self.eval_batch(points, out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::{point, vector, Matrix4};
+
+    use super::*;
+    use crate::shapes::{Difference, Ellipsoid, SmoothUnion, Sphere, Transformed, Union};
+
+    fn points() -> impl Iterator<Item = Point3<f32>> {
+        let steps = (-6..=6).map(|i| i as f32 * 0.25);
+
+        steps.clone().flat_map(move |x| {
+            let steps = steps.clone();
+            steps
+                .clone()
+                .flat_map(move |y| steps.clone().map(move |z| point![x, y, z]))
+        })
+    }
+
+    // tree is deep enough that registers have to be reused, and its axis aligned transforms have zero weights
+    // and zero offsets that fold away
+    fn tree() -> impl Surface + Compile {
+        SmoothUnion::new(
+            Transformed::new(
+                Ellipsoid::new(vector![1.0, 0.5, 0.75]),
+                Matrix4::new_translation(&vector![0.5, 0.0, 0.0]),
+            ),
+            Difference(
+                Union(
+                    Sphere::new(0.8),
+                    Transformed::new(Sphere::new(0.5), Matrix4::new_scaling(0.5)),
+                ),
+                Transformed::new(
+                    Sphere::new(0.4),
+                    Matrix4::new_rotation(vector![0.0, 0.0, 0.7])
+                        .append_translation(&vector![0.0, 0.6, 0.0]),
+                ),
+            ),
+            0.3,
+        )
+    }
+
+    #[test]
+    fn tape_matches_tree() {
+        let tree = tree();
+        let tape = Tape::compile(&tree);
+
+        assert!(tape.register_count() < tape.instructions().len());
+
+        for p in points() {
+            let (expected, actual) = (tree.sample(p), tape.sample(p));
+            assert!(
+                (expected - actual).abs() <= 1e-5,
+                "{p}: {expected} != {actual}"
+            );
+
+            let (expected, actual) = (tree.gradient(p), tape.gradient(p));
+            assert!(
+                (expected - actual).norm() <= 1e-2,
+                "{p}: {expected} != {actual}"
+            );
+        }
+    }
+
+    #[test]
+    fn batches_match_points() {
+        let tape = Tape::compile(&tree());
+
+        // More points than lanes, so the registers are reused between batches too
+        let points: Vec<Point3<f32>> = points().collect();
+        let mut out = vec![0.0; points.len()];
+        tape.eval_batch(&points, &mut out);
+
+        for (p, value) in points.iter().zip(out) {
+            assert_eq!(tape.eval(*p), value)
+        }
+    }
+
+    #[test]
+    fn shared_and_constant_nodes_fold() {
+        let sphere = Tape::compile(&Sphere::new(1.0));
+
+        // The union of a surface with itself is the same nodes, so the min of them goes away
+        let union = Tape::compile(&Union(Sphere::new(1.0), Sphere::new(1.0)));
+        assert_eq!(union.instructions().len(), sphere.instructions().len());
+
+        // An identity transform is a multiply by one and an add of zero on each axis, none of which are kept
+        let transformed = Tape::compile(&Transformed::new(Sphere::new(1.0), Matrix4::identity()));
+        assert_eq!(
+            transformed.instructions().len(),
+            sphere.instructions().len()
+        );
+    }
+}
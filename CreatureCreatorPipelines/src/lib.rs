@@ -1,6 +1,5 @@
 mod lines;
 mod transform;
 mod shared;
-mod plane;
 mod surfaces;
 mod utils;
@@ -3,7 +3,8 @@ use std::mem::size_of;
 use metal::{DeviceRef, MTLPixelFormat, MTLPrimitiveType, MTLVertexFormat, MTLVertexStepFunction, NSUInteger, RenderCommandEncoderRef, RenderPipelineDescriptor, RenderPipelineState, VertexAttributeDescriptor, VertexBufferLayoutDescriptor, VertexDescriptor};
 use nalgebra::{point, Point3, vector};
 
-use crate::plane::Plane;
+use creature_creator_implicit_sampler::plane::Plane;
+
 use crate::shared::Shared;
 use crate::transform::Transform;
 
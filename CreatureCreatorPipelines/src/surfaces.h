@@ -1,17 +1,56 @@
 #ifndef SURFACES_H
 #define SURFACES_H
 
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
 #include "transform.h"
 
 struct Ellipsoid {
     float size[3];
 };
 
+struct Metaball {
+    float radius;
+    float strength;
+};
+
+struct MirrorPlane {
+    float origin[3];
+    float normal[3];
+    float blend; // size of the smooth min where the two sides meet, 0 for a plain union
+};
+
+// Passed to surface_pipeline_set_metaball_falloff as a uint32_t
+enum MetaballFalloff {
+    MetaballFalloffBlinn = 0,
+    MetaballFalloffWyvill = 1,
+    MetaballFalloffSoftObject = 2,
+};
+
 void* surface_pipeline_make(void*); // (MTLDevice)
 void surface_pipeline_free(void*);  // (SurfacePipeline)
 void surface_pipeline_begin(void*); // (SurfacePipeline)
 void surface_pipeline_end(void*);   // (SurfacePipeline)
+bool surface_pipeline_begin_mirror(void*, struct MirrorPlane plane); // (SurfacePipeline, ...) false if the plane wasn't valid, draws aren't reflected
+void surface_pipeline_end_mirror(void*); // (SurfacePipeline)
+void surface_pipeline_begin_group(void*, uint32_t group); // (SurfacePipeline, ...)
+void surface_pipeline_end_group(void*); // (SurfacePipeline)
+void surface_pipeline_set_background_sampling(void*, bool background); // (SurfacePipeline, ...)
+bool surface_pipeline_sampling_failed(void*); // (SurfacePipeline) true once a background sampler has panicked
+size_t surface_pipeline_dropped_samples(void*); // (SurfacePipeline) samples that didn't fit in the instance buffer last frame
 void surface_pipeline_draw_ellipsoid(void*, struct FFITransform transform, struct Ellipsoid ellipsoid); // (SurfacePipeline, ...)
+void surface_pipeline_draw_mesh(void*, struct FFITransform transform, void* mesh); // (SurfacePipeline, ..., SurfaceMesh)
+void surface_pipeline_draw_description(void*, struct FFITransform transform, void* description); // (SurfacePipeline, ..., SurfaceDescription)
+void surface_pipeline_draw_metaball(void*, struct FFITransform transform, struct Metaball metaball); // (SurfacePipeline, ...)
+bool surface_pipeline_set_metaball_falloff(void*, uint32_t falloff, float blobbiness, float threshold); // (SurfacePipeline, ...) false if the falloff wasn't valid
 void surface_pipeline_encode(void*, void*); // (SurfacePipeline, MTLRenderCommandEncoder)
 
+void* surface_mesh_make(const float* vertices, size_t vertex_count, const uint32_t* indices, size_t triangle_count); // NULL if the mesh isn't valid
+void surface_mesh_free(void*); // (SurfaceMesh)
+
+void* surface_description_make(const char* source); // JSON or RON, NULL if it can't be read or built
+void surface_description_free(void*); // (SurfaceDescription)
+
 #endif
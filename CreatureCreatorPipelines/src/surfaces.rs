@@ -8,39 +8,275 @@
 
 use std::f32::consts::PI;
 use std::mem::size_of;
+use std::sync::Arc;
 use std::time::Instant;
 
 use metal::{DeviceRef, MTLPixelFormat, MTLPrimitiveType, MTLVertexFormat, MTLVertexStepFunction, NSUInteger, RenderCommandEncoderRef, RenderPipelineDescriptor, RenderPipelineState, VertexAttributeDescriptor, VertexBufferLayoutDescriptor, VertexDescriptor};
-use nalgebra::{Matrix4, Point3, vector, Vector3};
+use nalgebra::{Matrix4, point, Point3, vector, Vector3};
 
 use creature_creator_implicit_sampler::{ImplicitSampler, Surface};
+use creature_creator_implicit_sampler::shapes::{smooth_min, smooth_min_gradient};
+use creature_creator_implicit_sampler::background::{BackgroundSampler, Snapshot};
+use creature_creator_implicit_sampler::bvh::Bvh;
+use creature_creator_implicit_sampler::description::BuiltSurface;
+use creature_creator_implicit_sampler::interval::Aabb;
+use creature_creator_implicit_sampler::mesh::MeshSurface;
+use creature_creator_implicit_sampler::mirror::Mirror;
+use creature_creator_implicit_sampler::plane::Plane;
+use creature_creator_implicit_sampler::metaballs::{Ball, Falloff, Metaballs};
 
 use crate::shared::Shared;
 use crate::transform::Transform;
 
 #[repr(C)]
+#[derive(Copy, Clone)]
 pub struct Ellipsoid {
     size: [f32; 3]
 }
 
+// Metaballs are drawn at the origin of their transform, any scale in the transform is ignored
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct Metaball {
+    radius: f32,
+    strength: f32,
+}
+
+// Draws in a mirror group are also drawn reflected across this plane
+// The two sides are blended where they meet by a smooth min of size `blend`, 0 for a plain union
+#[repr(C)]
+pub struct MirrorPlane {
+    origin: [f32; 3],
+    normal: [f32; 3],
+    blend: f32,
+}
+
+impl MirrorPlane {
+    // mirror is None if the plane can't be made, a normal of zero or NaN anywhere would reflect everything to NaN
+    fn mirror(&self, surface: RenderSurface) -> Option<Mirror<RenderSurface>> {
+        let normal = Vector3::from(self.normal);
+        let valid = self.origin.iter().chain(&self.normal).all(|c| c.is_finite())
+            && normal.norm_squared() > 0.0
+            && self.blend.is_finite()
+            && self.blend >= 0.0;
+        if !valid {
+            return None;
+        }
+
+        let plane = Plane::from_origin_normal(self.origin.into(), normal);
+
+        Some(Mirror::new(surface, plane, self.blend))
+    }
+}
+
+// Falloffs come over FFI as a plain u32, since a C enum can hold values that aren't any of the variants
+pub enum MetaballFalloff {
+    Blinn,
+    Wyvill,
+    SoftObject,
+}
+
+impl MetaballFalloff {
+    // from_raw matches the order of the MetaballFalloff enum in surfaces.h
+    pub fn from_raw(raw: u32) -> Option<Self> {
+        match raw {
+            0 => Some(MetaballFalloff::Blinn),
+            1 => Some(MetaballFalloff::Wyvill),
+            2 => Some(MetaballFalloff::SoftObject),
+            _ => None,
+        }
+    }
+}
+
+// SHAPE_BLEND is the size of the smooth min used to blend shapes together
+const SHAPE_BLEND: f32 = 0.5;
+
+// Below this many shapes it's faster to just evaluate all of them than to go through the BVH
+const BVH_MIN_SHAPES: usize = 16;
+
+// The BVH bounds each shape out to where its field reaches BVH_LEVEL
+// Points where the nearest shape is more than BVH_LEVEL - SHAPE_BLEND are evaluated against every shape instead
+const BVH_LEVEL: f32 = 1.0;
+
+// Extra room for rounding when deciding if the BVH result can be trusted
+const BVH_MARGIN: f32 = 0.001;
+
+// Metaballs are blended with each other, then unioned with the ellipsoids
+// Meshes, loaded descriptions and mirrors are blended onto the ellipsoids one at a time
 pub struct RenderSurface {
     shapes: Vec<(Matrix4<f32>, Ellipsoid)>,
+    shape_bounds: Vec<Aabb>,
+    bvh: Bvh,
+
+    // Meshes are shared with whoever made them, so they only have to be built once
+    meshes: Vec<(Matrix4<f32>, Arc<MeshSurface>)>,
+    // Same goes for descriptions, they're built when they're loaded
+    descriptions: Vec<(Matrix4<f32>, Arc<BuiltSurface>)>,
+    // Everything drawn in a mirror group goes into its own surface, which is unioned with its reflection
+    // Metaballs in a mirror only melt into the other metaballs in it, the mirror's blended on as a whole
+    mirrors: Vec<Mirror<RenderSurface>>,
+
+    metaballs: Metaballs,
 }
 
 impl RenderSurface {
     fn new() -> Self {
-        Self { shapes: vec![] }
+        Self {
+            shapes: vec![],
+            shape_bounds: vec![],
+            bvh: Bvh::default(),
+
+            meshes: vec![],
+            descriptions: vec![],
+            mirrors: vec![],
+
+            metaballs: Metaballs::new(Falloff::Wyvill, 0.5),
+        }
+    }
+
+    // empty_like is a new empty surface with the same metaball falloff
+    fn empty_like(&self) -> Self {
+        let mut surface = Self::new();
+        let (falloff, threshold) = self.metaballs.falloff();
+        surface.metaballs.set_falloff(falloff, threshold);
+
+        surface
     }
     fn push(&mut self, transform: Transform, shape: Ellipsoid) {
+        self.shape_bounds.push(Self::level_bounds(&transform.matrix(), &shape, BVH_LEVEL));
         self.shapes.push((transform.matrix_inverse(), shape))
     }
 
+    // level_bounds is the box around the part of an ellipsoid's field that's under `level`
+    // The field is |p/size|² - 1, so that part is just the ellipsoid grown by sqrt(1 + level)
+    // Transforms are assumed to be affine
+    fn level_bounds(transform: &Matrix4<f32>, shape: &Ellipsoid, level: f32) -> Aabb {
+        let grow = (1.0 + level).sqrt();
+
+        let center = transform.transform_point(&Point3::origin());
+        let half_size = Vector3::from_fn(|row, _| {
+            (0..3)
+                .map(|axis| (transform[(row, axis)] * shape.size[axis] * grow).powf(2.0))
+                .sum::<f32>()
+                .sqrt()
+        });
+
+        // Padded a little so rounding can't leave out points right on the edge
+        Aabb::new(center - half_size, center + half_size).padded(half_size.max() * 0.001)
+    }
+
+    fn push_mesh(&mut self, transform: Transform, mesh: Arc<MeshSurface>) {
+        self.meshes.push((transform.matrix_inverse(), mesh))
+    }
+
+    fn push_description(&mut self, transform: Transform, surface: Arc<BuiltSurface>) {
+        self.descriptions.push((transform.matrix_inverse(), surface))
+    }
+
+    // push_mirror starts a new mirror, draws go into it through `mirror` until the next one
+    // Returns false and pushes nothing if the plane isn't valid
+    fn push_mirror(&mut self, plane: &MirrorPlane) -> bool {
+        let Some(mirror) = plane.mirror(self.empty_like()) else {
+            return false;
+        };
+
+        self.mirrors.push(mirror);
+
+        true
+    }
+
+    // mirror is the surface inside the newest mirror
+    fn mirror(&mut self) -> &mut RenderSurface {
+        &mut self.mirrors.last_mut().expect("a mirror should have been pushed").surface
+    }
+
+    // pop_empty_mirror drops the newest mirror if nothing was drawn in it, there'd be nothing to sample
+    fn pop_empty_mirror(&mut self) {
+        if self.mirrors.last().is_some_and(|mirror| mirror.surface.is_empty()) {
+            self.mirrors.pop();
+        }
+    }
+
+    // Balls with no size, or NaN anywhere, are skipped instead of poisoning the whole field
+    fn push_metaball(&mut self, transform: Transform, metaball: Metaball) {
+        let ball = Ball {
+            center: transform.matrix().transform_point(&Point3::origin()),
+            radius: metaball.radius,
+            strength: metaball.strength,
+        };
+
+        if ball.is_valid() {
+            self.metaballs.push(ball)
+        }
+    }
+
+    // metaball_falloff is None if the falloff or threshold isn't valid
+    fn metaball_falloff(falloff: MetaballFalloff, blobbiness: f32, threshold: f32) -> Option<Falloff> {
+        let falloff = match falloff {
+            MetaballFalloff::Blinn => Falloff::Blinn { blobbiness },
+            MetaballFalloff::Wyvill => Falloff::Wyvill,
+            MetaballFalloff::SoftObject => Falloff::SoftObject,
+        };
+
+        (falloff.is_valid() && !threshold.is_nan() && threshold > 0.0).then_some(falloff)
+    }
+
+    // set_falloff sets the metaball falloff here and in every mirror
+    fn set_falloff(&mut self, falloff: Falloff, threshold: f32) {
+        self.metaballs.set_falloff(falloff, threshold);
+
+        for mirror in &mut self.mirrors {
+            mirror.surface.set_falloff(falloff, threshold)
+        }
+    }
+
+    // take moves everything drawn into a new surface, leaving this one empty
+    // The metaball falloff stays with both, since it's kept between frames
+    fn take(&mut self) -> Self {
+        Self {
+            shapes: std::mem::take(&mut self.shapes),
+            shape_bounds: std::mem::take(&mut self.shape_bounds),
+            bvh: std::mem::take(&mut self.bvh),
+
+            meshes: std::mem::take(&mut self.meshes),
+            descriptions: std::mem::take(&mut self.descriptions),
+            mirrors: std::mem::take(&mut self.mirrors),
+
+            metaballs: self.metaballs.take(),
+        }
+    }
+
     fn clear(&mut self) {
-        self.shapes.clear()
+        self.shapes.clear();
+        self.shape_bounds.clear();
+        self.bvh = Bvh::default();
+        self.meshes.clear();
+        self.descriptions.clear();
+        self.mirrors.clear();
+        self.metaballs.clear()
     }
 
     fn is_empty(&self) -> bool {
         self.shapes.is_empty()
+            && self.meshes.is_empty()
+            && self.descriptions.is_empty()
+            && self.mirrors.is_empty()
+            && self.metaballs.is_empty()
+    }
+
+    // reindex has to be called once everything is drawn, before the surface is sampled
+    fn reindex(&mut self) {
+        self.bvh = if self.shapes.len() >= BVH_MIN_SHAPES {
+            Bvh::new(self.shape_bounds.clone())
+        } else {
+            Bvh::default()
+        };
+
+        for mirror in &mut self.mirrors {
+            mirror.surface.reindex()
+        }
+
+        self.metaballs.reindex()
     }
 
     fn eval_shape(&self, index: usize, at: Point3<f32>) -> f32 {
@@ -58,48 +294,461 @@ impl RenderSurface {
             - 1.0
     }
 
-    fn smooth_min(a: f32, b: f32, k: f32) -> f32 {
-        let h = (k - (a - b).abs()).max(0.0);
+    // eval_shape_gradient is eval_shape along with its exact gradient
+    // The field is |p/size|² - 1 in the shape's space, so its gradient there is 2p/size²
+    fn eval_shape_gradient(&self, index: usize, at: Point3<f32>) -> (f32, Vector3<f32>) {
+        let (t, s) = &self.shapes[index];
+
+        let tat = t.transform_point(&at);
+        let size_squared = vector![s.size[0].powf(2.0), s.size[1].powf(2.0), s.size[2].powf(2.0)];
+
+        let value = Self::eval_ellipsoid(&vector![s.size[0], s.size[1], s.size[2]], &tat);
+        let gradient = (tat.coords * 2.0).component_div(&size_squared);
 
-        a.min(b) - (h * h * 0.25 / k)
+        (value, Self::to_world(t, gradient))
     }
-}
 
-impl Surface for RenderSurface {
-    fn sample(&self, at: Point3<f32>) -> f32 {
+    // to_world takes a gradient from a shape's space back out through its (affine) world to shape transform
+    fn to_world(t: &Matrix4<f32>, gradient: Vector3<f32>) -> Vector3<f32> {
+        t.fixed_view::<3, 3>(0, 0).transpose() * gradient
+    }
+
+    fn sample_shapes(&self, at: Point3<f32>) -> f32 {
         match self.shapes.len() {
             0 => {
                 panic!("No shapes! Nothing to sample.")
             }
             1 => self.eval_shape(0, at),
-            2 => Self::smooth_min(self.eval_shape(0, at), self.eval_shape(1, at), 0.5),
+            2 => smooth_min(self.eval_shape(0, at), self.eval_shape(1, at), SHAPE_BLEND),
+            _ => {
+                let [(min_1, _), (min_2, _)] = self
+                    .min_pair_near(at)
+                    .unwrap_or_else(|| self.min_pair(0..self.shapes.len(), at));
+
+                smooth_min(min_1, min_2, SHAPE_BLEND)
+            }
+        }
+    }
+
+    // sample_shapes_gradient is sample_shapes along with its exact gradient
+    fn sample_shapes_gradient(&self, at: Point3<f32>) -> (f32, Vector3<f32>) {
+        match self.shapes.len() {
+            0 => {
+                panic!("No shapes! Nothing to sample.")
+            }
+            1 => self.eval_shape_gradient(0, at),
+            2 => smooth_min_gradient(
+                self.eval_shape_gradient(0, at),
+                self.eval_shape_gradient(1, at),
+                SHAPE_BLEND,
+            ),
+            _ => {
+                let pair = self
+                    .min_pair_near(at)
+                    .unwrap_or_else(|| self.min_pair(0..self.shapes.len(), at));
+
+                // A side with no shape behind it is f32::MAX, which is too far away to blend in
+                let [a, b] = pair.map(|(value, index)| match index {
+                    Some(index) => self.eval_shape_gradient(index, at),
+                    None => (value, Vector3::zeros()),
+                });
+
+                smooth_min_gradient(a, b, SHAPE_BLEND)
+            }
+        }
+    }
+
+    // blend_meshes smooth mins every mesh onto `value`, the meshes are sampled in their own space
+    // Scale in a mesh's transform scales its distances too, so it blends a bit differently
+    fn blend_meshes(&self, value: Option<f32>, at: Point3<f32>) -> Option<f32> {
+        self.meshes.iter().fold(value, |value, (t, mesh)| {
+            let distance = mesh.sample(t.transform_point(&at));
+
+            Some(match value {
+                Some(value) => smooth_min(value, distance, SHAPE_BLEND),
+                None => distance,
+            })
+        })
+    }
+
+    // blend_meshes_gradient is blend_meshes along with its exact gradient
+    fn blend_meshes_gradient(
+        &self,
+        value: Option<(f32, Vector3<f32>)>,
+        at: Point3<f32>,
+    ) -> Option<(f32, Vector3<f32>)> {
+        self.meshes.iter().fold(value, |value, (t, mesh)| {
+            let (distance, gradient) = mesh.evaluate(t.transform_point(&at));
+            let distance = (distance, Self::to_world(t, gradient));
+
+            Some(match value {
+                Some(value) => smooth_min_gradient(value, distance, SHAPE_BLEND),
+                None => distance,
+            })
+        })
+    }
+
+    // blend_descriptions is blend_meshes for the loaded descriptions
+    fn blend_descriptions(&self, value: Option<f32>, at: Point3<f32>) -> Option<f32> {
+        self.descriptions.iter().fold(value, |value, (t, surface)| {
+            let distance = surface.sample(t.transform_point(&at));
+
+            Some(match value {
+                Some(value) => smooth_min(value, distance, SHAPE_BLEND),
+                None => distance,
+            })
+        })
+    }
+
+    fn blend_descriptions_gradient(
+        &self,
+        value: Option<(f32, Vector3<f32>)>,
+        at: Point3<f32>,
+    ) -> Option<(f32, Vector3<f32>)> {
+        self.descriptions.iter().fold(value, |value, (t, surface)| {
+            let local = t.transform_point(&at);
+            let distance = (surface.sample(local), Self::to_world(t, surface.gradient(local)));
+
+            Some(match value {
+                Some(value) => smooth_min_gradient(value, distance, SHAPE_BLEND),
+                None => distance,
+            })
+        })
+    }
+
+    // blend_mirrors is blend_meshes for the mirrors, which are already in world space
+    fn blend_mirrors(&self, value: Option<f32>, at: Point3<f32>) -> Option<f32> {
+        self.mirrors.iter().fold(value, |value, mirror| {
+            let distance = mirror.sample(at);
+
+            Some(match value {
+                Some(value) => smooth_min(value, distance, SHAPE_BLEND),
+                None => distance,
+            })
+        })
+    }
+
+    fn blend_mirrors_gradient(
+        &self,
+        value: Option<(f32, Vector3<f32>)>,
+        at: Point3<f32>,
+    ) -> Option<(f32, Vector3<f32>)> {
+        self.mirrors.iter().fold(value, |value, mirror| {
+            let distance = (mirror.sample(at), mirror.gradient(at));
+
+            Some(match value {
+                Some(value) => smooth_min_gradient(value, distance, SHAPE_BLEND),
+                None => distance,
+            })
+        })
+    }
+
+    // min_pair goes through the shapes in order, returning the smallest value and the smallest value before it
+    // Each comes with the shape it's from, None if it's still f32::MAX
+    fn min_pair(&self, indices: impl Iterator<Item = usize>, at: Point3<f32>) -> [(f32, Option<usize>); 2] {
+        let mut min_1 = (f32::MAX, None);
+        let mut min_2 = (f32::MAX, None);
+
+        for i in indices {
+            let t = self.eval_shape(i, at);
+
+            if t < min_1.0 {
+                min_2 = min_1;
+                min_1 = (t, Some(i));
+            }
+        }
+
+        [min_1, min_2]
+    }
+
+    // min_pair_near is min_pair over just the shapes the BVH finds at `at`
+    // Every shape left out has a value over BVH_LEVEL. If the smallest value is more than SHAPE_BLEND under that,
+    // whatever they would have done to min_2 gets blended away to nothing, so the result is exactly the same
+    // None if the result can't be trusted and every shape has to be evaluated
+    fn min_pair_near(&self, at: Point3<f32>) -> Option<[(f32, Option<usize>); 2]> {
+        if self.bvh.len() != self.shapes.len() {
+            return None;
+        }
+
+        let mut near = vec![];
+        self.bvh.items_containing(at, &mut near);
+
+        // min_2 depends on the order shapes are evaluated in
+        near.sort_unstable();
+
+        let pair = self.min_pair(near.into_iter(), at);
+
+        (pair[0].0 + SHAPE_BLEND < BVH_LEVEL - BVH_MARGIN).then_some(pair)
+    }
+
+    // eval_shape_lanes is eval_shape for SAMPLE_LANES points at once
+    // Points are given as separate x, y, and z arrays so every step can be vectorized across the lanes
+    fn eval_shape_lanes(&self, index: usize, x: &Lanes, y: &Lanes, z: &Lanes) -> Lanes {
+        let (t, s) = &self.shapes[index];
+
+        let size_squared = [s.size[0].powf(2.0), s.size[1].powf(2.0), s.size[2].powf(2.0)];
+
+        let mut out = [0.0; SAMPLE_LANES];
+        for l in 0..SAMPLE_LANES {
+            // Same as Matrix4::transform_point, including the divide by w
+            let w = t[(3, 0)] * x[l] + t[(3, 1)] * y[l] + t[(3, 2)] * z[l] + t[(3, 3)];
+            let w = if w != 0.0 { w } else { 1.0 };
+
+            let tx = (t[(0, 0)] * x[l] + t[(0, 1)] * y[l] + t[(0, 2)] * z[l] + t[(0, 3)]) / w;
+            let ty = (t[(1, 0)] * x[l] + t[(1, 1)] * y[l] + t[(1, 2)] * z[l] + t[(1, 3)]) / w;
+            let tz = (t[(2, 0)] * x[l] + t[(2, 1)] * y[l] + t[(2, 2)] * z[l] + t[(2, 3)]) / w;
+
+            out[l] = (tx * tx / size_squared[0])
+                + (ty * ty / size_squared[1])
+                + (tz * tz / size_squared[2])
+                - 1.0
+        }
+
+        out
+    }
+
+    // sample_lanes is sample_shapes for SAMPLE_LANES points at once, `near` is just somewhere to keep BVH results
+    fn sample_lanes(&self, x: &Lanes, y: &Lanes, z: &Lanes, near: &mut Vec<usize>) -> Lanes {
+        match self.shapes.len() {
+            0 => {
+                panic!("No shapes! Nothing to sample.")
+            }
+            1 => self.eval_shape_lanes(0, x, y, z),
+            2 => {
+                let a = self.eval_shape_lanes(0, x, y, z);
+                let b = self.eval_shape_lanes(1, x, y, z);
+
+                std::array::from_fn(|l| smooth_min(a[l], b[l], SHAPE_BLEND))
+            }
             _ => {
-                let mut min_1 = f32::MAX;
-                let mut min_2 = f32::MAX;
+                let [min_1, min_2] = self
+                    .min_pair_lanes_near(x, y, z, near)
+                    .unwrap_or_else(|| self.min_pair_lanes(0..self.shapes.len(), x, y, z));
+
+                std::array::from_fn(|l| smooth_min(min_1[l], min_2[l], SHAPE_BLEND))
+            }
+        }
+    }
+
+    // min_pair_lanes is min_pair for SAMPLE_LANES points at once
+    fn min_pair_lanes(&self, indices: impl Iterator<Item = usize>, x: &Lanes, y: &Lanes, z: &Lanes) -> [Lanes; 2] {
+        let mut min_1 = [f32::MAX; SAMPLE_LANES];
+        let mut min_2 = [f32::MAX; SAMPLE_LANES];
+
+        for i in indices {
+            let t = self.eval_shape_lanes(i, x, y, z);
+
+            // Same as the branch in `min_pair`, written as selects so it stays vectorized
+            for l in 0..SAMPLE_LANES {
+                let lower = t[l] < min_1[l];
+                min_2[l] = if lower { min_1[l] } else { min_2[l] };
+                min_1[l] = if lower { t[l] } else { min_1[l] };
+            }
+        }
+
+        [min_1, min_2]
+    }
+
+    // min_pair_lanes_near is min_pair_near for SAMPLE_LANES points at once
+    // Every lane goes through the shapes the BVH finds anywhere in the box around all the lanes. The extra shapes
+    // are ones a lane's own query would have left out, so as long as the result can be trusted they're blended away
+    // the same way, and each lane comes out exactly the same as min_pair_near
+    // None if any lane's result can't be trusted, then they all evaluate every shape
+    fn min_pair_lanes_near(&self, x: &Lanes, y: &Lanes, z: &Lanes, near: &mut Vec<usize>) -> Option<[Lanes; 2]> {
+        if self.bvh.len() != self.shapes.len() {
+            return None;
+        }
+
+        let range = |c: &Lanes| c.iter().fold((f32::MAX, f32::MIN), |(min, max), c| (min.min(*c), max.max(*c)));
+        let [(min_x, max_x), (min_y, max_y), (min_z, max_z)] = [x, y, z].map(range);
+        let lanes = Aabb::new(point![min_x, min_y, min_z], point![max_x, max_y, max_z]);
 
-                for i in 0..self.shapes.len() {
-                    let t = self.eval_shape(i, at);
+        near.clear();
+        self.bvh.items_overlapping(&lanes, near);
 
-                    if t < min_1 {
-                        min_2 = min_1;
-                        min_1 = t;
-                    }
+        // min_2 depends on the order shapes are evaluated in
+        near.sort_unstable();
+
+        let pair = self.min_pair_lanes(near.iter().copied(), x, y, z);
+
+        pair[0].iter().all(|min_1| min_1 + SHAPE_BLEND < BVH_LEVEL - BVH_MARGIN).then_some(pair)
+    }
+
+    // batch_order is the order sample_batch goes through the points in
+    // With a BVH it's along a Morton curve through the BVH's bounds, so each batch is a handful of points close to each
+    // other, and the box around them only takes in a few shapes. Points that are anywhere in the buffer, like
+    // particles, would otherwise give batches that take in nearly every shape
+    fn batch_order(&self, points: &[Point3<f32>]) -> Vec<usize> {
+        let bounds = match self.bvh.bounds() {
+            Some(bounds) if self.bvh.len() == self.shapes.len() => bounds,
+            _ => return (0..points.len()).collect(),
+        };
+
+        // 10 bits of each axis, spread out to every third bit so they can be interleaved
+        // Points outside the bounds are clamped onto them, and NaN goes to 0
+        let scale = Vector3::repeat(1023.0).component_div(&bounds.size());
+        let spread = |c: f32| {
+            let mut v = c.clamp(0.0, 1023.0) as u32;
+            v = v.wrapping_mul(0x0001_0001) & 0xff00_00ff;
+            v = v.wrapping_mul(0x0000_0101) & 0x0f00_f00f;
+            v = v.wrapping_mul(0x0000_0011) & 0xc30c_30c3;
+            v.wrapping_mul(0x0000_0005) & 0x4924_9249
+        };
+
+        let mut keyed: Vec<(u32, usize)> = points
+            .iter()
+            .enumerate()
+            .map(|(i, p)| {
+                let c = (p - bounds.min).component_mul(&scale);
+
+                (spread(c.x) | (spread(c.y) << 1) | (spread(c.z) << 2), i)
+            })
+            .collect();
+        keyed.sort_unstable();
+
+        keyed.into_iter().map(|(_, i)| i).collect()
+    }
+}
+
+// Batches are sampled this many points at a time
+const SAMPLE_LANES: usize = 8;
+
+type Lanes = [f32; SAMPLE_LANES];
+
+impl Surface for RenderSurface {
+    // gradient follows the same branches as sample, taking the gradient of whichever part gives the value
+    fn gradient(&self, at: Point3<f32>) -> Vector3<f32> {
+        let shapes = (!self.shapes.is_empty()).then(|| self.sample_shapes_gradient(at));
+        let solids = self.blend_descriptions_gradient(self.blend_meshes_gradient(shapes, at), at);
+        let solids = self.blend_mirrors_gradient(solids, at);
+
+        match (solids, self.metaballs.is_empty()) {
+            (None, true) => {
+                panic!("No shapes! Nothing to sample.")
+            }
+            (Some(solids), true) => solids.1,
+            (None, false) => self.metaballs.gradient(at),
+            (Some(solids), false) => {
+                if solids.0 <= self.metaballs.sample(at) {
+                    solids.1
+                } else {
+                    self.metaballs.gradient(at)
                 }
+            }
+        }
+    }
 
-                Self::smooth_min(min_1, min_2, 0.5)
+    fn sample(&self, at: Point3<f32>) -> f32 {
+        let shapes = (!self.shapes.is_empty()).then(|| self.sample_shapes(at));
+        let solids = self.blend_descriptions(self.blend_meshes(shapes, at), at);
+        let solids = self.blend_mirrors(solids, at);
+
+        match (solids, self.metaballs.is_empty()) {
+            (None, true) => {
+                panic!("No shapes! Nothing to sample.")
             }
+            (Some(solids), true) => solids,
+            (None, false) => self.metaballs.sample(at),
+            (Some(solids), false) => solids.min(self.metaballs.sample(at)),
         }
     }
+
+    fn sample_batch(&self, points: &[Point3<f32>], out: &mut [f32]) {
+        assert_eq!(points.len(), out.len(), "every point needs somewhere to go");
+
+        if self.shapes.is_empty()
+            && self.meshes.is_empty()
+            && self.descriptions.is_empty()
+            && self.mirrors.is_empty()
+            && !self.metaballs.is_empty()
+        {
+            return self.metaballs.sample_batch(points, out);
+        }
+
+        // Only the shapes are sampled in lanes, meshes go through their own BVH, and descriptions and mirrors could
+        // be anything, so they're blended on a point at a time afterwards
+        if self.shapes.is_empty() {
+            for (p, o) in points.iter().zip(out.iter_mut()) {
+                *o = self.sample(*p)
+            }
+
+            return;
+        }
+
+        let mut near = vec![];
+        for batch in self.batch_order(points).chunks(SAMPLE_LANES) {
+            // Unused lanes repeat the first point, so they don't widen the box the BVH is searched with
+            // Their results are thrown away
+            let first = points[batch[0]];
+            let mut x = [first.x; SAMPLE_LANES];
+            let mut y = [first.y; SAMPLE_LANES];
+            let mut z = [first.z; SAMPLE_LANES];
+            for (l, i) in batch.iter().enumerate() {
+                x[l] = points[*i].x;
+                y[l] = points[*i].y;
+                z[l] = points[*i].z;
+            }
+
+            let samples = self.sample_lanes(&x, &y, &z, &mut near);
+            for (l, i) in batch.iter().enumerate() {
+                out[*i] = samples[l]
+            }
+        }
+
+        if !self.meshes.is_empty() || !self.descriptions.is_empty() || !self.mirrors.is_empty() {
+            for (p, o) in points.iter().zip(out.iter_mut()) {
+                let solids = self.blend_descriptions(self.blend_meshes(Some(*o), *p), *p);
+
+                *o = self.blend_mirrors(solids, *p).expect("there are shapes")
+            }
+        }
+
+        if !self.metaballs.is_empty() {
+            for (p, o) in points.iter().zip(out.iter_mut()) {
+                *o = o.min(self.metaballs.sample(*p))
+            }
+        }
+    }
+
+    // The hint is a point on one of the parts, a random point won't do once there are metaballs
+    // Away from the balls their field is flat, and wherever it's under everything else there's nowhere to step to
+    // The end of an ellipsoid's x axis and a mesh's vertices are on their surfaces, descriptions have their own
+    // hint in their own space, mirrors have the hint of what's inside them, and metaballs need to be searched for
+    // from near one of the balls
+    fn seed_hint(&self) -> Option<Point3<f32>> {
+        let shaped = self.shapes.first().and_then(|(t, s)| {
+            Some(t.try_inverse()?.transform_point(&point![s.size[0], 0.0, 0.0]))
+        });
+        let meshed = || self.meshes.iter().find_map(|(t, mesh)| {
+            Some(t.try_inverse()?.transform_point(mesh.vertices().first()?))
+        });
+        let described = || self.descriptions.iter().find_map(|(t, surface)| {
+            Some(t.try_inverse()?.transform_point(&surface.seed_hint()?))
+        });
+
+        shaped
+            .or_else(meshed)
+            .or_else(described)
+            .or_else(|| self.mirrors.iter().find_map(Mirror::seed_hint))
+            .or_else(|| self.metaballs.seed_hint())
+    }
 }
 
 
 pub mod ffi {
-    use std::ffi::c_void;
+    use std::ffi::{c_char, c_void, CStr};
+    use std::ptr;
+    use std::slice;
+    use std::sync::Arc;
 
     use metal::{DeviceRef, MTLDevice, RenderCommandEncoderRef};
     use metal::foreign_types::ForeignTypeRef;
+    use nalgebra::Point3;
+
+    use creature_creator_implicit_sampler::description::{BuiltSurface, Description};
+    use creature_creator_implicit_sampler::mesh::MeshSurface;
 
-    use crate::surfaces::{Ellipsoid, SurfacePipeline};
+    use crate::surfaces::{Ellipsoid, Metaball, MetaballFalloff, MirrorPlane, SurfacePipeline};
     use crate::transform::Transform;
     use crate::utils::{with_boxed, with_boxed_mut};
 
@@ -137,6 +786,57 @@ pub mod ffi {
         })
     }
 
+    // Returns false if the plane's normal is zero, or anything in it isn't finite
+    // The mirror group is still begun, but what's drawn in it isn't reflected
+    #[no_mangle]
+    pub extern "C" fn surface_pipeline_begin_mirror(pipeline_ptr: *mut c_void, plane: MirrorPlane) -> bool {
+        with_boxed_mut::<SurfacePipeline, _, _>(pipeline_ptr, |pipeline| {
+            pipeline.begin_mirror(plane)
+        })
+    }
+
+    #[no_mangle]
+    pub extern "C" fn surface_pipeline_end_mirror(pipeline_ptr: *mut c_void) {
+        with_boxed_mut::<SurfacePipeline, _, _>(pipeline_ptr, |pipeline| {
+            pipeline.end_mirror()
+        })
+    }
+
+    #[no_mangle]
+    pub extern "C" fn surface_pipeline_begin_group(pipeline_ptr: *mut c_void, group: u32) {
+        with_boxed_mut::<SurfacePipeline, _, _>(pipeline_ptr, |pipeline| {
+            pipeline.begin_group(group)
+        })
+    }
+
+    #[no_mangle]
+    pub extern "C" fn surface_pipeline_end_group(pipeline_ptr: *mut c_void) {
+        with_boxed_mut::<SurfacePipeline, _, _>(pipeline_ptr, |pipeline| {
+            pipeline.end_group()
+        })
+    }
+
+    #[no_mangle]
+    pub extern "C" fn surface_pipeline_set_background_sampling(pipeline_ptr: *mut c_void, background: bool) {
+        with_boxed_mut::<SurfacePipeline, _, _>(pipeline_ptr, |pipeline| {
+            pipeline.set_background_sampling(background)
+        })
+    }
+
+    #[no_mangle]
+    pub extern "C" fn surface_pipeline_sampling_failed(pipeline_ptr: *mut c_void) -> bool {
+        with_boxed::<SurfacePipeline, _, _>(pipeline_ptr, |pipeline| {
+            pipeline.sampling_failed()
+        })
+    }
+
+    #[no_mangle]
+    pub extern "C" fn surface_pipeline_dropped_samples(pipeline_ptr: *mut c_void) -> usize {
+        with_boxed::<SurfacePipeline, _, _>(pipeline_ptr, |pipeline| {
+            pipeline.dropped_samples()
+        })
+    }
+
     #[no_mangle]
     pub extern "C" fn surface_pipeline_draw_ellipsoid(pipeline_ptr: *mut c_void, transform: Transform, ellipsoid: Ellipsoid) {
         with_boxed_mut::<SurfacePipeline, _, _>(pipeline_ptr, |pipeline| {
@@ -144,6 +844,115 @@ pub mod ffi {
         })
     }
 
+    #[no_mangle]
+    pub extern "C" fn surface_pipeline_draw_mesh(pipeline_ptr: *mut c_void, transform: Transform, mesh_ptr: *mut c_void) {
+        let mesh = with_boxed::<Arc<MeshSurface>, _, _>(mesh_ptr, |mesh| Arc::clone(mesh));
+
+        with_boxed_mut::<SurfacePipeline, _, _>(pipeline_ptr, |pipeline| {
+            pipeline.draw_mesh(transform, mesh)
+        })
+    }
+
+    #[no_mangle]
+    pub extern "C" fn surface_pipeline_draw_description(pipeline_ptr: *mut c_void, transform: Transform, description_ptr: *mut c_void) {
+        let surface = with_boxed::<Arc<BuiltSurface>, _, _>(description_ptr, |surface| Arc::clone(surface));
+
+        with_boxed_mut::<SurfacePipeline, _, _>(pipeline_ptr, |pipeline| {
+            pipeline.draw_description(transform, surface)
+        })
+    }
+
+    #[no_mangle]
+    pub extern "C" fn surface_pipeline_draw_metaball(pipeline_ptr: *mut c_void, transform: Transform, metaball: Metaball) {
+        with_boxed_mut::<SurfacePipeline, _, _>(pipeline_ptr, |pipeline| {
+            pipeline.draw_metaball(transform, metaball)
+        })
+    }
+
+    // Returns false if the falloff isn't one of MetaballFalloff, or the blobbiness or threshold aren't positive
+    #[no_mangle]
+    pub extern "C" fn surface_pipeline_set_metaball_falloff(pipeline_ptr: *mut c_void, falloff: u32, blobbiness: f32, threshold: f32) -> bool {
+        let Some(falloff) = MetaballFalloff::from_raw(falloff) else {
+            return false;
+        };
+
+        with_boxed_mut::<SurfacePipeline, _, _>(pipeline_ptr, |pipeline| {
+            pipeline.set_metaball_falloff(falloff, blobbiness, threshold)
+        })
+    }
+
+    // Vertices are packed as x, y, z and triangles as 3 vertex indices, counter-clockwise seen from outside
+    // Returns null if there are no triangles, an index is out of range or a vertex isn't finite
+    #[no_mangle]
+    pub extern "C" fn surface_mesh_make(vertices_ptr: *const f32, vertex_count: usize, indices_ptr: *const u32, triangle_count: usize) -> *mut c_void {
+        if vertices_ptr.is_null() || indices_ptr.is_null() {
+            return ptr::null_mut();
+        }
+
+        let (vertices, indices) = unsafe {
+            (
+                slice::from_raw_parts(vertices_ptr, vertex_count * 3),
+                slice::from_raw_parts(indices_ptr, triangle_count * 3),
+            )
+        };
+
+        let vertices: Vec<Point3<f32>> = vertices.chunks_exact(3).map(|v| Point3::new(v[0], v[1], v[2])).collect();
+        let triangles: Vec<[u32; 3]> = indices.chunks_exact(3).map(|t| [t[0], t[1], t[2]]).collect();
+
+        if !MeshSurface::is_valid(&vertices, &triangles) {
+            return ptr::null_mut();
+        }
+
+        let mesh = Box::new(Arc::new(MeshSurface::new(vertices, triangles)));
+
+        Box::into_raw(mesh).cast()
+    }
+
+    // Pipelines keep their own reference to meshes drawn this frame, so they can be freed at any time
+    #[no_mangle]
+    pub extern "C" fn surface_mesh_free(mesh_ptr: *mut c_void) {
+        let mesh = unsafe {
+            Box::from_raw(mesh_ptr.cast::<Arc<MeshSurface>>())
+        };
+
+        drop(mesh)
+    }
+
+    // source is a saved description as JSON or RON, either is accepted
+    // Returns null if it can't be read, was saved by a newer version, or has something out of range in it
+    #[no_mangle]
+    pub extern "C" fn surface_description_make(source_ptr: *const c_char) -> *mut c_void {
+        if source_ptr.is_null() {
+            return ptr::null_mut();
+        }
+
+        let Ok(source) = unsafe { CStr::from_ptr(source_ptr) }.to_str() else {
+            return ptr::null_mut();
+        };
+
+        // JSON always starts with a brace, RON files start with the description's fields in parentheses
+        let description = if source.trim_start().starts_with('{') {
+            Description::from_json(source)
+        } else {
+            Description::from_ron(source)
+        };
+
+        match description.and_then(|description| description.build()) {
+            Ok(surface) => Box::into_raw(Box::new(Arc::new(surface))).cast(),
+            Err(_) => ptr::null_mut(),
+        }
+    }
+
+    // Like meshes, pipelines keep their own reference to descriptions drawn this frame
+    #[no_mangle]
+    pub extern "C" fn surface_description_free(description_ptr: *mut c_void) {
+        let surface = unsafe {
+            Box::from_raw(description_ptr.cast::<Arc<BuiltSurface>>())
+        };
+
+        drop(surface)
+    }
+
     #[no_mangle]
     pub extern "C" fn surface_pipeline_encode(pipeline_ptr: *mut c_void, encoder_ptr: *mut c_void) {
         let encoder = unsafe {
@@ -183,6 +992,144 @@ type Vertex = [f32; 3];
 type VertexBuffer = Shared<[Vertex; INSTANCE_VERTEX_COUNT]>;
 type InstanceBuffer = Shared<[Instance; MAX_INSTANCE_COUNT]>;
 
+// DEFAULT_GROUP is the surface group drawn into outside of begin_group and end_group
+const DEFAULT_GROUP: u32 = 0;
+
+// A SurfaceGroup has its own surface and sampler, so what's drawn in it doesn't blend with other groups
+struct SurfaceGroup {
+    id: u32,
+    surface: RenderSurface,
+
+    // The sampler is either updated in `end`, or handed to a BackgroundSampler to run on its own thread
+    // Exactly one of these is set, unless the background sampler panicked and took the sampler with it
+    sampler: Option<ImplicitSampler<MAX_INSTANCE_COUNT>>,
+    background: Option<BackgroundSampler<RenderSurface, MAX_INSTANCE_COUNT>>,
+    // The newest samples from the background sampler, drawn until newer ones turn up
+    snapshot: Option<Box<Snapshot>>,
+    // Whether the background sampler panicked, the last samples it published are drawn from then on
+    failed: bool,
+}
+
+impl SurfaceGroup {
+    fn new(id: u32, background: bool, sample_resolution: f32) -> Self {
+        let mut group = Self {
+            id,
+            surface: RenderSurface::new(),
+
+            sampler: Some(ImplicitSampler::new()),
+            background: None,
+            snapshot: None,
+            failed: false,
+        };
+
+        group.set_background_sampling(background, sample_resolution);
+
+        group
+    }
+
+    // set_background_sampling moves the sampler onto its own thread, or back again, keeping its particles
+    fn set_background_sampling(&mut self, background: bool, sample_resolution: f32) {
+        if background {
+            if let Some(sampler) = self.sampler.take() {
+                self.background = Some(BackgroundSampler::start(sampler, sample_resolution))
+            }
+        } else if let Some(background) = self.background.take() {
+            match background.try_stop() {
+                Ok(sampler) => {
+                    self.sampler = Some(sampler);
+                    self.snapshot = None
+                }
+                Err(_) => self.failed = true,
+            }
+        }
+    }
+
+    fn update(&mut self, sample_resolution: f32) {
+        self.surface.reindex();
+
+        if let Some(background) = &self.background {
+            // The panic's already been printed by the sampler thread, it's taken here so dropping the group can't
+            // carry it on into the render thread
+            if !background.is_alive() {
+                _ = self.background.take().unwrap().try_stop();
+                self.failed = true;
+                return;
+            }
+
+            background.submit(self.surface.take());
+
+            if let Some(snapshot) = background.latest() {
+                if let Some(old) = self.snapshot.replace(snapshot) {
+                    background.recycle(old)
+                }
+            }
+
+            return;
+        }
+
+        let Some(sampler) = self.sampler.as_mut() else {
+            return;
+        };
+        sampler.update(sample_resolution, &self.surface);
+        // Packing the particles together means copying them out reads straight through them
+        sampler.compact();
+    }
+
+    // sample_count is how many samples the group has to draw
+    fn sample_count(&self) -> usize {
+        match (&self.sampler, &self.snapshot) {
+            (Some(sampler), _) => sampler.samples().len(),
+            (None, Some(snapshot)) => snapshot.samples.len(),
+            (None, None) => 0,
+        }
+    }
+
+    // copy_instances fills the front of `instances` with the group's samples, and returns how many were filled
+    // There are no samples until the initial sampling is done, which can take a few frames
+    // A surface that never goes below zero, like metaballs too weak to reach the threshold, is empty and never
+    // has any, its sampler just tries seeding it again on each update
+    fn copy_instances(&self, instances: &mut [Instance]) -> usize {
+        match (&self.sampler, &self.snapshot) {
+            (Some(sampler), _) => {
+                let particles = sampler.particles().expect("sampler should be compacted after every update");
+                fill_instances(instances, particles, |particle| Instance {
+                    center: particle.position().coords.data.0[0],
+                    normal: particle.normal().data.0[0],
+                    radius: particle.radius(),
+                })
+            }
+            (None, Some(snapshot)) => fill_instances(instances, &snapshot.samples, |(_, position, normal, radius)| {
+                Instance {
+                    center: position.coords.data.0[0],
+                    normal: normal.data.0[0],
+                    radius: *radius,
+                }
+            }),
+            (None, None) => 0,
+        }
+    }
+}
+
+// A background sampler is left to finish its update on its own thread, rather than the render thread waiting on it
+impl Drop for SurfaceGroup {
+    fn drop(&mut self) {
+        if let Some(background) = self.background.take() {
+            background.detach()
+        }
+    }
+}
+
+// fill_instances copies straight through the front of `samples`, stopping at the end of `instances`
+// Anything past that isn't drawn
+fn fill_instances<T>(instances: &mut [Instance], samples: &[T], instance: impl Fn(&T) -> Instance) -> usize {
+    let count = samples.len().min(instances.len());
+    for (to, from) in instances[..count].iter_mut().zip(&samples[..count]) {
+        *to = instance(from)
+    }
+
+    count
+}
+
 struct SurfacePipeline {
     pipeline: RenderPipelineState,
 
@@ -190,10 +1137,22 @@ struct SurfacePipeline {
 
     instances: InstanceBuffer,
     instance_count: usize,
-
-    surface: RenderSurface,
-    sampler: ImplicitSampler<MAX_INSTANCE_COUNT>,
-    sample_resolution: f32
+    // How many samples didn't fit in the instance buffer last frame, they aren't drawn
+    dropped_samples: usize,
+
+    // Groups are kept between frames so their samplers carry on, until a frame where nothing is drawn in them
+    groups: Vec<SurfaceGroup>,
+    // The group between begin_group and end_group, None outside of one
+    group: Option<u32>,
+    // The metaball falloff and threshold set for each group id
+    // They're kept here rather than in the group, so they're still set if the group isn't drawn for a while
+    falloffs: Vec<(u32, Falloff, f32)>,
+    background: bool,
+    sample_resolution: f32,
+
+    // Some between begin_mirror and end_mirror, true if draws are going into the group's newest mirror
+    // False if the plane wasn't valid, then draws go straight into the group unreflected
+    mirror: Option<bool>,
 }
 
 impl SurfacePipeline {
@@ -335,39 +1294,72 @@ impl SurfacePipeline {
             vertices: Self::new_vertices_buffer(device),
             instances: Self::new_instance_buffer(device),
             instance_count: 0,
+            dropped_samples: 0,
 
-            surface: RenderSurface::new(),
-            sampler: ImplicitSampler::new(),
+            groups: vec![],
+            falloffs: vec![],
+            group: None,
+            background: false,
             sample_resolution: 0.3,
+
+            mirror: None,
         }
     }
 
     pub fn begin(&mut self) {
     //     Prepare for surface to be refreshed
-        self.surface.clear();
-        self.instance_count = 0
+    //     The instances are left alone, so encoding before `end` still draws the last complete frame
+        for group in &mut self.groups {
+            group.surface.clear()
+        }
+
+        self.group = None;
+        self.mirror = None
     }
 
-    fn update_surface_samples(&mut self) {
-        self.sampler.update(self.sample_resolution, &self.surface);
+    // set_background_sampling moves every group's sampler onto its own thread, or back again
+    // In the background `end` never waits on the samplers, it draws the newest samples each has finished
+    pub fn set_background_sampling(&mut self, background: bool) {
+        self.background = background;
+
+        for group in &mut self.groups {
+            group.set_background_sampling(background, self.sample_resolution)
+        }
+    }
 
-        let mut max_i = 0;
-        for (i, (position, normal, radius)) in self.sampler.samples().enumerate() {
-            self.instances[i] = Instance {
-                center: position.coords.data.0[0],
-                normal: normal.data.0[0],
-                radius,
-            };
+    // sampling_failed is whether any group's background sampler has panicked
+    // Those groups stop updating, they keep drawing the last samples they had
+    pub fn sampling_failed(&self) -> bool {
+        self.groups.iter().any(|group| group.failed)
+    }
 
-            max_i = i
+    fn update_surface_samples(&mut self) {
+        // Every group's samples go into the one instance buffer, one after another, so they're drawn together
+        let mut count = 0;
+        let mut total = 0;
+        for group in &mut self.groups {
+            group.update(self.sample_resolution);
+            count += group.copy_instances(&mut self.instances[count..]);
+            total += group.sample_count();
         }
 
-        self.instance_count = max_i + 1;
-        self.surface.clear();
+        self.instance_count = count;
+        self.dropped_samples = total - count;
+    }
+
+    // dropped_samples is how many samples didn't fit in the instance buffer last frame
+    // Every group's sampler is as big as the whole buffer, so between them they can have more samples than fit
+    pub fn dropped_samples(&self) -> usize {
+        self.dropped_samples
     }
 
     pub fn end(&mut self) {
-        assert!(!self.surface.is_empty(), "Nothing was drawn!");
+        assert!(self.group.is_none(), "Surface group was never ended!");
+        assert!(self.mirror.is_none(), "Mirror group was never ended!");
+
+        // Groups that weren't drawn this frame are done with, their samplers go with them
+        self.groups.retain(|group| !group.surface.is_empty());
+        assert!(!self.groups.is_empty(), "Nothing was drawn!");
 
         let start = Instant::now();
         self.update_surface_samples();
@@ -375,8 +1367,105 @@ impl SurfacePipeline {
         dbg!(sampling_elapsed);
     }
 
+    // Everything drawn until end_group is sampled separately from everything else
+    // Groups are told apart by `id` between frames, the same id picks up the same sampler
+    pub fn begin_group(&mut self, id: u32) {
+        assert!(self.group.is_none(), "Surface groups can't be nested!");
+        assert!(self.mirror.is_none(), "Surface groups can't be started inside a mirror group!");
+
+        self.group = Some(id)
+    }
+
+    pub fn end_group(&mut self) {
+        assert!(self.group.is_some(), "No surface group to end!");
+        assert!(self.mirror.is_none(), "Mirror group was never ended!");
+
+        self.group = None
+    }
+
+    // surface is the surface being drawn into, a group is made the first time it's drawn into
+    fn surface(&mut self) -> &mut RenderSurface {
+        let mirrored = self.mirror == Some(true);
+        let surface = self.group_surface();
+
+        if mirrored {
+            surface.mirror()
+        } else {
+            surface
+        }
+    }
+
+    fn group_surface(&mut self) -> &mut RenderSurface {
+        let id = self.group.unwrap_or(DEFAULT_GROUP);
+
+        let i = match self.groups.iter().position(|group| group.id == id) {
+            Some(i) => i,
+            None => {
+                let mut group = SurfaceGroup::new(id, self.background, self.sample_resolution);
+                if let Some((_, falloff, threshold)) = self.falloffs.iter().find(|(group, ..)| *group == id) {
+                    group.surface.set_falloff(*falloff, *threshold)
+                }
+
+                self.groups.push(group);
+                self.groups.len() - 1
+            }
+        };
+
+        &mut self.groups[i].surface
+    }
+
+    // Everything drawn until end_mirror is unioned with its reflection across the plane
+    // so only one side of a symmetric creature has to be drawn
+    // Returns false if the plane isn't valid, then what's drawn until end_mirror is drawn once as it is
+    pub fn begin_mirror(&mut self, plane: MirrorPlane) -> bool {
+        assert!(self.mirror.is_none(), "Mirror groups can't be nested!");
+
+        let mirrored = self.group_surface().push_mirror(&plane);
+        self.mirror = Some(mirrored);
+
+        mirrored
+    }
+
+    pub fn end_mirror(&mut self) {
+        let mirrored = self.mirror.take().expect("No mirror group to end!");
+
+        if mirrored {
+            self.group_surface().pop_empty_mirror()
+        }
+    }
+
     pub fn draw_ellipsoid(&mut self, transform: Transform, ellipsoid: Ellipsoid) {
-        self.surface.push(transform, ellipsoid)
+        self.surface().push(transform, ellipsoid)
+    }
+
+    pub fn draw_mesh(&mut self, transform: Transform, mesh: Arc<MeshSurface>) {
+        self.surface().push_mesh(transform, mesh)
+    }
+
+    pub fn draw_description(&mut self, transform: Transform, surface: Arc<BuiltSurface>) {
+        self.surface().push_description(transform, surface)
+    }
+
+    pub fn draw_metaball(&mut self, transform: Transform, metaball: Metaball) {
+        self.surface().push_metaball(transform, metaball)
+    }
+
+    // The falloff is kept between frames, it isn't reset by `begin`
+    // Each group has its own, set while the group is being drawn. Setting it doesn't make the group
+    pub fn set_metaball_falloff(&mut self, falloff: MetaballFalloff, blobbiness: f32, threshold: f32) -> bool {
+        let Some(falloff) = RenderSurface::metaball_falloff(falloff, blobbiness, threshold) else {
+            return false;
+        };
+
+        let id = self.group.unwrap_or(DEFAULT_GROUP);
+        self.falloffs.retain(|(group, ..)| *group != id);
+        self.falloffs.push((id, falloff, threshold));
+
+        if let Some(group) = self.groups.iter_mut().find(|group| group.id == id) {
+            group.surface.set_falloff(falloff, threshold)
+        }
+
+        true
     }
 
     pub fn encode(&self, encoder: &RenderCommandEncoderRef) {
@@ -393,4 +1482,110 @@ impl SurfacePipeline {
             )
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::{Rotation3, Translation3};
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use super::*;
+
+    // random_shapes scatters `count` stretched and turned ellipsoids, overlapping plenty of each other
+    // The same shapes go into two surfaces, and only the first gets a BVH, the second evaluates every shape
+    fn random_shapes(rng: &mut StdRng, count: usize) -> (RenderSurface, RenderSurface) {
+        let mut indexed = RenderSurface::new();
+        let mut brute = RenderSurface::new();
+
+        for _ in 0..count {
+            let mut random = |range: std::ops::Range<f32>| vector![
+                rng.gen_range(range.clone()),
+                rng.gen_range(range.clone()),
+                rng.gen_range(range)
+            ];
+            let matrix = Translation3::from(random(-3.0..3.0)).to_homogeneous()
+                * Rotation3::from_scaled_axis(random(-PI..PI)).to_homogeneous()
+                * Matrix4::new_nonuniform_scaling(&random(0.5..2.0));
+            let shape = Ellipsoid { size: random(0.1..1.0).into() };
+
+            indexed.push(Transform::from_matrix(matrix), shape);
+            brute.push(Transform::from_matrix(matrix), shape);
+        }
+
+        indexed.reindex();
+
+        (indexed, brute)
+    }
+
+    // random_point is either anywhere around the shapes, or near the surface of one of them, where the BVH is used
+    fn random_point(rng: &mut StdRng, surface: &RenderSurface) -> Point3<f32> {
+        if rng.gen_bool(0.3) {
+            return point![rng.gen_range(-5.0..5.0), rng.gen_range(-5.0..5.0), rng.gen_range(-5.0..5.0)];
+        }
+
+        let (t, s) = &surface.shapes[rng.gen_range(0..surface.shapes.len())];
+        let direction = vector![rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0)];
+        let local = direction.normalize().component_mul(&Vector3::from(s.size)) * rng.gen_range(0.8..1.2);
+
+        t.try_inverse().unwrap().transform_point(&Point3::from(local))
+    }
+
+    #[test]
+    fn bvh_matches_every_shape() {
+        let mut rng = StdRng::seed_from_u64(7);
+
+        for count in [BVH_MIN_SHAPES, 50, 200] {
+            let (indexed, brute) = random_shapes(&mut rng, count);
+            assert_eq!(indexed.bvh.len(), count);
+
+            let mut near = 0;
+            for _ in 0..2000 {
+                let at = random_point(&mut rng, &indexed);
+                if indexed.min_pair_near(at).is_some() {
+                    near += 1
+                }
+
+                // Shapes the BVH leaves out are blended away to nothing, so it's exactly the same, not just close
+                assert_eq!(indexed.sample(at), brute.sample(at), "at {at}");
+                assert_eq!(indexed.gradient(at), brute.gradient(at), "at {at}");
+            }
+
+            // Most points should have gone through the BVH, or this isn't testing much
+            assert!(near > 1000, "only {near} points used the BVH with {count} shapes");
+        }
+    }
+
+    #[test]
+    fn batches_match_points() {
+        let mut rng = StdRng::seed_from_u64(7);
+
+        for (count, mirrored) in [(3, false), (BVH_MIN_SHAPES, false), (200, false), (200, true)] {
+            let (mut surface, _) = random_shapes(&mut rng, count);
+            // Mirrors are blended on a point at a time after the lanes
+            if mirrored {
+                let plane = MirrorPlane {
+                    origin: [0.0; 3],
+                    normal: [1.0, 0.0, 0.0],
+                    blend: 0.2,
+                };
+                assert!(surface.push_mirror(&plane));
+                surface
+                    .mirror()
+                    .push(Transform::from_matrix(Matrix4::new_translation(&vector![1.0, 0.0, 0.0])), Ellipsoid { size: [1.0, 0.5, 0.5] });
+                surface.reindex();
+            }
+
+            // Not a whole number of batches, so the last one has unused lanes
+            let points: Vec<_> = (0..1001).map(|_| random_point(&mut rng, &surface)).collect();
+            let mut out = vec![0.0; points.len()];
+            surface.sample_batch(&points, &mut out);
+
+            // Lanes do the same sums as nalgebra, but not always in the same order
+            for (p, o) in points.iter().zip(out) {
+                let expected = surface.sample(*p);
+                assert!((o - expected).abs() <= 1e-4 * expected.abs().max(1.0), "at {p}, {o} != {expected}");
+            }
+        }
+    }
+}
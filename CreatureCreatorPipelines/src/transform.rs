@@ -15,3 +15,16 @@ impl Transform {
         Matrix4::from_data(ArrayStorage(self.matrix_inverse))
     }
 }
+
+#[cfg(test)]
+impl Transform {
+    // from_matrix is for tests, the app always sends both halves
+    pub fn from_matrix(matrix: Matrix4<f32>) -> Self {
+        let matrix_inverse = matrix.try_inverse().expect("transform should be invertible");
+
+        Transform {
+            matrix: matrix.data.0,
+            matrix_inverse: matrix_inverse.data.0,
+        }
+    }
+}